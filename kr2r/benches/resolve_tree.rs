@@ -0,0 +1,91 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use kr2r::classify::resolve_tree;
+use kr2r::taxonomy::{Taxonomy, TaxonomyNode};
+use std::collections::HashMap;
+
+/// 固定种子的线性同余随机数, 每次运行生成相同的树和命中
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) % bound
+    }
+}
+
+/// 按 BFS 编号生成 node_count 个节点的树, 子节点连续, 与 taxo.k2d 的布局相同
+fn synthetic_taxonomy(node_count: usize, rng: &mut Lcg) -> Taxonomy {
+    let mut taxonomy = Taxonomy::default();
+    taxonomy.nodes.push(TaxonomyNode::default());
+    taxonomy.nodes.push(TaxonomyNode {
+        external_id: 1,
+        ..Default::default()
+    });
+    let mut next = 2;
+    let mut parent = 1;
+    while next < node_count {
+        let child_count = (1 + rng.next(8) as usize).min(node_count - next);
+        taxonomy.nodes[parent].first_child = next as u64;
+        taxonomy.nodes[parent].child_count = child_count as u64;
+        for id in next..next + child_count {
+            taxonomy.nodes.push(TaxonomyNode {
+                parent_id: parent as u64,
+                external_id: id as u64,
+                ..Default::default()
+            });
+        }
+        next += child_count;
+        parent += 1;
+    }
+    taxonomy.generate_external_to_internal_id_map();
+    taxonomy.build_lca_index();
+    taxonomy
+}
+
+/// 长 read 的命中: 大部分集中在一个子树 (真实来源), 其余散落在整棵树上
+fn hit_group(taxonomy: &Taxonomy, distinct_taxa: usize, rng: &mut Lcg) -> HashMap<u32, u64> {
+    let node_count = taxonomy.nodes.len() as u64;
+    let source = 1 + rng.next(node_count - 1) as u32;
+    let mut hits = HashMap::new();
+    while hits.len() < distinct_taxa {
+        let taxon = if rng.next(4) == 0 {
+            1 + rng.next(node_count - 1) as u32
+        } else {
+            let mut taxon = source;
+            for _ in 0..rng.next(3) {
+                match taxonomy.children(taxon) {
+                    children if !children.is_empty() => {
+                        taxon = children.start + rng.next(children.len() as u64) as u32
+                    }
+                    _ => break,
+                }
+            }
+            taxon
+        };
+        *hits.entry(taxon).or_insert(0) += 1 + rng.next(50);
+    }
+    hits
+}
+
+fn bench_resolve_tree(c: &mut Criterion) {
+    let mut rng = Lcg(42);
+    let taxonomy = synthetic_taxonomy(2_000_000, &mut rng);
+
+    let mut group = c.benchmark_group("resolve_tree");
+    for distinct_taxa in [16, 256, 4096, 16384] {
+        let hits = hit_group(&taxonomy, distinct_taxa, &mut rng);
+        let total: u64 = hits.values().sum();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(distinct_taxa),
+            &hits,
+            |b, hits| b.iter(|| resolve_tree(black_box(hits), &taxonomy, total / 2)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_resolve_tree);
+criterion_main!(benches);
@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn run_kun_peng(binary: &Path, args: &[String]) -> String {
+    println!(
+        "Executing command: {} {}",
+        binary.to_string_lossy(),
+        args.join(" ")
+    );
+    let output = Command::new(binary)
+        .args(args)
+        .output()
+        .expect("Failed to run kun_peng command");
+    if !output.status.success() || !output.stderr.is_empty() {
+        println!(
+            "kun_peng {} error: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+/// 分类结果行的前三列 (C/U, 序列 id, taxid), 分页内的槽位顺序不影响结果
+fn classify_calls(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.starts_with("C\t") || line.starts_with("U\t"))
+        .map(|line| line.split('\t').take(3).collect::<Vec<_>>().join("\t"))
+        .collect()
+}
+
+fn build_args(data_dir: &Path, database: &Path) -> Vec<String> {
+    vec![
+        "build".to_string(),
+        "--download-dir".to_string(),
+        data_dir.to_string_lossy().to_string(),
+        "--db".to_string(),
+        database.to_string_lossy().to_string(),
+        "--hash-capacity".to_string(),
+        "4K".to_string(),
+    ]
+}
+
+fn main() {
+    let workspace_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_path_buf();
+    let kr2r_binary = workspace_root.join("target/release/kun_peng");
+    let data_dir = workspace_root.join("data");
+    let serial_dir = workspace_root.join("test_database_serial");
+    let paged_dir = workspace_root.join("test_database_pages");
+
+    for dir in [&serial_dir, &paged_dir] {
+        if dir.exists() {
+            fs::remove_dir_all(dir).expect("Failed to clean database directory");
+        }
+        fs::create_dir_all(dir).expect("Failed to create database directory");
+    }
+
+    // Reference: a normal build with small pages
+    run_kun_peng(&kr2r_binary, &build_args(&data_dir, &serial_dir));
+
+    // Paged build: chunk files only, then one build-page job per range, all at once
+    let mut args = build_args(&data_dir, &paged_dir);
+    args.push("--chunk-only".to_string());
+    let output = run_kun_peng(&kr2r_binary, &args);
+    // "chunk files written, build pages 1-N with ..."
+    let partition: usize = output
+        .lines()
+        .find_map(|line| line.split("build pages 1-").nth(1))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|n| n.parse().ok())
+        .expect("Failed to read the page count from build --chunk-only");
+
+    let per_job = partition.div_ceil(4);
+    let jobs: Vec<_> = (1..=partition)
+        .step_by(per_job)
+        .map(|start| {
+            let range = format!("{}-{}", start, (start + per_job - 1).min(partition));
+            println!("Spawning build-page --page {}", range);
+            Command::new(&kr2r_binary)
+                .args(["build-page", "--db"])
+                .arg(&paged_dir)
+                .args(["--page", &range])
+                .spawn()
+                .expect("Failed to spawn kun_peng build-page")
+        })
+        .collect();
+    for mut job in jobs {
+        let status = job.wait().expect("Failed to wait for kun_peng build-page");
+        assert!(status.success(), "build-page job failed: {}", status);
+    }
+
+    run_kun_peng(
+        &kr2r_binary,
+        &[
+            "build-finalize".to_string(),
+            "--db".to_string(),
+            paged_dir.to_string_lossy().to_string(),
+        ],
+    );
+    let verify = run_kun_peng(
+        &kr2r_binary,
+        &[
+            "db".to_string(),
+            "verify".to_string(),
+            "--db".to_string(),
+            paged_dir.to_string_lossy().to_string(),
+        ],
+    );
+    if verify.lines().any(|line| line.starts_with("FAIL")) {
+        panic!("paged database failed verification:\n{}", verify);
+    }
+
+    let mut mismatches = 0;
+    for fasta in ["COVID_19.fa", "HIV_1.fna", "MERS.fa"] {
+        let fasta = data_dir.join(fasta).to_string_lossy().to_string();
+        let mut calls = [&serial_dir, &paged_dir].map(|database| {
+            classify_calls(&run_kun_peng(
+                &kr2r_binary,
+                &[
+                    "direct".to_string(),
+                    "--db".to_string(),
+                    database.to_string_lossy().to_string(),
+                    fasta.clone(),
+                ],
+            ))
+        });
+        for call in calls.iter_mut() {
+            call.sort();
+        }
+        if calls[0].is_empty() || calls[0] != calls[1] {
+            mismatches += 1;
+            println!("paged build mismatch for {}", fasta);
+        }
+    }
+
+    if mismatches > 0 {
+        panic!("paged build differs for {} files", mismatches);
+    }
+    println!("paged build ok");
+}
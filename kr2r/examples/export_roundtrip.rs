@@ -0,0 +1,115 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn run_kun_peng(binary: &Path, args: &[String]) -> String {
+    println!(
+        "Executing command: {} {}",
+        binary.to_string_lossy(),
+        args.join(" ")
+    );
+    let output = Command::new(binary)
+        .args(args)
+        .output()
+        .expect("Failed to run kun_peng command");
+    if !output.status.success() || !output.stderr.is_empty() {
+        println!(
+            "kun_peng {} error: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+/// 只保留分类结果行 (C/U 开头)
+fn classify_lines(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.starts_with("C\t") || line.starts_with("U\t"))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn main() {
+    let workspace_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_path_buf();
+    let kr2r_binary = workspace_root.join("target/release/kun_peng");
+    let data_dir = workspace_root.join("data");
+    let test_dir = workspace_root.join("test_database");
+    let export_dir = workspace_root.join("test_database_kraken2");
+
+    if !test_dir.join("hash_config.k2d").exists() {
+        fs::create_dir_all(&test_dir).expect("Failed to create database directory");
+        run_kun_peng(
+            &kr2r_binary,
+            &[
+                "build".to_string(),
+                "--download-dir".to_string(),
+                data_dir.to_string_lossy().to_string(),
+                "--db".to_string(),
+                test_dir.to_string_lossy().to_string(),
+            ],
+        );
+    }
+
+    // Export to Kraken 2 format, then shard it back with hashshard
+    if export_dir.exists() {
+        fs::remove_dir_all(&export_dir).expect("Failed to clean export directory");
+    }
+    run_kun_peng(
+        &kr2r_binary,
+        &[
+            "export".to_string(),
+            "--db".to_string(),
+            test_dir.to_string_lossy().to_string(),
+            "--output-dir".to_string(),
+            export_dir.to_string_lossy().to_string(),
+        ],
+    );
+    run_kun_peng(
+        &kr2r_binary,
+        &[
+            "hashshard".to_string(),
+            "--db".to_string(),
+            export_dir.to_string_lossy().to_string(),
+            "--hash-capacity".to_string(),
+            "1M".to_string(),
+        ],
+    );
+
+    let mut mismatches = 0;
+    for fasta in ["COVID_19.fa", "HIV_1.fna", "MERS.fa"] {
+        let fasta = data_dir.join(fasta).to_string_lossy().to_string();
+        let expected = run_kun_peng(
+            &kr2r_binary,
+            &[
+                "direct".to_string(),
+                "--db".to_string(),
+                test_dir.to_string_lossy().to_string(),
+                fasta.clone(),
+            ],
+        );
+        let actual = run_kun_peng(
+            &kr2r_binary,
+            &[
+                "direct".to_string(),
+                "--db".to_string(),
+                export_dir.to_string_lossy().to_string(),
+                fasta.clone(),
+            ],
+        );
+        let (expected, actual) = (classify_lines(&expected), classify_lines(&actual));
+        if expected.is_empty() || expected != actual {
+            mismatches += 1;
+            println!("round-trip mismatch for {}", fasta);
+        }
+    }
+
+    if mismatches > 0 {
+        panic!("export round-trip failed for {} files", mismatches);
+    }
+    println!("export round-trip ok");
+}
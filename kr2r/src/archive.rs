@@ -0,0 +1,307 @@
+//! 单文件数据库归档 (db pack / db unpack)
+//!
+//! 布局: 32 字节头部 | 各文件内容 | 目录(JSON)
+//! 头部: magic(8) | version(u32) | 目录 crc32(u32) | 目录偏移(u64) | 目录长度(u64)
+//!
+//! 文件按原样保存(包括 .k2d 头部), 分页可以单独使用 zstd 压缩.
+//! `database.join("taxo.k2d")` 这类路径在 database 是归档文件时指向归档内的条目.
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"KUNPENGA";
+pub const ARCHIVE_VERSION: u32 = 1;
+const ARCHIVE_HEADER_SIZE: u64 = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+/// 目录中的一个文件
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchiveEntry {
+    pub name: String,
+    /// 在归档中的偏移
+    pub offset: u64,
+    /// 归档中保存的长度(压缩后)
+    pub stored_len: u64,
+    /// 原始文件长度
+    pub len: u64,
+    pub compression: Compression,
+    /// 原始文件内容的 crc32
+    pub crc32: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Toc {
+    entries: Vec<ArchiveEntry>,
+}
+
+fn invalid_data(path: &Path, reason: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{:?} is not a valid database archive: {}", path, reason),
+    )
+}
+
+/// 已打开的归档(只读取了目录)
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub path: PathBuf,
+    pub entries: Vec<ArchiveEntry>,
+}
+
+impl Archive {
+    /// 文件是否以归档 magic 开头
+    pub fn is_archive<P: AsRef<Path>>(path: P) -> bool {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return false;
+        }
+        let mut magic = [0u8; 8];
+        File::open(path)
+            .and_then(|mut file| file.read_exact(&mut magic))
+            .is_ok()
+            && &magic == ARCHIVE_MAGIC
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = crate::utils::open_file(path)?;
+        let file_len = file.metadata()?.len();
+
+        let mut header = [0u8; ARCHIVE_HEADER_SIZE as usize];
+        if file_len < ARCHIVE_HEADER_SIZE {
+            return Err(invalid_data(path, "file too short"));
+        }
+        file.read_exact(&mut header)?;
+        if &header[0..8] != ARCHIVE_MAGIC {
+            return Err(invalid_data(path, "bad magic"));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version > ARCHIVE_VERSION {
+            return Err(invalid_data(
+                path,
+                format!("unsupported archive version {}", version),
+            ));
+        }
+        let toc_crc32 = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        let toc_offset = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let toc_len = u64::from_le_bytes(header[24..32].try_into().unwrap());
+        if toc_offset < ARCHIVE_HEADER_SIZE || toc_offset + toc_len != file_len {
+            return Err(crate::k2d::K2dError::Truncated {
+                path: path.to_path_buf(),
+                expected: toc_offset + toc_len,
+                actual: file_len,
+            }
+            .into());
+        }
+
+        let mut toc = vec![0u8; toc_len as usize];
+        file.seek(SeekFrom::Start(toc_offset))?;
+        file.read_exact(&mut toc)?;
+        crate::k2d::verify_checksum(path, toc_crc32, &toc)?;
+        let toc: Toc = serde_json::from_slice(&toc).map_err(|e| invalid_data(path, e))?;
+        for entry in &toc.entries {
+            if entry.offset < ARCHIVE_HEADER_SIZE || entry.offset + entry.stored_len > toc_offset {
+                return Err(invalid_data(
+                    path,
+                    format!("entry {} lies outside the data section", entry.name),
+                ));
+            }
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries: toc.entries,
+        })
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&ArchiveEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    fn find(&self, name: &str) -> Result<&ArchiveEntry> {
+        self.entry(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found in {:?}", name, self.path),
+            )
+        })
+    }
+
+    /// 打开条目, 返回解压后的原始文件内容的读取器
+    pub fn open_entry(&self, entry: &ArchiveEntry) -> Result<Box<dyn Read>> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let stored = BufReader::new(file).take(entry.stored_len);
+        match entry.compression {
+            Compression::None => Ok(Box::new(stored)),
+            Compression::Zstd => Ok(Box::new(
+                zstd::stream::read::Decoder::with_buffer(stored)?.take(entry.len),
+            )),
+        }
+    }
+
+    /// 读取整个条目并校验 crc32
+    pub fn read_entry(&self, name: &str) -> Result<Vec<u8>> {
+        let entry = self.find(name)?;
+        let mut data = Vec::with_capacity(entry.len as usize);
+        self.open_entry(entry)?.read_to_end(&mut data)?;
+        self.check_entry(entry, data.len() as u64, crate::k2d::checksum(&data))?;
+        Ok(data)
+    }
+
+    /// 检查条目解压后的长度和 crc32
+    pub fn check_entry(&self, entry: &ArchiveEntry, len: u64, crc32: u32) -> Result<()> {
+        let path = self.path.join(&entry.name);
+        if len != entry.len {
+            return Err(crate::k2d::K2dError::Truncated {
+                path,
+                expected: entry.len,
+                actual: len,
+            }
+            .into());
+        }
+        if crc32 != entry.crc32 {
+            return Err(crate::k2d::K2dError::ChecksumMismatch {
+                path,
+                expected: entry.crc32,
+                actual: crc32,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// 哈希分页条目 hash_1.k2d ... hash_N.k2d, 按序号排序
+    pub fn pages(&self) -> Result<Vec<ArchiveEntry>> {
+        let mut pages: Vec<(usize, ArchiveEntry)> = self
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                entry
+                    .name
+                    .strip_prefix("hash_")
+                    .and_then(|s| s.strip_suffix(".k2d"))
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .map(|index| (index, entry.clone()))
+            })
+            .collect();
+        pages.sort_by_key(|(index, _)| *index);
+        for (i, (index, _)) in pages.iter().enumerate() {
+            if *index != i + 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("hash page {} missing from {:?}", i + 1, self.path),
+                ));
+            }
+        }
+        Ok(pages.into_iter().map(|(_, entry)| entry).collect())
+    }
+}
+
+/// 归档内的虚拟路径 archive/name, 返回归档路径和条目名
+pub fn split_path(path: &Path) -> Option<(&Path, String)> {
+    let parent = path.parent()?;
+    let name = path.file_name()?.to_str()?.to_string();
+    if Archive::is_archive(parent) {
+        Some((parent, name))
+    } else {
+        None
+    }
+}
+
+/// 写入归档: 依次添加文件, finish 时写入目录并回填头部
+pub struct ArchiveWriter {
+    writer: BufWriter<File>,
+    entries: Vec<ArchiveEntry>,
+}
+
+/// 计算写入内容的 crc32 和长度
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+    len: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+impl ArchiveWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&[0u8; ARCHIVE_HEADER_SIZE as usize])?;
+        Ok(Self {
+            writer,
+            entries: Vec::new(),
+        })
+    }
+
+    /// 添加文件, compression 为 Zstd 时以 level 压缩
+    pub fn add_file<P: AsRef<Path>>(
+        &mut self,
+        name: &str,
+        path: P,
+        compression: Compression,
+        level: i32,
+    ) -> Result<&ArchiveEntry> {
+        let offset = self.writer.stream_position()?;
+        let mut reader = HashingReader {
+            inner: BufReader::new(crate::utils::open_file(path)?),
+            hasher: crc32fast::Hasher::new(),
+            len: 0,
+        };
+        match compression {
+            Compression::None => {
+                io::copy(&mut reader, &mut self.writer)?;
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(&mut self.writer, level)?;
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        let stored_len = self.writer.stream_position()? - offset;
+
+        self.entries.push(ArchiveEntry {
+            name: name.to_string(),
+            offset,
+            stored_len,
+            len: reader.len,
+            compression,
+            crc32: reader.hasher.finalize(),
+        });
+        Ok(self.entries.last().unwrap())
+    }
+
+    pub fn finish(mut self) -> Result<Vec<ArchiveEntry>> {
+        let toc = Toc {
+            entries: self.entries,
+        };
+        let toc_bytes = serde_json::to_vec(&toc)?;
+        let toc_offset = self.writer.stream_position()?;
+        self.writer.write_all(&toc_bytes)?;
+
+        let mut header = [0u8; ARCHIVE_HEADER_SIZE as usize];
+        header[0..8].copy_from_slice(ARCHIVE_MAGIC);
+        header[8..12].copy_from_slice(&ARCHIVE_VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&crate::k2d::checksum(&toc_bytes).to_le_bytes());
+        header[16..24].copy_from_slice(&toc_offset.to_le_bytes());
+        header[24..32].copy_from_slice(&(toc_bytes.len() as u64).to_le_bytes());
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header)?;
+        self.writer.flush()?;
+        Ok(toc.entries)
+    }
+}
@@ -1,4 +1,5 @@
 // 使用时需要引用模块路径
+use crate::taxonomy::{standard_rank_index, STANDARD_RANKS};
 use crate::utils::expand_spaced_seed_mask;
 use crate::{construct_seed_template, parse_binary};
 use clap::Parser;
@@ -7,12 +8,13 @@ use seqkmer::{
     BITS_PER_CHAR, DEFAULT_KMER_LENGTH, DEFAULT_MINIMIZER_LENGTH, DEFAULT_MINIMIZER_SPACES,
     DEFAULT_TOGGLE_MASK,
 };
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
 pub const U32MAXPLUS: u64 = u32::MAX as u64;
 pub const ONEGB: u64 = 1073741824;
 
-#[derive(Parser, Debug, Clone)]
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 #[clap(version, about = "build database")]
 pub struct Build {
     /// ncbi library fna database directory
@@ -37,6 +39,49 @@ pub struct Build {
 
 const BATCH_SIZE: usize = 16 * 1024 * 1024;
 
+/// 每条 read 输出行中分类结果的格式
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ReadOutputArgs {
+    /// Print the scientific name with the taxid in the per-read output, e.g. "Escherichia coli (taxid 562)"
+    #[clap(long, value_parser, default_value_t = false)]
+    pub use_names: bool,
+
+    /// Append the 7-rank lineage of the call (superkingdom;phylum;class;order;family;genus;species) as a sixth column.
+    /// Unranked nodes are skipped and missing ranks are left empty
+    #[clap(long, value_parser, default_value_t = false)]
+    pub lineage: bool,
+
+    /// Collapse each call to its ancestor at this rank; calls above the rank go to the nearest standard rank
+    /// at or above them (a "species group" becomes its genus with --report-rank species).
+    /// Applies to the per-read output and the kreport, and also writes a flat abundance table (output_N.abundance.tsv)
+    #[clap(long, value_parser = clap::builder::PossibleValuesParser::new(STANDARD_RANKS))]
+    pub report_rank: Option<String>,
+
+    /// TSV file mapping taxids to reporting taxids (taxid<TAB>target_taxid). Applied to each call and to the
+    /// report counts after --report-rank
+    #[clap(long, value_parser)]
+    pub taxid_remap: Option<PathBuf>,
+}
+
+/// 分类时的 taxid 范围过滤
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ScopeArgs {
+    /// Only use hits within these taxa, comma-separated, e.g. 2,10239. Reads without such hits are unclassified
+    #[arg(long, value_delimiter = ',')]
+    pub include_taxids: Vec<u64>,
+
+    /// Ignore hits within these taxa, comma-separated, e.g. 9606
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_taxids: Vec<u64>,
+}
+
+impl ReadOutputArgs {
+    /// --report-rank 在 STANDARD_RANKS 中的位置
+    pub fn report_rank_index(&self) -> Option<usize> {
+        self.report_rank.as_deref().and_then(standard_rank_index)
+    }
+}
+
 /// Command line arguments for the classify program.
 ///
 /// This structure defines the command line arguments that are accepted by the classify program.
@@ -59,6 +104,10 @@ pub struct ClassifyArgs {
     #[clap(long)]
     pub chunk_dir: PathBuf,
 
+    /// Capacity of each virtual page when reading a Kraken 2 hash.k2d directly (used only if hash_config.k2d is absent).
+    #[clap(long = "hash-capacity", value_parser = parse_size, default_value = "1G")]
+    pub hash_capacity: usize,
+
     /// File path for outputting normal Kraken output.
     #[clap(long = "output-dir", value_parser)]
     pub kraken_output_dir: Option<PathBuf>,
@@ -105,6 +154,11 @@ pub struct ClassifyArgs {
     )]
     pub minimum_hit_groups: usize,
 
+    /// Count hit groups as the number of minimizers in the read, as earlier versions did,
+    /// instead of Kraken 2's runs of identical minimizers that hit the database.
+    #[clap(long, value_parser, default_value_t = false)]
+    pub legacy_hit_groups: bool,
+
     /// In comb. w/ -R, provide minimizer information in report
     #[clap(short = 'K', long, value_parser, default_value_t = false)]
     pub report_kmer_data: bool,
@@ -117,13 +171,19 @@ pub struct ClassifyArgs {
     #[clap(long, value_parser, default_value_t = false)]
     pub full_output: bool,
 
+    #[clap(flatten)]
+    pub read_output: ReadOutputArgs,
+
+    #[clap(flatten)]
+    pub scope: ScopeArgs,
+
     /// A list of input file paths (FASTA/FASTQ) to be processed by the classify program.
     /// Supports fasta or fastq format files (e.g., .fasta, .fastq) and gzip compressed files (e.g., .fasta.gz, .fastq.gz).
     // #[clap(short = 'F', long = "files")]
     pub input_files: Vec<String>,
 }
 
-#[derive(Parser, Debug, Clone, Copy)]
+#[derive(Parser, Debug, Clone, Copy, Serialize, Deserialize)]
 #[clap(version, about = "k-mer")]
 pub struct KLMTArgs {
     /// Set length of k-mers, k must be positive integer, k=35, k cannot be less than l
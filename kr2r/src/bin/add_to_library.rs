@@ -0,0 +1,403 @@
+use clap::Parser;
+use kr2r::args::parse_size;
+use kr2r::db::{merge_custom_nodes, write_custom_nodes};
+use kr2r::library::{
+    for_each_record, generate_library_taxonomy, header_seqid, sequence_name, LibraryAppender,
+};
+use kr2r::taxonomy::{
+    parse_custom_nodes_file, parse_nodes_file, RedirectStats, TaxidRedirect, CUSTOM_NODES_FILENAME,
+};
+use kr2r::utils::{open_text_file, read_accession2taxid, read_id_to_taxon_map};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, Result};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// 错误信息中最多列出的示例数量
+const MAX_EXAMPLES: usize = 10;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Add custom FASTA/FASTA.gz sequences to the library. Taxids come from a seqid to taxid TSV, `kraken:taxid|N` in the sequence id or NCBI accession2taxid files"
+)]
+pub struct Args {
+    /// database directory
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Tab separated seqid to taxid file (seqid<TAB>taxid). Takes precedence over `kraken:taxid|N` in the header
+    #[arg(long)]
+    pub seqid2taxid: Option<PathBuf>,
+
+    /// NCBI accession2taxid files (e.g. nucl_gb.accession2taxid.gz, nucl_wgs.accession2taxid.gz),
+    /// used for sequences whose taxid is neither in the TSV nor in the header. Can be repeated
+    #[arg(long)]
+    pub accession2taxid: Vec<PathBuf>,
+
+    /// Directory with nodes.dmp and names.dmp, copied into the database after the checks if it has no taxonomy yet
+    #[arg(long)]
+    pub taxonomy_dir: Option<PathBuf>,
+
+    /// Custom taxa for genomes without an NCBI taxid (taxid<TAB>parent taxid<TAB>rank<TAB>name).
+    /// Their taxids must not exist in nodes.dmp
+    #[arg(long)]
+    pub custom_nodes: Option<PathBuf>,
+
+    /// library fna temp file max size
+    #[arg(long = "max-file-size", value_parser = parse_size, default_value = "2G")]
+    pub max_file_size: usize,
+
+    /// Skip sequences without a valid taxid instead of failing
+    #[arg(long, default_value_t = false)]
+    pub skip_invalid: bool,
+
+    /// FASTA or FASTA.gz files to add
+    #[arg(required = true)]
+    pub input_files: Vec<PathBuf>,
+}
+
+/// 不能加入 library 的序列
+#[derive(Default)]
+struct Problems {
+    missing_taxid: Vec<String>,
+    unknown_taxid: BTreeSet<u64>,
+    duplicate: Vec<String>,
+    /// merged.dmp 重定向的和 delnodes.dmp 中已删除的 taxid
+    redirect: RedirectStats,
+    count: usize,
+}
+
+impl Problems {
+    fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn examples(items: &[String]) -> String {
+        let mut text = items
+            .iter()
+            .take(MAX_EXAMPLES)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ");
+        if items.len() > MAX_EXAMPLES {
+            text.push_str(", ...");
+        }
+        text
+    }
+
+    fn summary(&self) -> String {
+        let mut lines = Vec::new();
+        if !self.missing_taxid.is_empty() {
+            lines.push(format!(
+                "{} sequences without taxid: {}",
+                self.missing_taxid.len(),
+                Self::examples(&self.missing_taxid)
+            ));
+        }
+        if !self.unknown_taxid.is_empty() {
+            let taxids: Vec<String> = self.unknown_taxid.iter().map(|t| t.to_string()).collect();
+            lines.push(format!(
+                "{} taxids not in the taxonomy: {}",
+                taxids.len(),
+                Self::examples(&taxids)
+            ));
+        }
+        if !self.redirect.deleted.is_empty() {
+            let taxids: Vec<String> = self
+                .redirect
+                .deleted
+                .iter()
+                .map(|t| t.to_string())
+                .collect();
+            lines.push(format!(
+                "{} taxids deleted from the NCBI taxonomy: {}",
+                taxids.len(),
+                Self::examples(&taxids)
+            ));
+        }
+        if !self.duplicate.is_empty() {
+            lines.push(format!(
+                "{} sequences already in the library: {}",
+                self.duplicate.len(),
+                Self::examples(&self.duplicate)
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+/// 查找序列的 taxid 并检查, 通过检查时返回 seqid2taxid.map 中的序列名
+struct Resolver {
+    seqid_map: HashMap<String, u64>,
+    accession_map: HashMap<String, u64>,
+    taxids: HashSet<u64>,
+    redirect: TaxidRedirect,
+    known_ids: HashSet<String>,
+    kraken_taxid: regex::Regex,
+}
+
+impl Resolver {
+    fn taxid(&self, seqid: &str) -> Option<u64> {
+        self.seqid_map
+            .get(seqid)
+            .copied()
+            .or_else(|| {
+                self.kraken_taxid
+                    .captures(seqid)
+                    .and_then(|caps| caps[1].parse().ok())
+            })
+            .or_else(|| self.accession_map.get(seqid).copied())
+    }
+
+    /// header 为去掉 '>' 的标题行
+    fn resolve(
+        &self,
+        header: &str,
+        added: &HashSet<String>,
+        problems: &mut Problems,
+    ) -> Option<(String, u64)> {
+        let seqid = header_seqid(header);
+        let Some(taxid) = self.taxid(seqid) else {
+            problems.missing_taxid.push(seqid.to_string());
+            problems.count += 1;
+            return None;
+        };
+        let Some(taxid) = self.redirect.resolve_counted(taxid, &mut problems.redirect) else {
+            problems.count += 1;
+            return None;
+        };
+        if !self.taxids.contains(&taxid) {
+            problems.unknown_taxid.insert(taxid);
+            problems.count += 1;
+            return None;
+        }
+        let name = sequence_name(taxid, seqid);
+        if self.known_ids.contains(&name) || added.contains(&name) {
+            problems.duplicate.push(seqid.to_string());
+            problems.count += 1;
+            return None;
+        }
+        Some((name, taxid))
+    }
+}
+
+/// 读取 seqid<TAB>taxid 文件
+fn read_seqid_map(path: &Path) -> Result<HashMap<String, u64>> {
+    let mut map = HashMap::new();
+    for (i, line) in open_text_file(path)?.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let (seqid, taxid) = (
+            fields.next(),
+            fields.next().map(|t| t.trim().parse::<u64>()),
+        );
+        match (seqid, taxid) {
+            (Some(seqid), Some(Ok(taxid))) => {
+                map.insert(seqid.trim().to_string(), taxid);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} line {}: expected seqid<TAB>taxid", path, i + 1),
+                ))
+            }
+        }
+    }
+    Ok(map)
+}
+
+/// 数据库中已有 taxonomy 时使用数据库的, 否则使用 taxonomy_dir. 只返回目录, 不写入数据库
+fn taxonomy_source(database: &Path, taxonomy_dir: Option<&PathBuf>) -> Result<PathBuf> {
+    let dst_tax_dir = database.join("taxonomy");
+    if dst_tax_dir.join("nodes.dmp").exists() && dst_tax_dir.join("names.dmp").exists() {
+        return Ok(dst_tax_dir);
+    }
+    match taxonomy_dir {
+        Some(dir) => Ok(dir.clone()),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "{:?} not found, use --taxonomy-dir to provide it",
+                dst_tax_dir.join("nodes.dmp")
+            ),
+        )),
+    }
+}
+
+/// 把检查时使用的 taxonomy 文件复制到数据库中
+fn install_taxonomy(source: &Path, database: &Path) -> Result<PathBuf> {
+    let dst_tax_dir = database.join("taxonomy");
+    if source == dst_tax_dir {
+        return Ok(dst_tax_dir);
+    }
+    fs::create_dir_all(&dst_tax_dir)?;
+    for name in [
+        "nodes.dmp",
+        "names.dmp",
+        "merged.dmp",
+        "delnodes.dmp",
+        CUSTOM_NODES_FILENAME,
+    ] {
+        if source.join(name).exists() {
+            fs::copy(source.join(name), dst_tax_dir.join(name))?;
+        }
+    }
+    Ok(dst_tax_dir)
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let start = Instant::now();
+    let database = &args.database;
+    let library_dir = database.join("library");
+    let taxonomy_dir = taxonomy_source(database, args.taxonomy_dir.as_ref())?;
+
+    // 自定义节点先在内存中合并检查, 序列检查通过后再写入数据库
+    let custom_nodes = match &args.custom_nodes {
+        Some(filename) => Some(merge_custom_nodes(filename, &taxonomy_dir)?),
+        None => None,
+    };
+
+    let (parent_map, _, _, _) = parse_nodes_file(taxonomy_dir.join("nodes.dmp"))?;
+    let mut taxids: HashSet<u64> = parent_map.into_keys().collect();
+    let custom_nodes_filename = taxonomy_dir.join(CUSTOM_NODES_FILENAME);
+    match &custom_nodes {
+        Some((nodes, _)) => taxids.extend(nodes.iter().map(|node| node.taxid)),
+        None if custom_nodes_filename.exists() => taxids.extend(
+            parse_custom_nodes_file(&custom_nodes_filename)?
+                .iter()
+                .map(|node| node.taxid),
+        ),
+        None => {}
+    }
+    let seqid2taxid_path = database.join("seqid2taxid.map");
+    let known_ids: HashSet<String> = if seqid2taxid_path.exists() {
+        read_id_to_taxon_map(&seqid2taxid_path)?
+            .into_keys()
+            .collect()
+    } else {
+        HashSet::new()
+    };
+    let mut resolver = Resolver {
+        seqid_map: match &args.seqid2taxid {
+            Some(path) => read_seqid_map(path)?,
+            None => HashMap::new(),
+        },
+        taxids,
+        redirect: TaxidRedirect::from_dir(&taxonomy_dir)?,
+        known_ids,
+        accession_map: HashMap::new(),
+        kraken_taxid: regex::Regex::new(r"kraken:taxid\|(\d+)").unwrap(),
+    };
+
+    // 只保留 library 中还没有 taxid 的序列的 accession, 不把整个 accession2taxid 读入内存
+    if !args.accession2taxid.is_empty() {
+        let mut accessions = HashSet::new();
+        for path in &args.input_files {
+            for_each_record(
+                path,
+                |header| {
+                    let seqid = header_seqid(header);
+                    if resolver.taxid(seqid).is_none() {
+                        accessions.insert(seqid.to_string());
+                    }
+                    None
+                },
+                |_, _| Ok(()),
+            )?;
+        }
+        resolver.accession_map = read_accession2taxid(&args.accession2taxid, &accessions)?;
+        println!(
+            "accession2taxid: found {} of {} accessions",
+            resolver.accession_map.len(),
+            accessions.len()
+        );
+    }
+
+    // 先检查全部序列, 有问题时不写入任何内容
+    if !args.skip_invalid {
+        let mut problems = Problems::default();
+        let mut added = HashSet::new();
+        for path in &args.input_files {
+            for_each_record(
+                path,
+                |header| {
+                    let (name, _) = resolver.resolve(header, &added, &mut problems)?;
+                    added.insert(name);
+                    None
+                },
+                |_, _| Ok(()),
+            )?;
+        }
+        if !problems.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "nothing added, {} sequences can not be added (use --skip-invalid to skip them):\n{}",
+                    problems.count,
+                    problems.summary()
+                ),
+            ));
+        }
+    }
+
+    let taxonomy_dir = install_taxonomy(&taxonomy_dir, database)?;
+    if let Some((nodes, count)) = &custom_nodes {
+        if *count > 0 {
+            write_custom_nodes(&taxonomy_dir, nodes)?;
+            println!("installed {} custom taxa", count);
+        }
+    }
+
+    let mut problems = Problems::default();
+    let mut added = HashSet::new();
+    let mut taxa = HashSet::new();
+    let mut appender = LibraryAppender::new(database, args.max_file_size as u64)?;
+    for path in &args.input_files {
+        appender.append(path, |header| {
+            let (name, taxid) = resolver.resolve(header, &added, &mut problems)?;
+            added.insert(name.clone());
+            taxa.insert(taxid);
+            Some((name, taxid))
+        })?;
+    }
+    appender.finish()?;
+
+    if !problems.redirect.merged.is_empty() {
+        eprintln!(
+            "{} merged taxids redirected to their new taxids",
+            problems.redirect.merged.len()
+        );
+    }
+    if !problems.is_empty() {
+        eprintln!(
+            "skipped {} sequences:\n{}",
+            problems.count,
+            problems.summary()
+        );
+    }
+    println!(
+        "added {} sequences of {} taxa to {:?}",
+        added.len(),
+        taxa.len(),
+        library_dir
+    );
+
+    // 重新生成 taxo.k2d, 包含新加入的 taxid
+    generate_library_taxonomy(database)?;
+    println!("add to library took: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
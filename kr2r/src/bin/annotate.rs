@@ -1,5 +1,6 @@
 use clap::Parser;
-use kr2r::compact_hash::{CHTable, Compact, HashConfig, Row, Slot};
+use kr2r::args::parse_size;
+use kr2r::compact_hash::{CHTable, Compact, HashConfig, HashFiles, Row, Slot};
 use kr2r::utils::{find_and_sort_files, open_file};
 use seqkmer::buffer_read_parallel;
 use std::collections::HashMap;
@@ -30,6 +31,10 @@ pub struct Args {
     #[clap(long)]
     pub chunk_dir: PathBuf,
 
+    /// Capacity of each virtual page when reading a Kraken 2 hash.k2d directly (used only if hash_config.k2d is absent).
+    #[clap(long = "hash-capacity", value_parser = parse_size, default_value = "1G")]
+    pub hash_capacity: usize,
+
     #[clap(long, default_value_t = BATCH_SIZE)]
     pub batch_size: usize,
 
@@ -168,7 +173,8 @@ where
 fn process_chunk_file<P: AsRef<Path>>(
     args: &Args,
     chunk_file: P,
-    hash_files: &Vec<PathBuf>,
+    config: HashConfig,
+    hash_files: &HashFiles,
 ) -> Result<()> {
     let file = open_file(chunk_file)?;
     let mut reader = BufReader::new(file);
@@ -178,7 +184,6 @@ fn process_chunk_file<P: AsRef<Path>>(
     let start = Instant::now();
 
     println!("start load table...");
-    let config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
     let chtm = CHTable::from_range(config, hash_files, page_index, page_index + 1)?;
 
     // 计算持续时间
@@ -200,13 +205,14 @@ fn process_chunk_file<P: AsRef<Path>>(
 
 pub fn run(args: Args) -> Result<()> {
     let chunk_files = find_and_sort_files(&args.chunk_dir, "sample", ".k2", true)?;
-    let hash_files = find_and_sort_files(&args.database, "hash", ".k2d", true)?;
+    let config = HashConfig::from_database(&args.database, args.hash_capacity)?;
+    let hash_files = HashFiles::from_database(&args.database, &config)?;
 
     // 开始计时
     let start = Instant::now();
     println!("annotate start...");
     for chunk_file in &chunk_files {
-        process_chunk_file(&args, chunk_file, &hash_files)?;
+        process_chunk_file(&args, chunk_file, config, &hash_files)?;
         let _ = std::fs::remove_file(chunk_file);
     }
     // 计算持续时间
@@ -1,10 +1,9 @@
 // 使用时需要引用模块路径
 use clap::Parser;
+use kr2r::checkpoint::Checkpoint;
 use kr2r::compact_hash::HashConfig;
-use kr2r::db::process_k2file;
+use kr2r::db::{build_page, finalize};
 use kr2r::taxonomy::Taxonomy;
-use kr2r::utils::find_and_trans_files;
-use std::fs::remove_file;
 use std::path::PathBuf;
 use std::time::Instant;
 
@@ -23,45 +22,43 @@ pub fn run(database: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
     let hash_filename = k2d_dir.join("hash_config.k2d");
 
     let mut hash_config = HashConfig::from_hash_header(&hash_filename)?;
+    let checkpoint = Checkpoint::new(k2d_dir)?;
+    // 没有 chunk_db 指纹时所有分页都重新生成, 元素数量直接求和
+    let fingerprint = checkpoint.chunk_fingerprint();
+    let mut size = 0;
 
     // 开始计时
     let start = Instant::now();
 
-    let chunk_files = find_and_trans_files(&k2d_dir, "chunk", ".k2", true)?;
-
-    let mut size: usize = 0;
-
     println!("start process k2 files...");
-    for (i, chunk_file) in &chunk_files {
-        // 计算持续时间
-        let count = process_k2file(
+    for page_index in 1..=hash_config.partition {
+        size += build_page(
+            k2d_dir,
             hash_config,
-            &k2d_dir,
-            &chunk_file,
             &taxonomy,
-            hash_config.hash_capacity,
-            *i,
+            &checkpoint,
+            fingerprint.as_deref(),
+            page_index,
         )?;
-        size += count;
         let duration = start.elapsed();
         println!(
             "process chunk file {:?}/{:}: duration: {:?}",
-            i, hash_config.partition, duration
+            page_index, hash_config.partition, duration
         );
     }
 
-    hash_config.size = size;
-    hash_config.write_to_file(&hash_filename)?;
+    if fingerprint.is_some() {
+        finalize(database)?;
+    } else {
+        hash_config.size = size;
+        hash_config.write_to_file(&hash_filename)?;
+    }
 
     // 计算持续时间
     let duration = start.elapsed();
     // 打印运行时间
     println!("build k2 db took: {:?}", duration);
 
-    for (_, chunk_file) in &chunk_files {
-        remove_file(chunk_file)?;
-    }
-
     Ok(())
 }
 
@@ -0,0 +1,115 @@
+use clap::Parser;
+use kr2r::checkpoint::Checkpoint;
+use kr2r::compact_hash::HashConfig;
+use kr2r::db::{build_page, finalize};
+use kr2r::manifest::{Manifest, MANIFEST_FILENAME};
+use kr2r::taxonomy::Taxonomy;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// 解析分页范围: "3" 或 "1-10" (包含两端, 从1开始)
+fn parse_page_range(s: &str) -> Result<RangeInclusive<usize>, String> {
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("invalid page number: {}", v))
+    };
+    let (start, end) = match s.split_once('-') {
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => {
+            let page = parse(s)?;
+            (page, page)
+        }
+    };
+    if start > end {
+        return Err(format!("invalid page range: {}", s));
+    }
+    Ok(start..=end)
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Build hash pages from chunk files, e.g. one page per cluster job. Run `build --chunk-only` first and `build-finalize` after all pages are built."
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Page number (1-based) or inclusive range, e.g. `3` or `1-10`
+    #[arg(long, value_parser = parse_page_range, required = true)]
+    pub page: RangeInclusive<usize>,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Sum the sizes of the built hash pages into hash_config.k2d and write the manifest"
+)]
+pub struct FinalizeArgs {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+}
+
+pub fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let database = &args.database;
+    let hash_config = HashConfig::from_hash_header(database.join("hash_config.k2d"))?;
+    if *args.page.end() > hash_config.partition {
+        return Err(format!(
+            "page {} is out of range, the database has {} pages",
+            args.page.end(),
+            hash_config.partition
+        )
+        .into());
+    }
+    let taxonomy = Taxonomy::from_file(database.join("taxo.k2d"))?;
+    let checkpoint = Checkpoint::new(database)?;
+    let fingerprint = checkpoint.require_chunk_fingerprint()?;
+
+    let start = Instant::now();
+    for page_index in args.page {
+        let size = build_page(
+            database,
+            hash_config,
+            &taxonomy,
+            &checkpoint,
+            Some(&fingerprint),
+            page_index,
+        )?;
+        println!(
+            "hash page {}/{}: {} cells, duration: {:?}",
+            page_index,
+            hash_config.partition,
+            size,
+            start.elapsed()
+        );
+    }
+    Ok(())
+}
+
+pub fn run_finalize(args: FinalizeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let database = &args.database;
+    let hash_config = finalize(database)?;
+    println!(
+        "hash table size: {} of capacity {} in {} pages",
+        hash_config.size, hash_config.capacity, hash_config.partition
+    );
+
+    let params = Checkpoint::new(database)?.load_params()?;
+    let manifest = Manifest::collect(database, params)?;
+    manifest.write_to_file(database.join(MANIFEST_FILENAME))?;
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
@@ -1,9 +1,10 @@
 // 使用时需要引用模块路径
 use clap::Parser;
 use kr2r::args::{parse_size, Build};
+use kr2r::checkpoint::{Checkpoint, Stage};
 use kr2r::compact_hash::HashConfig;
 use kr2r::db::{convert_fna_to_k2_format, get_bits_for_taxid};
-use kr2r::taxonomy::Taxonomy;
+use kr2r::taxonomy::{redirect_id_map, TaxidRedirect, Taxonomy};
 use kr2r::utils::{
     create_partition_files, create_partition_writers, find_files, get_file_limit,
     read_id_to_taxon_map, set_fd_limit,
@@ -26,9 +27,14 @@ pub fn run(args: Args, required_capacity: usize) -> Result<(), Box<dyn std::erro
     let file_num_limit = get_file_limit();
     let meros = args.build.klmt.as_meros();
     let k2d_dir = &args.build.database;
+    // 重新生成 chunk 文件, 之前的 chunk_db 标记和分页标记都已过期
+    Checkpoint::new(k2d_dir)?.invalidate_from(Stage::ChunkDb)?;
 
     let id_to_taxon_map_filename = args.build.database.join("seqid2taxid.map");
-    let id_to_taxon_map = read_id_to_taxon_map(&id_to_taxon_map_filename)?;
+    let mut id_to_taxon_map = read_id_to_taxon_map(&id_to_taxon_map_filename)?;
+    // 与生成 taxo.k2d 时一样重定向合并过的 taxid (警告已在生成 taxo.k2d 时打印)
+    let redirect = TaxidRedirect::from_dir(k2d_dir.join("taxonomy"))?;
+    redirect_id_map(&mut id_to_taxon_map, &redirect);
 
     let taxonomy_filename = k2d_dir.join("taxo.k2d");
     let taxonomy = Taxonomy::from_file(taxonomy_filename)?;
@@ -0,0 +1,1034 @@
+use clap::{Args as ClapArgs, Parser, Subcommand};
+use kr2r::archive::{Archive, ArchiveWriter, Compression};
+use kr2r::args::parse_size;
+use kr2r::checkpoint::{check_no_pending_migration, MIGRATE_MARKER};
+use kr2r::compact_hash::{HashConfig, HashFiles, PageReader};
+use kr2r::db::{get_bits_for_taxid, load_ncbi_taxonomy};
+use kr2r::k2d::{self, FileKind, K2dWriter};
+use kr2r::manifest::{index_files, modified_time, BuildParams, Manifest, MANIFEST_FILENAME};
+use kr2r::taxonomy::{NCBITaxonomy, TaxidRedirect, Taxonomy, CUSTOM_NODES_FILENAME};
+use kr2r::utils::{find_and_sort_files, read_id_to_taxon_map};
+use kr2r::IndexOptions;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::{self, create_dir_all, File};
+use std::io::{self, BufWriter, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const BATCH_SIZE: usize = 1024 * 1024;
+/// 每个分页最多列出的无效单元数
+const MAX_REPORTED_CELLS: usize = 10;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Database maintenance: manifest info, integrity verification, pack, unpack and taxonomy migration"
+)]
+pub struct Args {
+    #[clap(subcommand)]
+    pub cmd: DbCommands,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DbCommands {
+    /// Print the database manifest and validate it against the files present
+    Info(InfoArgs),
+    /// Check that every hash page is present and intact, recompute checksums and validate the taxids stored in cells
+    Verify(VerifyArgs),
+    /// Pack a database into a single archive, which can be used directly as `--db`
+    Pack(PackArgs),
+    /// Unpack a database archive into a directory
+    Unpack(UnpackArgs),
+    /// Move a built database to a newer NCBI taxdump by rewriting the taxids stored in the hash pages and taxo.k2d
+    Migrate(MigrateArgs),
+}
+
+#[derive(ClapArgs, Debug, Clone)]
+pub struct InfoArgs {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Print the raw manifest JSON instead of the summary
+    #[clap(long, value_parser, default_value_t = false)]
+    pub json: bool,
+
+    /// Recompute the checksums of the library, seqid2taxid.map and taxonomy files (only for databases built with --source-checksums)
+    #[clap(long, value_parser, default_value_t = false)]
+    pub checksums: bool,
+}
+
+#[derive(ClapArgs, Debug, Clone)]
+pub struct VerifyArgs {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Capacity of each virtual page when reading a Kraken 2 hash.k2d directly (used only if hash_config.k2d is absent).
+    #[clap(long = "hash-capacity", value_parser = parse_size, default_value = "1G")]
+    pub hash_capacity: usize,
+}
+
+#[derive(ClapArgs, Debug, Clone)]
+pub struct PackArgs {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Output archive file
+    #[clap(short, long, required = true)]
+    pub output: PathBuf,
+
+    /// Compress each hash page with zstd
+    #[clap(long, value_parser, default_value_t = false)]
+    pub zstd: bool,
+
+    /// zstd compression level
+    #[clap(long, default_value_t = 3)]
+    pub level: i32,
+}
+
+#[derive(ClapArgs, Debug, Clone)]
+pub struct UnpackArgs {
+    /// Database archive written by `db pack`
+    #[clap(short, long, required = true)]
+    pub input: PathBuf,
+
+    /// Output database directory
+    #[clap(short, long, required = true)]
+    pub output_dir: PathBuf,
+}
+
+#[derive(ClapArgs, Debug, Clone)]
+pub struct MigrateArgs {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Directory with the new NCBI taxdump: nodes.dmp, names.dmp and, if present, merged.dmp and delnodes.dmp
+    #[arg(long = "taxonomy-dir", required = true)]
+    pub taxonomy_dir: PathBuf,
+
+    /// Report of the taxa whose placement changed (default: <db>/taxonomy_migration.tsv)
+    #[clap(long)]
+    pub report: Option<PathBuf>,
+}
+
+fn write_summary<W: Write>(writer: &mut W, manifest: &Manifest) -> Result<()> {
+    let build = &manifest.build;
+    let klmt = &build.build.klmt;
+    writeln!(writer, "Kun-peng version: {}", manifest.tool_version)?;
+    writeln!(writer, "Created: {}", manifest.created)?;
+    writeln!(
+        writer,
+        "Build: k = {}, l = {}, minimizer spaces = {}, toggle mask = {:#x}, min clear hash value = {}",
+        klmt.k_mer,
+        klmt.l_mer,
+        klmt.minimizer_spaces,
+        klmt.toggle_mask,
+        klmt.min_clear_hash_value
+            .map_or("none".to_string(), |v| v.to_string())
+    )?;
+    writeln!(
+        writer,
+        "Build: requested bits for taxid = {}, threads = {}, max n = {}, load factor = {}, max file size = {}, hash capacity = {}",
+        build.build.requested_bits_for_taxid,
+        build.build.threads,
+        build.max_n,
+        build.load_factor,
+        build.max_file_size,
+        build.hash_capacity
+    )?;
+    if let Some(download_dir) = &build.download_dir {
+        writeln!(writer, "Download dir: {:?}", download_dir)?;
+    }
+    writeln!(
+        writer,
+        "Taxonomy: {} nodes, date {}",
+        manifest.taxonomy.node_count,
+        manifest.taxonomy.date.as_deref().unwrap_or("unknown")
+    )?;
+    let table = &manifest.hash_table;
+    writeln!(
+        writer,
+        "Hash table: capacity {}, size {}, {} pages of {}, value bits {}, version {}",
+        table.capacity,
+        table.size,
+        table.partition,
+        table.hash_capacity,
+        table.value_bits,
+        table.version
+    )?;
+    for assembly in &manifest.assemblies {
+        let levels: Vec<String> = assembly
+            .asm_levels
+            .iter()
+            .map(|(level, count)| format!("{}: {}", level, count))
+            .collect();
+        writeln!(
+            writer,
+            "Assembly summary: {} {} ({} genomes; {}) crc32 {}",
+            assembly.group,
+            assembly.site,
+            assembly.genomes,
+            levels.join(", "),
+            assembly.file.crc32.as_deref().unwrap_or("-")
+        )?;
+    }
+    for entry in manifest.sources.iter().chain(manifest.index_files.iter()) {
+        match (&entry.crc32, &entry.modified) {
+            (Some(crc32), _) => writeln!(
+                writer,
+                "File: {}\t{}\tcrc32 {}",
+                entry.path, entry.size, crc32
+            )?,
+            (None, modified) => writeln!(
+                writer,
+                "File: {}\t{}\tmodified {}",
+                entry.path,
+                entry.size,
+                modified.as_deref().unwrap_or("unknown")
+            )?,
+        }
+    }
+    Ok(())
+}
+
+fn info(args: InfoArgs) -> Result<()> {
+    let manifest = Manifest::from_database(&args.database)?;
+
+    let mut writer = io::stdout().lock();
+    if args.json {
+        serde_json::to_writer_pretty(&mut writer, &manifest)?;
+        writeln!(writer)?;
+    } else {
+        write_summary(&mut writer, &manifest)?;
+    }
+    writer.flush()?;
+
+    let problems = manifest.validate(&args.database, args.checksums)?;
+    if problems.is_empty() {
+        eprintln!("manifest matches the database files");
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "database {:?} does not match its manifest: {} problem(s)",
+            args.database,
+            problems.len()
+        ),
+    ))
+}
+
+/// 单个分页的校验结果
+struct PageCheck {
+    name: String,
+    capacity: usize,
+    size: usize,
+    checksum: Option<u32>,
+    problems: Vec<String>,
+}
+
+/// 逐个单元检查分页: 分页头部, 校验和, 以及单元中的 taxid 是否为有效的内部 id
+fn verify_page(
+    config: &HashConfig,
+    hash_files: &HashFiles,
+    page_index: usize,
+    node_count: usize,
+) -> PageCheck {
+    let name = match hash_files {
+        HashFiles::Pages(files) => file_name(&files[page_index]),
+        HashFiles::Kraken2 { path, .. } => format!("{} page {}", file_name(path), page_index + 1),
+        HashFiles::Packed { pages, .. } => pages[page_index].name.clone(),
+    };
+    let mut check = PageCheck {
+        name,
+        capacity: 0,
+        size: 0,
+        checksum: None,
+        problems: Vec::new(),
+    };
+    if let HashFiles::Pages(files) = hash_files {
+        if !files[page_index].exists() {
+            check.problems.push("missing".to_string());
+            return check;
+        }
+    }
+    let page = match hash_files.open_page(page_index) {
+        Ok(page) => page,
+        Err(e) => {
+            check.problems.push(e.to_string());
+            return check;
+        }
+    };
+    if let Err(e) = scan_page(config, page, page_index, node_count, &mut check) {
+        check.problems.push(e.to_string());
+    }
+    check
+}
+
+fn scan_page(
+    config: &HashConfig,
+    mut page: PageReader,
+    page_index: usize,
+    node_count: usize,
+    check: &mut PageCheck,
+) -> Result<()> {
+    let page_start = page_index * config.hash_capacity;
+    let expected_capacity = config
+        .hash_capacity
+        .min(config.capacity.saturating_sub(page_start));
+    if page.index != page_index + 1 {
+        check.problems.push(format!(
+            "page index is {}, expected {}",
+            page.index,
+            page_index + 1
+        ));
+    }
+    if page.capacity != expected_capacity {
+        check.problems.push(format!(
+            "page capacity is {}, expected {}",
+            page.capacity, expected_capacity
+        ));
+    }
+    check.capacity = page.capacity;
+    check.checksum = page.checksum;
+
+    let value_mask = config.value_mask as u32;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut invalid = 0;
+    let mut buffer = vec![0u8; BATCH_SIZE * 4];
+    let mut offset = 0;
+    while offset < page.capacity {
+        let cells = (page.capacity - offset).min(BATCH_SIZE);
+        let bytes = &mut buffer[..cells * 4];
+        page.reader.read_exact(bytes)?;
+        hasher.update(bytes);
+
+        for (i, chunk) in bytes.chunks_exact(4).enumerate() {
+            let cell = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            if cell == 0 {
+                continue;
+            }
+            check.size += 1;
+            let taxid = cell & value_mask;
+            if taxid == 0 || taxid as usize >= node_count {
+                if invalid < MAX_REPORTED_CELLS {
+                    check.problems.push(format!(
+                        "cell {} has taxid {}, valid internal ids are 1..{}",
+                        offset + i,
+                        taxid,
+                        node_count
+                    ));
+                }
+                invalid += 1;
+            }
+        }
+        offset += cells;
+    }
+    if invalid > MAX_REPORTED_CELLS {
+        check.problems.push(format!(
+            "{} more cells with invalid taxids",
+            invalid - MAX_REPORTED_CELLS
+        ));
+    }
+    page.verify_checksum(hasher)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+/// 输出一条检查结果, 返回问题数量
+fn report<W: Write>(
+    writer: &mut W,
+    name: &str,
+    detail: &str,
+    problems: &[String],
+) -> Result<usize> {
+    if problems.is_empty() {
+        writeln!(writer, "OK\t{}\t{}", name, detail)?;
+    }
+    for problem in problems {
+        writeln!(writer, "FAIL\t{}\t{}", name, problem)?;
+    }
+    Ok(problems.len())
+}
+
+fn verify(args: VerifyArgs) -> Result<()> {
+    let database = &args.database;
+    let mut writer = io::stdout().lock();
+    let mut failures = 0;
+
+    let config = match HashConfig::from_database(database, args.hash_capacity) {
+        Ok(config) => config,
+        Err(e) => {
+            report(&mut writer, "hash_config.k2d", "", &[e.to_string()])?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("database {:?} cannot be verified", database),
+            ));
+        }
+    };
+    // 归档中的文件在读取时已经校验过目录和头部中的校验和
+    let packed = Archive::is_archive(database);
+    let headered = |name: &str, kind: FileKind| match k2d::open(database.join(name), kind) {
+        _ if packed => "packed".to_string(),
+        Ok((_, Some(header))) => format!("crc32 {:08x}", header.checksum),
+        _ => "no checksum (legacy format)".to_string(),
+    };
+    let has_config = packed || database.join("hash_config.k2d").exists();
+    if has_config {
+        failures += report(
+            &mut writer,
+            "hash_config.k2d",
+            &format!(
+                "capacity {}, size {}, {} pages of {}; {}",
+                config.capacity,
+                config.size,
+                config.partition,
+                config.hash_capacity,
+                headered("hash_config.k2d", FileKind::HashConfig)
+            ),
+            &[],
+        )?;
+    }
+
+    failures += match IndexOptions::read_index_options(database.join("opts.k2d")) {
+        Ok(_) => report(
+            &mut writer,
+            "opts.k2d",
+            &headered("opts.k2d", FileKind::IndexOptions),
+            &[],
+        )?,
+        Err(e) => report(&mut writer, "opts.k2d", "", &[e.to_string()])?,
+    };
+
+    let node_count = match Taxonomy::from_file(database.join("taxo.k2d")) {
+        Ok(taxonomy) => {
+            report(
+                &mut writer,
+                "taxo.k2d",
+                &format!(
+                    "{} nodes; {}",
+                    taxonomy.node_count(),
+                    headered("taxo.k2d", FileKind::Taxonomy)
+                ),
+                &[],
+            )?;
+            Some(taxonomy.node_count())
+        }
+        Err(e) => {
+            failures += report(&mut writer, "taxo.k2d", "", &[e.to_string()])?;
+            None
+        }
+    };
+
+    // 按 hash_config.k2d 的 partition 列出应有的分页, 而不是按目录中实际存在的文件
+    let hash_files = if packed {
+        match HashFiles::from_database(database, &config) {
+            Ok(hash_files) => hash_files,
+            Err(e) => {
+                report(&mut writer, "hash pages", "", &[e.to_string()])?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("database {:?} failed verification", database),
+                ));
+            }
+        }
+    } else if has_config {
+        HashFiles::Pages(
+            (1..=config.partition)
+                .map(|i| database.join(format!("hash_{}.k2d", i)))
+                .collect(),
+        )
+    } else {
+        HashFiles::from_database(database, &config)?
+    };
+    if packed && hash_files.len() != config.partition {
+        failures += report(
+            &mut writer,
+            "hash pages",
+            "",
+            &[format!(
+                "archive holds {} pages, hash_config.k2d lists {}",
+                hash_files.len(),
+                config.partition
+            )],
+        )?;
+    }
+    if has_config && !packed {
+        let extra: Vec<String> = find_and_sort_files(database, "hash", ".k2d", false)?
+            .iter()
+            .map(|path| file_name(path))
+            .filter(|name| {
+                name.trim_start_matches("hash_")
+                    .trim_end_matches(".k2d")
+                    .parse::<usize>()
+                    .is_ok_and(|i| i > config.partition)
+            })
+            .collect();
+        for name in extra {
+            failures += report(
+                &mut writer,
+                &name,
+                "",
+                &[format!(
+                    "not listed in hash_config.k2d ({} pages)",
+                    config.partition
+                )],
+            )?;
+        }
+    }
+
+    // taxo.k2d 无法读取时, 不检查单元中的 taxid
+    let taxid_limit = node_count.unwrap_or(usize::MAX);
+    let checks: Vec<PageCheck> = (0..hash_files.len())
+        .into_par_iter()
+        .map(|page_index| verify_page(&config, &hash_files, page_index, taxid_limit))
+        .collect();
+    let mut size = 0;
+    let mut complete = true;
+    for check in &checks {
+        size += check.size;
+        complete &= check.problems.is_empty();
+        let detail = format!(
+            "{} cells, {} used; {}",
+            check.capacity,
+            check.size,
+            check
+                .checksum
+                .map_or("no checksum (legacy format)".to_string(), |c| format!(
+                    "crc32 {:08x}",
+                    c
+                ))
+        );
+        failures += report(&mut writer, &check.name, &detail, &check.problems)?;
+    }
+    if complete && has_config && size != config.size {
+        failures += report(
+            &mut writer,
+            "hash_config.k2d",
+            "",
+            &[format!(
+                "size is {}, but the pages hold {} cells",
+                config.size, size
+            )],
+        )?;
+    }
+
+    let has_manifest = if packed {
+        Archive::open(database)?.entry(MANIFEST_FILENAME).is_some()
+    } else {
+        database.join(MANIFEST_FILENAME).exists()
+    };
+    if has_manifest {
+        let problems = Manifest::from_database(database)
+            .and_then(|manifest| manifest.validate(database, false))
+            .unwrap_or_else(|e| vec![e.to_string()]);
+        failures += report(&mut writer, MANIFEST_FILENAME, "matches", &problems)?;
+    }
+    writer.flush()?;
+
+    if failures > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "database {:?} failed verification: {} problem(s)",
+                database, failures
+            ),
+        ));
+    }
+    eprintln!("database {:?} verified", database);
+    Ok(())
+}
+
+fn pack(args: PackArgs) -> Result<()> {
+    if args.output.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("archive {:?} already exists", args.output),
+        ));
+    }
+    check_no_pending_migration(&args.database)?;
+    let config = HashConfig::from_hash_header(args.database.join("hash_config.k2d"))?;
+    let mut files = index_files(&args.database)?;
+    if files.len() != config.partition + 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "expected {} hash files, found {}",
+                config.partition,
+                files.len() - 3
+            ),
+        ));
+    }
+    let manifest_path = args.database.join(MANIFEST_FILENAME);
+    if manifest_path.exists() {
+        Manifest::from_file(&manifest_path)?;
+    }
+
+    let start = Instant::now();
+    let mut writer = ArchiveWriter::create(&args.output)?;
+    let (mut len, mut stored_len) = (0, 0);
+    for (path, kind) in files.drain(..) {
+        let compression = if args.zstd && kind == FileKind::Page {
+            Compression::Zstd
+        } else {
+            Compression::None
+        };
+        let entry = writer.add_file(&file_name(&path), &path, compression, args.level)?;
+        len += entry.len;
+        stored_len += entry.stored_len;
+    }
+    if manifest_path.exists() {
+        writer.add_file(MANIFEST_FILENAME, &manifest_path, Compression::None, 0)?;
+    }
+    let entries = writer.finish()?;
+
+    println!(
+        "packed {} files ({} bytes) into {:?} ({} bytes)",
+        entries.len(),
+        len,
+        args.output,
+        stored_len
+    );
+    println!("pack took: {:?}", start.elapsed());
+    Ok(())
+}
+
+fn unpack(args: UnpackArgs) -> Result<()> {
+    let archive = Archive::open(&args.input)?;
+    create_dir_all(&args.output_dir)?;
+    for entry in &archive.entries {
+        if args.output_dir.join(&entry.name).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", args.output_dir.join(&entry.name)),
+            ));
+        }
+    }
+
+    let start = Instant::now();
+    for entry in &archive.entries {
+        let path = args.output_dir.join(&entry.name);
+        let mut reader = archive.open_entry(entry)?;
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let mut hasher = crc32fast::Hasher::new();
+        let mut len = 0u64;
+        let mut buffer = vec![0u8; BATCH_SIZE];
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            writer.write_all(&buffer[..n])?;
+            len += n as u64;
+        }
+        writer.flush()?;
+        archive.check_entry(entry, len, hasher.finalize())?;
+    }
+
+    println!(
+        "unpacked {} files into {:?}",
+        archive.entries.len(),
+        args.output_dir
+    );
+    println!("unpack took: {:?}", start.elapsed());
+    Ok(())
+}
+
+/// taxdump 中迁移时使用的文件, nodes.dmp 和 names.dmp 必须存在
+const TAXDUMP_FILES: [&str; 4] = ["nodes.dmp", "names.dmp", "merged.dmp", "delnodes.dmp"];
+/// 迁移时准备新 taxonomy 的临时目录
+const MIGRATE_DIR: &str = "taxonomy.migrate";
+
+/// 把新的 taxdump 和数据库中的自定义节点复制到 staging 目录
+fn stage_taxonomy(database: &Path, taxonomy_dir: &Path, staging: &Path) -> Result<()> {
+    create_dir_all(staging)?;
+    let custom_nodes = database.join("taxonomy").join(CUSTOM_NODES_FILENAME);
+    let files = TAXDUMP_FILES
+        .iter()
+        .map(|name| (taxonomy_dir.join(name), *name))
+        .chain(std::iter::once((custom_nodes, CUSTOM_NODES_FILENAME)));
+    for (source, name) in files {
+        let target = staging.join(name);
+        if source.exists() {
+            fs::copy(&source, &target)?;
+        } else if name == "nodes.dmp" || name == "names.dmp" {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{:?} not found", source),
+            ));
+        } else if target.exists() {
+            fs::remove_file(&target)?;
+        }
+    }
+    Ok(())
+}
+
+/// 用 mapping (旧内部 id -> 新内部 id) 改写分页中单元的 taxid, minimizer 部分保持不变.
+/// 结果写到 target, 返回改变了 taxid 的单元数
+fn migrate_page(
+    config: &HashConfig,
+    hash_files: &HashFiles,
+    page_index: usize,
+    mapping: &[u32],
+    target: &Path,
+) -> Result<usize> {
+    let mut page = hash_files.open_page(page_index)?;
+    let mut writer = K2dWriter::new(BufWriter::new(File::create(target)?), FileKind::Page)?;
+    writer.write_all(&(page.index as u64).to_le_bytes())?;
+    writer.write_all(&(page.capacity as u64).to_le_bytes())?;
+
+    let value_mask = config.value_mask as u32;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut changed = 0;
+    let mut buffer = vec![0u8; BATCH_SIZE * 4];
+    let mut offset = 0;
+    while offset < page.capacity {
+        let cells = (page.capacity - offset).min(BATCH_SIZE);
+        let bytes = &mut buffer[..cells * 4];
+        page.reader.read_exact(bytes)?;
+        hasher.update(bytes);
+
+        for (i, chunk) in bytes.chunks_exact_mut(4).enumerate() {
+            let cell = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            if cell == 0 {
+                continue;
+            }
+            let taxid = cell & value_mask;
+            let new_taxid = mapping
+                .get(taxid as usize)
+                .copied()
+                .filter(|&t| t != 0)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{:?} cell {} has invalid taxid {}",
+                            page.path,
+                            offset + i,
+                            taxid
+                        ),
+                    )
+                })?;
+            if new_taxid != taxid {
+                changed += 1;
+                chunk.copy_from_slice(&((cell & !value_mask) | new_taxid).to_le_bytes());
+            }
+        }
+        writer.write_all(bytes)?;
+        offset += cells;
+    }
+    page.verify_checksum(hasher)?;
+    writer.finish()?;
+    Ok(changed)
+}
+
+/// 把 seqid2taxid.map 中的 taxid 改为新 taxonomy 中的 taxid, 保持行的顺序.
+/// 有变化时写到 target, 返回改变的行数
+fn migrate_id_map(path: &Path, target: &Path, new_taxid: impl Fn(u64) -> u64) -> Result<usize> {
+    let content = fs::read_to_string(path)?;
+    let mut output = String::with_capacity(content.len());
+    let mut changed = 0;
+    for line in content.lines() {
+        let parsed = line
+            .split_once('\t')
+            .and_then(|(seqid, taxid)| Some((seqid, taxid.trim().parse::<u64>().ok()?)));
+        match parsed {
+            Some((seqid, taxid)) if new_taxid(taxid) != taxid => {
+                changed += 1;
+                output.push_str(&format!("{}\t{}\n", seqid, new_taxid(taxid)));
+            }
+            _ => {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+    }
+    if changed > 0 {
+        fs::write(target, output)?;
+    }
+    Ok(changed)
+}
+
+/// 按 MIGRATE_MARKER 中的记录把暂存文件移到数据库中, 最后删除标记.
+/// 中断后重新运行时已经移动的文件 (暂存文件不存在) 跳过
+fn finish_migration(database: &Path) -> Result<()> {
+    let marker = database.join(MIGRATE_MARKER);
+    for line in fs::read_to_string(&marker)?.lines() {
+        let Some((staged, target)) = line.split_once('\t') else {
+            continue;
+        };
+        let target = database.join(target);
+        if staged.is_empty() {
+            if target.exists() {
+                fs::remove_file(&target)?;
+            }
+        } else if database.join(staged).exists() {
+            fs::rename(database.join(staged), &target)?;
+        }
+    }
+    let staging = database.join(MIGRATE_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::remove_file(&marker)
+}
+
+/// 迁移到新的 taxdump. 每个库中的 taxid 按 merged.dmp 重定向, 已删除的取最近的仍然存在的祖先;
+/// 每个旧节点改为其子树中所有库 taxid 在新树中的 LCA, 节点被拆分或移动时随之重新计算 LCA
+fn migrate(args: MigrateArgs) -> Result<()> {
+    let database = &args.database;
+    if Archive::is_archive(database) || !database.join("hash_config.k2d").exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{:?} is not a Kun-peng database directory (unpack archives first)",
+                database
+            ),
+        ));
+    }
+    if database.join(MIGRATE_MARKER).exists() {
+        finish_migration(database)?;
+        println!("finished the interrupted migration of {:?}", database);
+        return Ok(());
+    }
+    let start = Instant::now();
+    let config = HashConfig::from_hash_header(database.join("hash_config.k2d"))?;
+    let old_taxo = Taxonomy::from_file(database.join("taxo.k2d"))?;
+    let node_count = old_taxo.node_count();
+
+    let staging = database.join(MIGRATE_DIR);
+    stage_taxonomy(database, &args.taxonomy_dir, &staging)?;
+    let mut ncbi = load_ncbi_taxonomy(&staging)?;
+    let redirect = TaxidRedirect::from_dir(&staging)?;
+
+    // 库中的 taxid: 旧树的叶节点, 以及 seqid2taxid.map 中的 taxid
+    let id_map_file = database.join("seqid2taxid.map");
+    let mut sources: Vec<bool> = old_taxo
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| i > 0 && node.child_count == 0)
+        .collect();
+    if id_map_file.exists() {
+        for taxid in read_id_to_taxon_map(&id_map_file)?.values() {
+            sources[old_taxo.get_internal_id(*taxid) as usize] = true;
+        }
+    }
+    sources[0] = false;
+
+    // 旧节点在新树中的位置
+    let current =
+        |ncbi: &NCBITaxonomy, taxid: u64| redirect.resolve(taxid).filter(|&t| ncbi.contains(t));
+    let placed: Vec<u64> = (0..node_count)
+        .map(|i| {
+            let mut node = i;
+            while node > 1 {
+                if let Some(taxid) = current(&ncbi, old_taxo.nodes[node].external_id) {
+                    return taxid;
+                }
+                node = old_taxo.nodes[node].parent_id as usize;
+            }
+            1
+        })
+        .collect();
+    for i in (0..node_count).filter(|&i| sources[i]) {
+        ncbi.mark_node(placed[i]);
+    }
+    let mut new_taxo = ncbi.convert_to_kraken_taxonomy();
+    new_taxo.generate_external_to_internal_id_map();
+    new_taxo.build_lca_index();
+    get_bits_for_taxid(config.value_bits, new_taxo.node_count() as f64).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the new taxonomy has {} nodes, which do not fit in the {} value bits of the hash table; rebuild the database instead",
+                new_taxo.node_count(),
+                config.value_bits
+            ),
+        )
+    })?;
+
+    // 旧树按广度优先编号, 子节点的 id 大于父节点, 倒序遍历即可自下而上合并 LCA
+    let mut mapping = vec![0u32; node_count];
+    for i in (1..node_count).rev() {
+        if sources[i] {
+            mapping[i] = new_taxo.lca(mapping[i], new_taxo.get_internal_id(placed[i]));
+        }
+        let parent = old_taxo.nodes[i].parent_id as usize;
+        if parent > 0 {
+            mapping[parent] = new_taxo.lca(mapping[parent], mapping[i]);
+        }
+    }
+
+    let hash_files = HashFiles::Pages(
+        (1..=config.partition)
+            .map(|i| database.join(format!("hash_{}.k2d", i)))
+            .collect(),
+    );
+    let temp_file =
+        |page_index: usize| database.join(format!("hash_{}.k2d.migrate", page_index + 1));
+    let changed_cells: Vec<usize> = (0..config.partition)
+        .into_par_iter()
+        .map(|page_index| {
+            migrate_page(
+                &config,
+                &hash_files,
+                page_index,
+                &mapping,
+                &temp_file(page_index),
+            )
+        })
+        .collect::<Result<_>>()?;
+
+    // 所有输出都写到暂存文件后记录要替换的文件, 再统一替换.
+    // 替换过程中断时标记仍在, 加载数据库会报错, 再次运行 migrate 完成替换
+    let mut moves: Vec<(String, String)> = (1..=config.partition)
+        .map(|i| (format!("hash_{}.k2d.migrate", i), format!("hash_{}.k2d", i)))
+        .collect();
+    new_taxo.write_to_disk(database.join("taxo.k2d.migrate"))?;
+    moves.push(("taxo.k2d.migrate".to_string(), "taxo.k2d".to_string()));
+    let remapped = if id_map_file.exists() {
+        let remapped = migrate_id_map(
+            &id_map_file,
+            &database.join("seqid2taxid.map.migrate"),
+            |taxid| match old_taxo.get_internal_id(taxid) {
+                0 => current(&ncbi, taxid).unwrap_or(taxid),
+                i => placed[i as usize],
+            },
+        )?;
+        if remapped > 0 {
+            moves.push((
+                "seqid2taxid.map.migrate".to_string(),
+                "seqid2taxid.map".to_string(),
+            ));
+        }
+        remapped
+    } else {
+        0
+    };
+    create_dir_all(database.join("taxonomy"))?;
+    for name in TAXDUMP_FILES {
+        let target = format!("taxonomy/{}", name);
+        if staging.join(name).exists() {
+            moves.push((format!("{}/{}", MIGRATE_DIR, name), target));
+        } else {
+            moves.push((String::new(), target));
+        }
+    }
+    let marker: String = moves
+        .iter()
+        .map(|(staged, target)| format!("{}\t{}\n", staged, target))
+        .collect();
+    let marker_temp = database.join(format!("{}.tmp", MIGRATE_MARKER));
+    fs::write(&marker_temp, marker)?;
+    fs::rename(&marker_temp, database.join(MIGRATE_MARKER))?;
+    finish_migration(database)?;
+
+    // 报告位置发生变化的节点
+    let report_file = args
+        .report
+        .clone()
+        .unwrap_or_else(|| database.join("taxonomy_migration.tsv"));
+    let mut writer = BufWriter::new(File::create(&report_file)?);
+    writeln!(writer, "#old_taxid\told_name\tnew_taxid\tnew_name\tchange")?;
+    let mut changes: BTreeMap<&str, usize> = BTreeMap::new();
+    for (i, &new_id) in mapping.iter().enumerate().skip(1) {
+        let old_node = &old_taxo.nodes[i];
+        // 只有库中的 taxid 和多个分支的交汇点才可能出现在单元中
+        if !sources[i] && old_node.child_count < 2 {
+            continue;
+        }
+        let new_node = &new_taxo.nodes[new_id as usize];
+        let old_parent = old_taxo.nodes[old_node.parent_id as usize].external_id;
+        let new_parent = new_taxo.nodes[new_node.parent_id as usize].external_id;
+        let change = match current(&ncbi, old_node.external_id) {
+            None => "deleted",
+            Some(taxid) if taxid != old_node.external_id => "merged",
+            Some(_) if new_node.external_id != old_node.external_id => "split",
+            Some(_) if i > 1 && current(&ncbi, old_parent) != Some(new_parent) => "moved",
+            Some(_) => continue,
+        };
+        *changes.entry(change).or_insert(0) += 1;
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            old_node.external_id,
+            old_taxo.name(i as u32),
+            new_node.external_id,
+            new_taxo.name(new_id),
+            change
+        )?;
+    }
+    writer.flush()?;
+
+    let manifest_path = database.join(MANIFEST_FILENAME);
+    if manifest_path.exists() {
+        let old = Manifest::from_file(&manifest_path)?;
+        let mut manifest = Manifest::collect(
+            database,
+            BuildParams {
+                download_dir: None,
+                ..old.build.clone()
+            },
+        )?;
+        manifest.build.download_dir = old.build.download_dir;
+        manifest.assemblies = old.assemblies;
+        manifest.taxonomy.date = modified_time(&args.taxonomy_dir.join("nodes.dmp"));
+        manifest.write_to_file(&manifest_path)?;
+    }
+
+    let summary: Vec<String> = changes
+        .iter()
+        .map(|(change, count)| format!("{} {}", count, change))
+        .collect();
+    println!(
+        "taxonomy: {} nodes -> {} nodes; changed taxa: {}",
+        node_count,
+        new_taxo.node_count(),
+        if summary.is_empty() {
+            "none".to_string()
+        } else {
+            summary.join(", ")
+        }
+    );
+    println!(
+        "rewrote {} cells in {} pages, {} seqid2taxid.map entries",
+        changed_cells.iter().sum::<usize>(),
+        config.partition,
+        remapped
+    );
+    println!("report written to {:?}", report_file);
+    println!("migrate took: {:?}", start.elapsed());
+    Ok(())
+}
+
+pub fn run(args: Args) -> Result<()> {
+    match args.cmd {
+        DbCommands::Info(info_args) => info(info_args),
+        DbCommands::Verify(verify_args) => verify(verify_args),
+        DbCommands::Pack(pack_args) => pack(pack_args),
+        DbCommands::Unpack(unpack_args) => unpack(unpack_args),
+        DbCommands::Migrate(migrate_args) => migrate(migrate_args),
+    }
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
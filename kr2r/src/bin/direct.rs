@@ -1,10 +1,11 @@
 use clap::Parser;
-use kr2r::classify::process_hitgroup;
-use kr2r::compact_hash::{CHTable, Compact, HashConfig, Row};
+use kr2r::args::{parse_size, ReadOutputArgs, ScopeArgs};
+use kr2r::classify::{format_read_output, process_hitgroup, CallOptions, TaxidRules};
+use kr2r::compact_hash::{CHTable, Compact, HashConfig, HashFiles, Row};
 use kr2r::readcounts::{TaxonCounters, TaxonCountersDash};
-use kr2r::report::report_kraken_style;
+use kr2r::report::write_reports;
 use kr2r::taxonomy::Taxonomy;
-use kr2r::utils::{create_sample_file, find_and_sort_files, get_lastest_file_index};
+use kr2r::utils::{create_sample_file, get_lastest_file_index};
 use kr2r::{HitGroup, IndexOptions};
 use seqkmer::{read_parallel, Base, FastxReader, Meros, MinimizerIterator, OptionPair, Reader};
 use std::collections::HashMap;
@@ -30,6 +31,10 @@ pub struct Args {
     #[clap(long = "output-dir", value_parser)]
     pub kraken_output_dir: Option<PathBuf>,
 
+    /// Capacity of each virtual page when reading a Kraken 2 hash.k2d directly (used only if hash_config.k2d is absent).
+    #[clap(long = "hash-capacity", value_parser = parse_size, default_value = "1G")]
+    pub hash_capacity: usize,
+
     /// Enable paired-end processing.
     #[clap(short = 'P', long = "paired-end-processing", action)]
     pub paired_end_processing: bool,
@@ -73,10 +78,21 @@ pub struct Args {
     )]
     pub minimum_hit_groups: usize,
 
+    /// Count hit groups as the number of minimizers in the read, as earlier versions did,
+    /// instead of Kraken 2's runs of identical minimizers that hit the database.
+    #[clap(long, value_parser, default_value_t = false)]
+    pub legacy_hit_groups: bool,
+
     /// The number of threads to use.
     #[clap(short = 'p', long = "num-threads", value_parser, default_value_t = num_cpus::get())]
     pub num_threads: usize,
 
+    #[clap(flatten)]
+    pub read_output: ReadOutputArgs,
+
+    #[clap(flatten)]
+    pub scope: ScopeArgs,
+
     /// A list of input file paths (FASTA/FASTQ) to be processed by the classify program.
     /// Supports fasta or fastq format files (e.g., .fasta, .fastq) and gzip compressed files (e.g., .fasta.gz, .fastq.gz).
     // #[clap(short = 'F', long = "files")]
@@ -101,7 +117,7 @@ fn process_seq(
         let taxid = chtable.get_from_page(index, compacted, partition_index);
         if taxid > 0 {
             let high = u32::combined(compacted, taxid, value_bits);
-            let row = Row::new(high, 0, sort as u32 + 1 + offset as u32);
+            let row = Row::new(high, 0, sort as u32 + offset as u32);
             rows.push(row);
         }
     }
@@ -113,13 +129,17 @@ fn process_record(
     args: &Args,
     taxonomy: &Taxonomy,
     chtable: &CHTable,
-    hash_config: &HashConfig,
+    rules: &TaxidRules,
     cur_taxon_counts: &TaxonCountersDash,
     classify_counter: &AtomicUsize,
 ) -> String {
+    let hash_config = &chtable.config;
     let id = &marker.header.id.clone();
-    let rows: Vec<Row> = marker
-        .fold(|rows, m_iter, offset| process_seq(rows, m_iter, &hash_config, chtable, offset));
+    let mut rows: Vec<Row> =
+        marker.fold(|rows, m_iter, offset| process_seq(rows, m_iter, hash_config, chtable, offset));
+    rules
+        .scope
+        .retain(&mut rows, taxonomy, hash_config.value_mask);
 
     let hits = HitGroup::new(rows, marker.range());
 
@@ -131,8 +151,12 @@ fn process_record(
         taxonomy,
         classify_counter,
         required_score,
-        args.minimum_hit_groups,
         hash_config.value_mask,
+        CallOptions {
+            minimum_hit_groups: args.minimum_hit_groups,
+            legacy_hit_groups: args.legacy_hit_groups,
+            report_rank: args.read_output.report_rank_index(),
+        },
     );
 
     hit_data.3.iter().for_each(|(key, value)| {
@@ -142,16 +166,21 @@ fn process_record(
             .merge(value)
             .unwrap();
     });
-    format!(
-        "{}\t{}\t{}\t{}\t{}\n",
-        hit_data.0, id, hit_data.1, seq_len_str, hit_data.2
+    format_read_output(
+        taxonomy,
+        &args.read_output,
+        &hit_data.0,
+        id,
+        rules.remap.external_call(taxonomy, hit_data.1),
+        &seq_len_str,
+        &hit_data.2,
     )
 }
 
 fn process_fastx_file<R>(
     args: &Args,
     meros: Meros,
-    hash_config: HashConfig,
+    rules: &TaxidRules,
     file_index: usize,
     reader: &mut R,
     chtable: &CHTable,
@@ -188,7 +217,7 @@ where
                     args,
                     taxonomy,
                     chtable,
-                    &hash_config,
+                    rules,
                     &cur_taxon_counts,
                     &classify_counter,
                 );
@@ -226,15 +255,16 @@ where
     let thread_sequences = seq_counter.load(Ordering::SeqCst);
     let thread_classified = classify_counter.load(Ordering::SeqCst);
     if let Some(output) = &args.kraken_output_dir {
-        let filename = output.join(format!("output_{}.kreport2", file_index));
-        report_kraken_style(
-            filename,
-            args.report_zero_counts,
-            args.report_kmer_data,
-            &taxonomy,
+        write_reports(
+            output.join(format!("output_{}", file_index)),
+            taxonomy,
+            rules,
             &sample_taxon_counts,
             thread_sequences as u64,
             (thread_sequences - thread_classified) as u64,
+            args.report_zero_counts,
+            args.report_kmer_data,
+            args.read_output.report_rank.as_deref(),
         )?;
     }
 
@@ -247,6 +277,7 @@ fn process_files(
     hash_config: HashConfig,
     chtable: &CHTable,
     taxonomy: &Taxonomy,
+    rules: &TaxidRules,
 ) -> Result<()> {
     let (mut file_index, mut file_writer) = if let Some(out_dir) = &args.kraken_output_dir {
         let file_path = out_dir.join("sample_file.map");
@@ -285,7 +316,7 @@ fn process_files(
             let (thread_sequences, thread_unclassified) = process_fastx_file(
                 &args,
                 meros,
-                hash_config,
+                rules,
                 file_index,
                 &mut reader,
                 chtable,
@@ -296,15 +327,16 @@ fn process_files(
             total_unclassified += thread_unclassified;
         }
         if let Some(output) = &args.kraken_output_dir {
-            let filename = output.join("output.kreport2");
-            report_kraken_style(
-                filename,
-                args.report_zero_counts,
-                args.report_kmer_data,
-                &taxonomy,
+            write_reports(
+                output.join("output"),
+                taxonomy,
+                rules,
                 &total_taxon_counts,
                 total_seqs as u64,
                 total_unclassified as u64,
+                args.report_zero_counts,
+                args.report_kmer_data,
+                args.read_output.report_rank.as_deref(),
             )?;
         }
 
@@ -337,20 +369,18 @@ pub fn run(args: Args) -> Result<()> {
 
     let taxonomy_filename = args.database.join("taxo.k2d");
     let taxo = Taxonomy::from_file(taxonomy_filename)?;
+    let rules = TaxidRules::from_args(&args.database, &args.scope, &args.read_output, &taxo)?;
 
-    let hash_config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
+    let hash_config = HashConfig::from_database(&args.database, args.hash_capacity)?;
 
     println!("{:?}", hash_config);
-    if hash_config.hash_capacity == 0 {
-        panic!("`hash_capacity` can't be zero!");
-    }
     println!("classify start...");
     let start = Instant::now();
     let meros = idx_opts.as_meros();
-    let hash_files = find_and_sort_files(&args.database, "hash", ".k2d", true)?;
+    let hash_files = HashFiles::from_database(&args.database, &hash_config)?;
     let chtable = CHTable::from_hash_files(hash_config, &hash_files)?;
 
-    process_files(args, meros, hash_config, &chtable, &taxo)?;
+    process_files(args, meros, hash_config, &chtable, &taxo, &rules)?;
     let duration = start.elapsed();
     println!("classify took: {:?}", duration);
     Ok(())
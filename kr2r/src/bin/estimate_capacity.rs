@@ -130,6 +130,14 @@ fn process_sequence<P: AsRef<Path>>(
     hllp
 }
 
+/// 删除 library 目录中的 hllp 缓存. 缓存只按文件名和 n 区分, 参数或 library 变化后会过期
+pub fn clean_cache(database: &Path) -> std::io::Result<()> {
+    for path in find_files(database.join("library"), "library", ".json") {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
 pub fn run(args: Args) -> usize {
     let meros = args.klmt.as_meros();
 
@@ -0,0 +1,300 @@
+use clap::Parser;
+use kr2r::compact_hash::{HashConfig, HashFiles, KRAKEN2_HEADER_SIZE};
+use kr2r::k2d::{self, FileKind};
+use kr2r::utils::find_and_sort_files;
+use std::fs::{self, create_dir_all, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Result, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+const BATCH_SIZE: usize = 4 * 1024;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Export a Kun-peng database to the Kraken 2 index format (hash.k2d opts.k2d taxo.k2d)."
+)]
+pub struct Args {
+    /// The database directory for the Kun-peng index. contains index files(hash_config.k2d hash_*.k2d opts.k2d taxo.k2d)
+    #[clap(long = "db", value_parser, required = true)]
+    pub database: PathBuf,
+
+    /// Output directory for the Kraken 2 index files
+    #[clap(short, long, required = true)]
+    pub output_dir: PathBuf,
+}
+
+/// 分页末尾回绕到页首的连续区块
+struct WrappedBlock {
+    /// 在全局哈希表中的起始位置(即分页结束位置)
+    start: usize,
+    cells: Vec<u32>,
+}
+
+/// 将单个分页的数据追加到 writer, 返回非零单元数和需要回绕的区块
+fn copy_page<W: Write>(
+    config: &HashConfig,
+    hash_files: &HashFiles,
+    page_index: usize,
+    writer: &mut W,
+) -> Result<(usize, Option<WrappedBlock>)> {
+    let mut page = hash_files.open_page(page_index)?;
+    let page_size = page.capacity;
+    let mut hasher = crc32fast::Hasher::new();
+
+    let page_start = page_index * config.hash_capacity;
+    if page_start + page_size > config.capacity {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "page {:?} exceeds the hash table capacity {}",
+                page.path, config.capacity
+            ),
+        ));
+    }
+
+    let mut size = 0;
+    let mut first_block = Vec::new();
+    let mut first_block_done = false;
+    let mut last_cell = 0u32;
+
+    let mut buffer = vec![0u8; BATCH_SIZE * 4];
+    let mut remaining = page_size;
+    while remaining > 0 {
+        let cells = remaining.min(BATCH_SIZE);
+        let bytes = &mut buffer[..cells * 4];
+        page.reader.read_exact(bytes)?;
+        hasher.update(bytes);
+        writer.write_all(bytes)?;
+
+        for chunk in bytes.chunks_exact(4) {
+            let cell = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            if cell != 0 {
+                size += 1;
+                if !first_block_done {
+                    first_block.push(cell);
+                }
+            } else {
+                first_block_done = true;
+            }
+            last_cell = cell;
+        }
+        remaining -= cells;
+    }
+    page.verify_checksum(hasher)?;
+
+    // Kun-peng 构建的分页在页内回绕探测, 页首的连续区块可能属于页尾的探测链.
+    // Kraken 2 是全局线性探测, 因此把这些单元复制到分页之后.
+    // 单元中只有 key 的高位, 无法判断它属于页首还是页尾, 所以原位置也保留一份.
+    let wrapped = if config.version >= 1 && last_cell != 0 && !first_block.is_empty() {
+        Some(WrappedBlock {
+            start: (page_start + page_size) % config.capacity,
+            cells: first_block,
+        })
+    } else {
+        None
+    };
+
+    Ok((size, wrapped))
+}
+
+/// 从 start 开始线性探测, 把 cell 写入第一个空单元
+fn insert_cell(file: &mut File, capacity: usize, start: usize, cell: u32) -> Result<()> {
+    let mut buffer = vec![0u8; BATCH_SIZE * 4];
+    let mut pos = start;
+    let mut probed = 0;
+    while probed < capacity {
+        let cells = (capacity - pos).min(BATCH_SIZE);
+        let offset = (KRAKEN2_HEADER_SIZE + pos * 4) as u64;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer[..cells * 4])?;
+
+        for (i, chunk) in buffer[..cells * 4].chunks_exact(4).enumerate() {
+            if chunk == [0u8; 4] {
+                file.seek(SeekFrom::Start(offset + (i * 4) as u64))?;
+                file.write_all(&cell.to_le_bytes())?;
+                return Ok(());
+            }
+        }
+        probed += cells;
+        pos = (pos + cells) % capacity;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "hash table is full, cannot export",
+    ))
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let config = HashConfig::from_hash_header(args.database.join("hash_config.k2d"))?;
+    let hash_files = HashFiles::Pages(find_and_sort_files(&args.database, "hash", ".k2d", true)?);
+    if hash_files.len() != config.partition {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "expected {} hash files, found {}",
+                config.partition,
+                hash_files.len()
+            ),
+        ));
+    }
+
+    println!("export start...");
+    let start = Instant::now();
+
+    create_dir_all(&args.output_dir)?;
+    let index_filename = args.output_dir.join("hash.k2d");
+    let mut writer = BufWriter::new(File::create(&index_filename)?);
+    config.write_kraken2_header(&mut writer)?;
+
+    let mut size = 0;
+    let mut wrapped_blocks = Vec::new();
+    for page_index in 0..hash_files.len() {
+        let (page_size, wrapped) = copy_page(&config, &hash_files, page_index, &mut writer)?;
+        size += page_size;
+        if let Some(block) = wrapped {
+            wrapped_blocks.push(block);
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&index_filename)?;
+    for block in wrapped_blocks {
+        for cell in block.cells {
+            insert_cell(&mut file, config.capacity, block.start, cell)?;
+        }
+    }
+
+    // 回写不同元素的数量, 回绕区块的副本不计入
+    let mut export_config = config;
+    export_config.size = size;
+    file.seek(SeekFrom::Start(0))?;
+    export_config.write_kraken2_header(&mut file)?;
+    file.flush()?;
+
+    // Kraken 2 不认识 Kun-peng 的文件头部, 只写入原始内容
+    for (name, kind) in [
+        ("taxo.k2d", FileKind::Taxonomy),
+        ("opts.k2d", FileKind::IndexOptions),
+    ] {
+        let payload = k2d::read_payload(args.database.join(name), kind)?;
+        fs::write(args.output_dir.join(name), payload)?;
+    }
+
+    let duration = start.elapsed();
+    println!("export took: {:?}", duration);
+    println!("exported {} cells to {:?}", size, index_filename);
+
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use kr2r::k2d::K2dWriter;
+    use std::collections::HashSet;
+
+    const VALUE_BITS: usize = 8;
+    const PAGE_SIZE: usize = 8;
+
+    /// 按 Kun-peng 的方式在页内回绕线性探测
+    fn insert_in_page(page: &mut [u32], home: usize, cell: u32) {
+        let mut idx = home % page.len();
+        while page[idx] != 0 {
+            idx = (idx + 1) % page.len();
+        }
+        page[idx] = cell;
+    }
+
+    /// 按 Kraken 2 的方式从 home 开始全局线性探测
+    fn kraken2_lookup(table: &[u32], home: usize, compacted: u32) -> u32 {
+        let mut idx = home;
+        loop {
+            let cell = table[idx];
+            if cell == 0 {
+                return 0;
+            }
+            if cell >> VALUE_BITS == compacted {
+                return cell & ((1 << VALUE_BITS) - 1);
+            }
+            idx = (idx + 1) % table.len();
+        }
+    }
+
+    #[test]
+    fn export_round_trip_keeps_distinct_keys() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("kun_peng_export_{}", std::process::id()));
+        let database = dir.join("db");
+        let output_dir = dir.join("k2");
+        create_dir_all(&database)?;
+
+        // (home, compacted key, taxid). 第一页页尾的探测链回绕到页首
+        let keys: Vec<(usize, u32, u32)> = vec![
+            (6, 1, 11),
+            (7, 2, 12),
+            (7, 3, 13),
+            (7, 4, 14),
+            (0, 5, 15),
+            (8, 6, 16),
+            (9, 7, 17),
+        ];
+        let capacity = 2 * PAGE_SIZE;
+        let mut pages = vec![vec![0u32; PAGE_SIZE]; 2];
+        for &(home, compacted, taxid) in &keys {
+            let cell = compacted << VALUE_BITS | taxid;
+            insert_in_page(&mut pages[home / PAGE_SIZE], home, cell);
+        }
+        assert_ne!(pages[0][PAGE_SIZE - 1], 0);
+
+        HashConfig::new(1, capacity, VALUE_BITS, keys.len(), 2, PAGE_SIZE)
+            .write_to_file(database.join("hash_config.k2d"))?;
+        for (i, page) in pages.iter().enumerate() {
+            let file = File::create(database.join(format!("hash_{}.k2d", i + 1)))?;
+            let mut writer = K2dWriter::new(BufWriter::new(file), FileKind::Page)?;
+            writer.write_u64::<LittleEndian>(i as u64 + 1)?;
+            writer.write_u64::<LittleEndian>(PAGE_SIZE as u64)?;
+            for &cell in page {
+                writer.write_u32::<LittleEndian>(cell)?;
+            }
+            writer.finish()?;
+        }
+        k2d::write_file(database.join("taxo.k2d"), FileKind::Taxonomy, b"taxo")?;
+        k2d::write_file(database.join("opts.k2d"), FileKind::IndexOptions, b"opts")?;
+
+        run(Args {
+            database: database.clone(),
+            output_dir: output_dir.clone(),
+        })?;
+
+        let index_filename = output_dir.join("hash.k2d");
+        let exported = HashConfig::from_kraken2_header(&index_filename)?;
+        let bytes = fs::read(&index_filename)?;
+        let table: Vec<u32> = bytes[KRAKEN2_HEADER_SIZE..]
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(table.len(), capacity);
+        let distinct: HashSet<u32> = table.iter().copied().filter(|&cell| cell != 0).collect();
+        assert_eq!(distinct.len(), keys.len());
+        assert_eq!(exported.size, keys.len());
+        for &(home, compacted, taxid) in &keys {
+            assert_eq!(kraken2_lookup(&table, home, compacted), taxid);
+        }
+        Ok(())
+    }
+}
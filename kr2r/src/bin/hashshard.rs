@@ -1,6 +1,7 @@
 use clap::Parser;
 use kr2r::args::parse_size;
 use kr2r::compact_hash::HashConfig;
+use kr2r::k2d::{FileKind, K2dWriter};
 // use memmap2::MmapOptions;
 use std::fs::{self, create_dir_all, File, OpenOptions};
 use std::io::BufWriter;
@@ -18,7 +19,7 @@ fn mmap_read_write<P: AsRef<Path>, Q: AsRef<Path>>(
     length: usize,
 ) -> IOResult<()> {
     // 打开目标文件，准备写入数据
-    let mut dest_file = BufWriter::new(File::create(dest_path)?);
+    let mut dest_file = K2dWriter::new(BufWriter::new(File::create(dest_path)?), FileKind::Page)?;
     dest_file
         .write_all(&partition.to_le_bytes())
         .expect("Failed to write capacity");
@@ -37,6 +38,7 @@ fn mmap_read_write<P: AsRef<Path>, Q: AsRef<Path>>(
 
     // 将读取的数据写入目标文件
     dest_file.write_all(&buffer)?;
+    dest_file.finish()?;
 
     Ok(())
 }
@@ -82,7 +84,10 @@ pub fn run(args: Args) -> IOResult<()> {
 
     let config_file = k2d_dir.join("hash_config.k2d");
     if config_file.exists() {
-        panic!("hash config is exists!!!");
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("hash config {:?} already exists", config_file),
+        ));
     }
 
     hash_config.write_to_file(config_file)?;
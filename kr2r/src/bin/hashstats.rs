@@ -0,0 +1,88 @@
+use clap::Parser;
+use kr2r::args::parse_size;
+use kr2r::compact_hash::{CHTable, HashConfig, HashFiles, HashStats};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Result, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Hash table statistics: occupancy, probe chain lengths, page spill and estimated false positive rate (JSON)"
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Capacity of each virtual page when reading a Kraken 2 hash.k2d directly (used only if hash_config.k2d is absent).
+    #[clap(long = "hash-capacity", value_parser = parse_size, default_value = "1G")]
+    pub hash_capacity: usize,
+
+    /// Output JSON file, default is stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Only output the overall statistics, without per page details
+    #[clap(long, value_parser, default_value_t = false)]
+    pub summary_only: bool,
+}
+
+#[derive(Serialize)]
+struct StatsReport {
+    version: usize,
+    partition: usize,
+    hash_capacity: usize,
+    value_bits: usize,
+    table: HashStats,
+    pages: Vec<HashStats>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let config = HashConfig::from_database(&args.database, args.hash_capacity)?;
+    let hash_files = HashFiles::from_database(&args.database, &config)?;
+
+    let start = Instant::now();
+    let mut table = HashStats {
+        key_bits: 32 - config.value_bits,
+        ..Default::default()
+    };
+    let mut pages = Vec::new();
+    for page_index in 0..hash_files.len() {
+        let stats = CHTable::page_stats(&config, &hash_files, page_index)?;
+        table.merge(&stats);
+        if !args.summary_only {
+            pages.push(stats);
+        }
+    }
+
+    let report = StatsReport {
+        version: config.version,
+        partition: config.partition,
+        hash_capacity: config.hash_capacity,
+        value_bits: config.value_bits,
+        table,
+        pages,
+    };
+
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    serde_json::to_writer_pretty(&mut writer, &report)?;
+    writeln!(writer)?;
+    writer.flush()?;
+
+    eprintln!("hashstats took: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
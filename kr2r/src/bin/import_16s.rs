@@ -0,0 +1,284 @@
+use clap::{Parser, ValueEnum};
+use kr2r::args::parse_size;
+use kr2r::library::{
+    for_each_record, generate_library_taxonomy, header_seqid, read_library_map, sequence_name,
+    LibraryAppender,
+};
+use kr2r::lineage::{parse_prefixed_lineage, standard_rank, LineageTaxonomy};
+use kr2r::utils::open_text_file;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, BufRead, Result};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// 16S 扩增子较短 (V4 区约 250bp), 使用比默认值 (k=35, l=31) 短的 k-mer 和 minimizer,
+/// minimizer 中忽略的位置数不能超过 l/4
+pub const AMPLICON_KMER_LENGTH: &str = "31";
+pub const AMPLICON_MINIMIZER_LENGTH: &str = "25";
+pub const AMPLICON_MINIMIZER_SPACES: &str = "6";
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    /// SILVA SSU Ref NR (tax_slv_ssu_*.txt with SILVA taxids, lineage in the FASTA header)
+    Silva,
+    /// Greengenes or Greengenes2 (seqid<TAB>k__...; p__...; s__... taxonomy)
+    Greengenes,
+    /// RDP (Lineage=Root;rootrank;Bacteria;domain;... in the FASTA header)
+    Rdp,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Silva => write!(f, "SILVA"),
+            Format::Greengenes => write!(f, "Greengenes"),
+            Format::Rdp => write!(f, "RDP"),
+        }
+    }
+}
+
+/// 16S 数据库的序列和 taxonomy 文件
+#[derive(clap::Args, Debug, Clone)]
+pub struct Source {
+    /// 16S database format
+    #[arg(long, value_enum)]
+    pub format: Format,
+
+    /// Reference FASTA/FASTA.gz files, e.g. SILVA_138.1_SSURef_NR99_tax_silva.fasta.gz,
+    /// 2022.10.backbone.full-length.fna or current_Bacteria_unaligned.fa
+    #[arg(long, required = true, num_args = 1..)]
+    pub sequences: Vec<PathBuf>,
+
+    /// Taxonomy file: tax_slv_ssu_*.txt for SILVA, the seqid to lineage TSV for Greengenes
+    /// (2022.10.taxonomy.id.tsv, gg_13_5_taxonomy.txt). Not used for RDP
+    #[arg(long)]
+    pub taxonomy: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Import a SILVA, Greengenes or RDP 16S database into the library with a synthetic taxonomy"
+)]
+pub struct Args {
+    /// database directory
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    #[clap(flatten)]
+    pub source: Source,
+
+    /// library fna temp file max size
+    #[arg(long = "max-file-size", value_parser = parse_size, default_value = "2G")]
+    pub max_file_size: usize,
+}
+
+/// 读取 SILVA 的 tax_slv_ssu 文件 (path;<TAB>taxid<TAB>rank<TAB>remark<TAB>release), 保留 SILVA 的 taxid
+fn read_silva_taxonomy(path: &Path, taxonomy: &mut LineageTaxonomy) -> Result<()> {
+    let mut nodes = BTreeMap::new();
+    for (i, line) in open_text_file(path)?.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let node = match fields.as_slice() {
+            [path, taxid, rank, ..] => taxid.trim().parse::<u64>().ok().map(|taxid| {
+                (
+                    path.trim().trim_end_matches(';').to_string(),
+                    (taxid, *rank),
+                )
+            }),
+            _ => None,
+        };
+        let Some((path, (taxid, rank))) = node else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} line {}: expected path<TAB>taxid<TAB>rank",
+                    path,
+                    i + 1
+                ),
+            ));
+        };
+        nodes.insert(path, (taxid, standard_rank(rank.trim())));
+    }
+
+    for (path, (taxid, _)) in &nodes {
+        let mut prefix = String::new();
+        let lineage: Vec<(String, String)> = path
+            .split(';')
+            .map(|name| {
+                if !prefix.is_empty() {
+                    prefix.push(';');
+                }
+                prefix.push_str(name);
+                let rank = nodes
+                    .get(&prefix)
+                    .map(|(_, rank)| rank.clone())
+                    .unwrap_or_else(|| "no rank".to_string());
+                (rank, name.to_string())
+            })
+            .collect();
+        taxonomy.insert(&lineage);
+        taxonomy.set_taxid(path, *taxid);
+    }
+    Ok(())
+}
+
+/// SILVA 的序列按标题行中的 lineage 归到 taxonomy 中最深的节点 (taxonomy 不包含种)
+fn silva_path(taxonomy: &LineageTaxonomy, header: &str) -> Option<String> {
+    let (_, lineage) = header.split_once(char::is_whitespace)?;
+    let names: Vec<&str> = lineage.split(';').map(|name| name.trim()).collect();
+    (1..=names.len())
+        .rev()
+        .map(|len| names[..len].join(";"))
+        .find(|path| taxonomy.contains(path))
+}
+
+/// RDP 标题行中的 `Lineage=Root;rootrank;Bacteria;domain;...` (name;rank 成对出现)
+fn rdp_lineage(header: &str) -> Vec<(String, String)> {
+    let Some((_, lineage)) = header.split_once("Lineage=") else {
+        return Vec::new();
+    };
+    let fields: Vec<&str> = lineage.trim().trim_end_matches(';').split(';').collect();
+    fields
+        .chunks_exact(2)
+        .filter(|pair| pair[1] != "rootrank")
+        .map(|pair| {
+            (
+                standard_rank(pair[1]),
+                pair[0].trim_matches('"').to_string(),
+            )
+        })
+        .filter(|(_, name)| !name.is_empty())
+        .collect()
+}
+
+/// 读取 Greengenes 的 seqid<TAB>lineage 文件, 跳过表头和没有 lineage 的序列
+fn read_greengenes_taxonomy(
+    path: &Path,
+    taxonomy: &mut LineageTaxonomy,
+    seq_paths: &mut HashMap<String, String>,
+) -> Result<()> {
+    for line in open_text_file(path)?.lines() {
+        let line = line?;
+        let Some((seqid, lineage)) = line.split_once('\t') else {
+            continue;
+        };
+        let lineage = parse_prefixed_lineage(lineage.split('\t').next().unwrap_or_default());
+        if !lineage.is_empty() {
+            seq_paths.insert(seqid.trim().to_string(), taxonomy.insert(&lineage));
+        }
+    }
+    Ok(())
+}
+
+/// 生成 taxonomy, 返回每条序列所属节点的路径
+fn read_reference(source: &Source) -> Result<(LineageTaxonomy, HashMap<String, String>)> {
+    let mut taxonomy = LineageTaxonomy::new();
+    let mut seq_paths = HashMap::new();
+    let taxonomy_file = || {
+        source.taxonomy.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--taxonomy is required for {}", source.format),
+            )
+        })
+    };
+    match source.format {
+        Format::Silva => read_silva_taxonomy(taxonomy_file()?, &mut taxonomy)?,
+        Format::Greengenes => {
+            read_greengenes_taxonomy(taxonomy_file()?, &mut taxonomy, &mut seq_paths)?
+        }
+        Format::Rdp => {}
+    }
+    if matches!(source.format, Format::Silva | Format::Rdp) {
+        for path in &source.sequences {
+            for_each_record(
+                path,
+                |header| {
+                    let leaf = match source.format {
+                        Format::Silva => silva_path(&taxonomy, header),
+                        _ => {
+                            let lineage = rdp_lineage(header);
+                            (!lineage.is_empty()).then(|| taxonomy.insert(&lineage))
+                        }
+                    };
+                    if let Some(leaf) = leaf {
+                        seq_paths.insert(header_seqid(header).to_string(), leaf);
+                    }
+                    None
+                },
+                |_, _| Ok(()),
+            )?;
+        }
+    }
+    Ok((taxonomy, seq_paths))
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let start = Instant::now();
+    let database = &args.database;
+    let source = &args.source;
+
+    let (taxonomy, seq_paths) = read_reference(source)?;
+    let taxids = taxonomy.assign_taxids();
+    let all_taxids: HashSet<u64> = taxids.values().copied().collect();
+    let known_ids = read_library_map(database, &all_taxids, &source.format.to_string())?;
+    taxonomy.write_taxdump(&database.join("taxonomy"), &taxids)?;
+    println!("{} taxonomy: {} taxa", source.format, taxonomy.len());
+
+    let mut appender = LibraryAppender::new(database, args.max_file_size as u64)?.rna_to_dna();
+    let (mut added, mut duplicate) = (0, 0);
+    let mut unclassified = Vec::new();
+    let mut taxa = HashSet::new();
+    for path in &source.sequences {
+        appender.append(path, |header| {
+            let seqid = header_seqid(header);
+            let Some(taxid) = seq_paths.get(seqid).map(|path| taxids[path]) else {
+                unclassified.push(seqid.to_string());
+                return None;
+            };
+            let name = sequence_name(taxid, seqid);
+            if known_ids.contains_key(&name) {
+                duplicate += 1;
+                return None;
+            }
+            added += 1;
+            taxa.insert(taxid);
+            Some((name, taxid))
+        })?;
+    }
+    appender.finish()?;
+
+    if !unclassified.is_empty() {
+        eprintln!(
+            "skipped {} sequences without taxonomy, e.g. {}",
+            unclassified.len(),
+            unclassified[0]
+        );
+    }
+    if duplicate > 0 {
+        eprintln!("skipped {} sequences already in the library", duplicate);
+    }
+    println!(
+        "added {} sequences of {} taxa to {:?}",
+        added,
+        taxa.len(),
+        database.join("library")
+    );
+
+    generate_library_taxonomy(database)?;
+    println!("import 16s took: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
@@ -0,0 +1,168 @@
+use clap::Parser;
+use kr2r::args::parse_size;
+use kr2r::library::{
+    generate_library_taxonomy, header_seqid, read_library_map, sequence_name, LibraryAppender,
+};
+use kr2r::lineage::{parse_prefixed_lineage, LineageTaxonomy};
+use kr2r::utils::{find_files, open_text_file};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Result};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Import the GTDB taxonomy (bac120_taxonomy.tsv/ar53_taxonomy.tsv) as the database taxonomy and add GTDB genomes to the library"
+)]
+pub struct Args {
+    /// database directory
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// GTDB taxonomy files (accession<TAB>d__...;s__...), e.g. bac120_taxonomy.tsv ar53_taxonomy.tsv
+    #[arg(long, required = true, num_args = 1..)]
+    pub taxonomy: Vec<PathBuf>,
+
+    /// Directory with genome FASTA/FASTA.gz files named by accession (GCF_000005845.2_..._genomic.fna.gz).
+    /// Each contig is assigned to the species of its genome
+    #[arg(long)]
+    pub genomes_dir: Option<PathBuf>,
+
+    /// library fna temp file max size
+    #[arg(long = "max-file-size", value_parser = parse_size, default_value = "2G")]
+    pub max_file_size: usize,
+}
+
+/// 读取 GTDB taxonomy, 返回 accession (去掉 RS_/GB_ 前缀) 到叶节点路径的映射
+fn read_gtdb_taxonomy(
+    path: &Path,
+    taxonomy: &mut LineageTaxonomy,
+    genomes: &mut HashMap<String, String>,
+) -> Result<()> {
+    for (i, line) in open_text_file(path)?.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let lineage = line
+            .split_once('\t')
+            .map(|(accession, lineage)| (accession, parse_prefixed_lineage(lineage)))
+            .filter(|(_, lineage)| !lineage.is_empty());
+        let Some((accession, lineage)) = lineage else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} line {}: expected accession<TAB>d__...;s__...",
+                    path,
+                    i + 1
+                ),
+            ));
+        };
+        let accession = accession
+            .trim()
+            .trim_start_matches("RS_")
+            .trim_start_matches("GB_");
+        genomes.insert(accession.to_string(), taxonomy.insert(&lineage));
+    }
+    Ok(())
+}
+
+/// genomes_dir 中的基因组文件, 以及文件名中的 accession
+fn genome_files(genomes_dir: &Path) -> Vec<(String, PathBuf)> {
+    let accession = Regex::new(r"GC[AF]_\d+\.\d+").unwrap();
+    find_files(genomes_dir, "", "")
+        .into_iter()
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let stem = name.strip_suffix(".gz").unwrap_or(name);
+            if ![".fna", ".fa", ".fasta"]
+                .iter()
+                .any(|ext| stem.ends_with(ext))
+            {
+                return None;
+            }
+            let accession = accession.find(name)?.as_str().to_string();
+            Some((accession, path))
+        })
+        .collect()
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let start = Instant::now();
+    let database = &args.database;
+
+    let mut taxonomy = LineageTaxonomy::new();
+    let mut genomes = HashMap::new();
+    for path in &args.taxonomy {
+        read_gtdb_taxonomy(path, &mut taxonomy, &mut genomes)?;
+    }
+    let taxids = taxonomy.assign_taxids();
+
+    let gtdb_taxids: HashSet<u64> = taxids.values().copied().collect();
+    let known_ids = read_library_map(database, &gtdb_taxids, "GTDB")?;
+
+    taxonomy.write_taxdump(&database.join("taxonomy"), &taxids)?;
+    println!(
+        "GTDB taxonomy: {} taxa, {} genomes",
+        taxonomy.len(),
+        genomes.len()
+    );
+
+    if let Some(genomes_dir) = &args.genomes_dir {
+        let mut appender = LibraryAppender::new(database, args.max_file_size as u64)?;
+        let (mut added_genomes, mut added, mut duplicate) = (0, 0, 0);
+        let mut unmatched = Vec::new();
+        for (accession, path) in genome_files(genomes_dir) {
+            let Some(taxid) = genomes.get(&accession).map(|path| taxids[path]) else {
+                unmatched.push(accession);
+                continue;
+            };
+            appender.append(&path, |header| {
+                let name = sequence_name(taxid, header_seqid(header));
+                if known_ids.contains_key(&name) {
+                    duplicate += 1;
+                    return None;
+                }
+                added += 1;
+                Some((name, taxid))
+            })?;
+            added_genomes += 1;
+        }
+        appender.finish()?;
+        if !unmatched.is_empty() {
+            eprintln!(
+                "{} genome files not in the GTDB taxonomy, e.g. {}",
+                unmatched.len(),
+                unmatched[0]
+            );
+        }
+        if duplicate > 0 {
+            eprintln!("skipped {} sequences already in the library", duplicate);
+        }
+        println!(
+            "added {} sequences of {} genomes to {:?}",
+            added,
+            added_genomes,
+            database.join("library")
+        );
+    }
+
+    // 重新生成 taxo.k2d
+    if database.join("seqid2taxid.map").exists() {
+        generate_library_taxonomy(database)?;
+    }
+    println!("import gtdb took: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
@@ -0,0 +1,184 @@
+use clap::Parser;
+use kr2r::args::parse_size;
+use kr2r::compact_hash::{HashConfig, HashFiles, PageReader};
+use kr2r::readcounts::{ReadCounter, TaxonCounters};
+use kr2r::report::write_kraken_style_report;
+use kr2r::taxonomy::Taxonomy;
+use kr2r::IndexOptions;
+use rayon::prelude::*;
+use seqkmer::BITS_PER_CHAR;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Result, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+const BATCH_SIZE: usize = 1024 * 1024;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Inspect a database: minimizer counts per taxon in kraken report format"
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Capacity of each virtual page when reading a Kraken 2 hash.k2d directly (used only if hash_config.k2d is absent).
+    #[clap(long = "hash-capacity", value_parser = parse_size, default_value = "1G")]
+    pub hash_capacity: usize,
+
+    /// Output file, default is stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Only print the database summary header
+    #[clap(short = 's', long, value_parser, default_value_t = false)]
+    pub skip_counts: bool,
+
+    /// Report taxa w/ 0 count
+    #[clap(short = 'z', long, value_parser, default_value_t = false)]
+    pub report_zero_counts: bool,
+}
+
+/// 统计单个分页中每个内部 taxid 的单元数
+fn count_page(
+    hash_files: &HashFiles,
+    page_index: usize,
+    value_mask: u32,
+) -> Result<HashMap<u64, u64>> {
+    let PageReader {
+        mut reader,
+        capacity,
+        ..
+    } = hash_files.open_page(page_index)?;
+    let mut counts = HashMap::new();
+
+    let mut buffer = vec![0u8; BATCH_SIZE * 4];
+    let mut remaining = capacity;
+    while remaining > 0 {
+        let cells = remaining.min(BATCH_SIZE);
+        let bytes = &mut buffer[..cells * 4];
+        reader.read_exact(bytes)?;
+        for chunk in bytes.chunks_exact(4) {
+            let taxid = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) & value_mask;
+            if taxid != 0 {
+                *counts.entry(taxid as u64).or_insert(0) += 1;
+            }
+        }
+        remaining -= cells;
+    }
+    Ok(counts)
+}
+
+fn write_summary<W: Write>(
+    writer: &mut W,
+    idx_opts: &IndexOptions,
+    config: &HashConfig,
+    taxonomy: &Taxonomy,
+) -> Result<()> {
+    let db_type = if idx_opts.dna_db {
+        "nucleotide"
+    } else {
+        "protein"
+    };
+    writeln!(
+        writer,
+        "# Database options: {} db, k = {}, l = {}",
+        db_type, idx_opts.k, idx_opts.l
+    )?;
+    writeln!(
+        writer,
+        "# Spaced mask = {:0width$b}",
+        idx_opts.spaced_seed_mask,
+        width = idx_opts.l * BITS_PER_CHAR
+    )?;
+    writeln!(writer, "# Toggle mask = {:064b}", idx_opts.toggle_mask)?;
+    writeln!(
+        writer,
+        "# Min clear hash value = {}",
+        idx_opts.minimum_acceptable_hash_value
+    )?;
+    writeln!(writer, "# Total taxonomy nodes: {}", taxonomy.node_count())?;
+    writeln!(writer, "# Table size: {}", config.size)?;
+    writeln!(writer, "# Table capacity: {}", config.capacity)?;
+    writeln!(writer, "# Value bits: {}", config.value_bits)?;
+    writeln!(
+        writer,
+        "# Hash pages: {} (page capacity {}, version {})",
+        config.partition, config.hash_capacity, config.version
+    )?;
+    Ok(())
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let idx_opts = IndexOptions::read_index_options(args.database.join("opts.k2d"))?;
+    let taxonomy = Taxonomy::from_file(args.database.join("taxo.k2d"))?;
+    let config = HashConfig::from_database(&args.database, args.hash_capacity)?;
+    let hash_files = HashFiles::from_database(&args.database, &config)?;
+
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    write_summary(&mut writer, &idx_opts, &config, &taxonomy)?;
+    if args.skip_counts {
+        writer.flush()?;
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    let value_mask = config.value_mask as u32;
+    let page_counts = (0..hash_files.len())
+        .into_par_iter()
+        .map(|page_index| count_page(&hash_files, page_index, value_mask))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut counts: HashMap<u64, u64> = HashMap::new();
+    for page in page_counts {
+        for (taxid, count) in page {
+            *counts.entry(taxid).or_insert(0) += count;
+        }
+    }
+
+    let node_count = taxonomy.node_count() as u64;
+    let unknown: u64 = counts
+        .iter()
+        .filter(|(&taxid, _)| taxid >= node_count)
+        .map(|(_, &count)| count)
+        .sum();
+    if unknown > 0 {
+        writeln!(writer, "# Cells with unknown taxid: {}", unknown)?;
+    }
+
+    let mut call_counters = TaxonCounters::new();
+    let mut total_minimizers = 0;
+    for (&taxid, &count) in counts.iter().filter(|(&taxid, _)| taxid < node_count) {
+        call_counters.insert(taxid, ReadCounter::new(count, 0));
+        total_minimizers += count;
+    }
+
+    write_kraken_style_report(
+        &mut writer,
+        args.report_zero_counts,
+        false,
+        &taxonomy,
+        &call_counters,
+        total_minimizers,
+        0,
+    )?;
+    writer.flush()?;
+
+    eprintln!("inspect took: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
@@ -1,20 +1,34 @@
 use clap::{Parser, Subcommand};
+mod add_to_library;
 mod annotate;
 mod build_k2_db;
+mod build_page;
 mod chunk_db;
+mod database;
 mod direct;
 mod estimate_capacity;
+mod export;
 mod hashshard;
+mod hashstats;
+mod import_16s;
+mod import_gtdb;
+mod inspect;
 mod merge_fna;
 mod resolve;
 // mod seqid2taxid;
 mod splitr;
+mod taxonomy;
 
 use kr2r::args::ClassifyArgs;
 use kr2r::args::{parse_size, Build};
+use kr2r::checkpoint::{fingerprint, remove_if_exists, Checkpoint, Stage};
+use kr2r::compact_hash::HashConfig;
+use kr2r::db::install_custom_nodes;
+use kr2r::library::generate_library_taxonomy;
+use kr2r::manifest::{BuildParams, Manifest, MANIFEST_FILENAME};
 use kr2r::utils::find_files;
 // use std::io::Result;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 #[derive(Parser, Debug, Clone)]
@@ -23,9 +37,10 @@ struct BuildArgs {
     // /// database hash chunk directory and other files
     // #[clap(long)]
     // pub k2d_dir: Option<PathBuf>,
-    /// Directory to store downloaded files
-    #[arg(short, long, required = true)]
-    pub download_dir: PathBuf,
+    /// Directory to store downloaded files. Without it the library already in the database
+    /// (e.g. from `add-to-library`) is built and the merge fna stage is skipped
+    #[arg(short, long)]
+    pub download_dir: Option<PathBuf>,
 
     // chunk_dir: PathBuf,
     #[clap(flatten)]
@@ -52,6 +67,51 @@ struct BuildArgs {
     /// library fna temp file max size
     #[arg(long = "max-file-size", value_parser = parse_size, default_value = "2G")]
     pub max_file_size: usize,
+
+    /// Specifies the hash file capacity, e.g. '1G', '250M'. Each page holds this many cells
+    #[arg(long, value_parser = parse_size, default_value = "1G")]
+    pub hash_capacity: usize,
+
+    /// Custom taxa for genomes without an NCBI taxid (taxid<TAB>parent taxid<TAB>rank<TAB>name),
+    /// merged into the taxonomy. Their taxids must not exist in nodes.dmp
+    #[arg(long)]
+    pub custom_nodes: Option<PathBuf>,
+
+    /// NCBI accession2taxid files (e.g. nucl_gb.accession2taxid.gz, nucl_wgs.accession2taxid.gz).
+    /// Genomes in the download dir that are not in any assembly summary get their taxids from them. Can be repeated
+    #[arg(long)]
+    pub accession2taxid: Vec<PathBuf>,
+
+    /// Stop after writing the chunk files; build pages with `build-page` and finish with `build-finalize`
+    #[arg(long, default_value_t = false)]
+    pub chunk_only: bool,
+
+    /// Record the crc32 of the library, seqid2taxid.map and taxonomy files in manifest.json.
+    /// Reads the whole library once more; by default only their sizes and modification times are recorded
+    #[arg(long, default_value_t = false)]
+    pub source_checksums: bool,
+}
+
+/// 导入 16S 数据库后按 build 的流程构建, k 和 l 的默认值适合短扩增子
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    author,
+    version,
+    about = "Import a SILVA, Greengenes or RDP 16S database and build it",
+    long_about = None
+)]
+#[command(
+    mut_arg("k_mer", |arg| arg.default_value(import_16s::AMPLICON_KMER_LENGTH)),
+    mut_arg("l_mer", |arg| arg.default_value(import_16s::AMPLICON_MINIMIZER_LENGTH)),
+    mut_arg("minimizer_spaces", |arg| arg.default_value(import_16s::AMPLICON_MINIMIZER_SPACES)),
+    mut_arg("download_dir", |arg| arg.hide(true))
+)]
+struct Build16sArgs {
+    #[clap(flatten)]
+    pub source: import_16s::Source,
+
+    #[clap(flatten)]
+    pub build_args: BuildArgs,
 }
 
 #[derive(Parser, Debug)]
@@ -70,6 +130,7 @@ impl From<ClassifyArgs> for splitr::Args {
             minimum_quality_score: item.minimum_quality_score,
             num_threads: item.num_threads,
             chunk_dir: item.chunk_dir,
+            hash_capacity: item.hash_capacity,
             input_files: item.input_files,
         }
     }
@@ -80,6 +141,7 @@ impl From<ClassifyArgs> for annotate::Args {
         Self {
             database: item.database,
             chunk_dir: item.chunk_dir,
+            hash_capacity: item.hash_capacity,
             batch_size: item.batch_size,
             num_threads: item.num_threads,
         }
@@ -94,10 +156,13 @@ impl From<ClassifyArgs> for resolve::Args {
             batch_size: item.batch_size,
             confidence_threshold: item.confidence_threshold,
             minimum_hit_groups: item.minimum_hit_groups,
+            legacy_hit_groups: item.legacy_hit_groups,
             kraken_output_dir: item.kraken_output_dir,
             report_kmer_data: item.report_kmer_data,
             report_zero_counts: item.report_zero_counts,
             full_output: item.full_output,
+            read_output: item.read_output,
+            scope: item.scope,
             num_threads: item.num_threads,
         }
     }
@@ -120,17 +185,33 @@ impl From<BuildArgs> for chunk_db::Args {
     fn from(item: BuildArgs) -> Self {
         Self {
             build: item.build,
-            hash_capacity: parse_size("1G").unwrap(),
+            hash_capacity: item.hash_capacity,
         }
     }
 }
 
-impl From<BuildArgs> for merge_fna::Args {
+impl From<BuildArgs> for BuildParams {
     fn from(item: BuildArgs) -> Self {
         Self {
             download_dir: item.download_dir,
+            build: item.build,
+            max_n: item.max_n,
+            load_factor: item.load_factor,
+            max_file_size: item.max_file_size,
+            hash_capacity: item.hash_capacity,
+            source_checksums: item.source_checksums,
+            accession2taxid: item.accession2taxid,
+        }
+    }
+}
+
+impl From<BuildArgs> for merge_fna::Args {
+    fn from(item: BuildArgs) -> Self {
+        Self {
+            download_dir: item.download_dir.unwrap_or_default(),
             database: item.build.database,
             max_file_size: item.max_file_size,
+            accession2taxid: item.accession2taxid,
         }
     }
 }
@@ -140,13 +221,171 @@ enum Commands {
     Estimate(estimate_capacity::Args),
     // Seqid2taxid(seqid2taxid::Args),
     Build(BuildArgs),
+    BuildPage(build_page::Args),
+    BuildFinalize(build_page::FinalizeArgs),
     Hashshard(hashshard::Args),
+    Export(export::Args),
+    Inspect(inspect::Args),
+    Hashstats(hashstats::Args),
+    Db(database::Args),
+    Taxonomy(taxonomy::Args),
     Splitr(splitr::Args),
     Annotate(annotate::Args),
     Resolve(resolve::Args),
     Classify(ClassifyArgs),
     Direct(direct::Args),
     MergeFna(merge_fna::Args),
+    AddToLibrary(add_to_library::Args),
+    ImportGtdb(import_gtdb::Args),
+    #[command(name = "import-16s")]
+    Import16s(import_16s::Args),
+    #[command(name = "build-16s")]
+    Build16s(Build16sArgs),
+}
+
+/// 数据库目录中的 library_*.fna
+fn library_files(database: &Path) -> Vec<PathBuf> {
+    find_files(database.join("library"), "library", ".fna")
+}
+
+/// 所有分页都可以继续构建: 分页已完成, 或者 chunk 文件还在
+fn pages_recoverable(database: &Path, checkpoint: &Checkpoint, fingerprint: &str) -> bool {
+    let Ok(config) = HashConfig::from_hash_header(database.join("hash_config.k2d")) else {
+        return false;
+    };
+    (1..=config.partition).all(|page_index| {
+        checkpoint.page(page_index, fingerprint).is_some()
+            || database.join(format!("chunk_{}.k2", page_index)).exists()
+    })
+}
+
+/// 依次运行 merge_fna (没有 download dir 时跳过), estimate_capacity, chunk_db 和 build_k2_db.
+/// 每个阶段完成后在 build_state 中记录指纹, 重新运行时跳过参数和输入都没有变化的阶段和分页
+fn build(cmd_args: BuildArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let database = cmd_args.build.database.clone();
+    let checkpoint = Checkpoint::new(&database)?;
+    checkpoint.save_params(&BuildParams::from(cmd_args.clone()))?;
+    let klmt = &cmd_args.build.klmt;
+
+    match &cmd_args.download_dir {
+        Some(download_dir) => {
+            let mut merge_inputs = merge_fna::input_files(download_dir);
+            merge_inputs.extend(cmd_args.accession2taxid.iter().cloned());
+            let merge_fingerprint =
+                fingerprint(&(download_dir, cmd_args.max_file_size), &merge_inputs)?;
+            if checkpoint
+                .stage(Stage::MergeFna, &merge_fingerprint)
+                .is_some()
+            {
+                println!("merge fna already done, skipping");
+            } else {
+                checkpoint.invalidate_from(Stage::MergeFna)?;
+                merge_fna::clean_outputs(&database)?;
+                merge_fna::run(merge_fna::Args::from(cmd_args.clone()))?;
+                checkpoint.finish_stage(Stage::MergeFna, &merge_fingerprint, None)?;
+            }
+        }
+        None => {
+            for name in ["seqid2taxid.map", "taxo.k2d"] {
+                if !database.join(name).exists() {
+                    return Err(format!(
+                        "{:?} not found, add sequences with `add-to-library` or pass --download-dir",
+                        database.join(name)
+                    )
+                    .into());
+                }
+            }
+            println!("no download dir, building the library in {:?}", database);
+        }
+    }
+
+    // 合并 fna 之后 taxonomy 目录才完整, 合并自定义节点后重新生成 taxo.k2d.
+    // 没有新节点时不重新生成, 以免后面的阶段失效
+    if let Some(custom_nodes) = &cmd_args.custom_nodes {
+        let count = install_custom_nodes(custom_nodes, &database.join("taxonomy"))?;
+        if count > 0 {
+            generate_library_taxonomy(&database)?;
+            println!("installed {} custom taxa", count);
+        }
+    }
+
+    let estimate_fingerprint = fingerprint(
+        &(klmt, cmd_args.max_n, cmd_args.load_factor),
+        &library_files(&database),
+    )?;
+    let required_capacity = match checkpoint
+        .stage(Stage::Estimate, &estimate_fingerprint)
+        .and_then(|marker| marker.value)
+    {
+        Some(required_capacity) => {
+            println!(
+                "estimate already done, required capacity: {}",
+                required_capacity
+            );
+            required_capacity
+        }
+        None => {
+            // 上次的估算已经过期, hllp 缓存也不能再用
+            if checkpoint.stage_marker(Stage::Estimate).is_some() {
+                estimate_capacity::clean_cache(&database)?;
+            }
+            checkpoint.invalidate_from(Stage::Estimate)?;
+            let required_capacity =
+                estimate_capacity::run(estimate_capacity::Args::from(cmd_args.clone()));
+            checkpoint.finish_stage(
+                Stage::Estimate,
+                &estimate_fingerprint,
+                Some(required_capacity),
+            )?;
+            required_capacity
+        }
+    };
+
+    let build_args = chunk_db::Args::from(cmd_args.clone());
+    let mut chunk_inputs = library_files(&database);
+    chunk_inputs.push(database.join("seqid2taxid.map"));
+    chunk_inputs.push(database.join("taxo.k2d"));
+    let chunk_fingerprint = fingerprint(
+        &(
+            klmt,
+            cmd_args.build.requested_bits_for_taxid,
+            build_args.hash_capacity,
+            required_capacity,
+        ),
+        &chunk_inputs,
+    )?;
+    if checkpoint
+        .stage(Stage::ChunkDb, &chunk_fingerprint)
+        .is_some()
+        && pages_recoverable(&database, &checkpoint, &chunk_fingerprint)
+    {
+        println!("chunk db already done, skipping");
+    } else {
+        checkpoint.invalidate_from(Stage::ChunkDb)?;
+        for path in find_files(&database, "chunk_", ".k2")
+            .into_iter()
+            .chain(find_files(&database, "hash_", ".k2d"))
+        {
+            remove_if_exists(path)?;
+        }
+        chunk_db::run(build_args, required_capacity)?;
+        checkpoint.finish_stage(Stage::ChunkDb, &chunk_fingerprint, None)?;
+    }
+
+    if cmd_args.chunk_only {
+        let hash_config = HashConfig::from_hash_header(database.join("hash_config.k2d"))?;
+        println!(
+            "chunk files written, build pages 1-{} with `build-page --db {:?}` and then run `build-finalize`",
+            hash_config.partition, database
+        );
+        return Ok(());
+    }
+
+    build_k2_db::run(&database)?;
+
+    let manifest = Manifest::collect(&database, BuildParams::from(cmd_args))?;
+    manifest.write_to_file(database.join(MANIFEST_FILENAME))?;
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -156,23 +395,60 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Commands::MergeFna(cmd_args) => {
             merge_fna::run(cmd_args)?;
         }
+        Commands::AddToLibrary(cmd_args) => {
+            add_to_library::run(cmd_args)?;
+        }
+        Commands::ImportGtdb(cmd_args) => {
+            import_gtdb::run(cmd_args)?;
+        }
+        Commands::Import16s(cmd_args) => {
+            import_16s::run(cmd_args)?;
+        }
+        Commands::Build16s(cmd_args) => {
+            let build_args = cmd_args.build_args;
+            if build_args.download_dir.is_some() {
+                return Err(
+                    "build-16s builds the imported 16S library, --download-dir is not supported"
+                        .into(),
+                );
+            }
+            import_16s::run(import_16s::Args {
+                database: build_args.build.database.clone(),
+                source: cmd_args.source,
+                max_file_size: build_args.max_file_size,
+            })?;
+            build(build_args)?;
+        }
         Commands::Estimate(cmd_args) => {
             estimate_capacity::run(cmd_args);
         }
         Commands::Build(cmd_args) => {
-            let fna_args = merge_fna::Args::from(cmd_args.clone());
-            merge_fna::run(fna_args)?;
-            let ec_args = estimate_capacity::Args::from(cmd_args.clone());
-            let required_capacity = estimate_capacity::run(ec_args);
-
-            let build_args = chunk_db::Args::from(cmd_args.clone());
-            let database = &build_args.build.database.clone();
-            chunk_db::run(build_args, required_capacity)?;
-            build_k2_db::run(database)?;
+            build(cmd_args)?;
+        }
+        Commands::BuildPage(cmd_args) => {
+            build_page::run(cmd_args)?;
+        }
+        Commands::BuildFinalize(cmd_args) => {
+            build_page::run_finalize(cmd_args)?;
         }
         Commands::Hashshard(cmd_args) => {
             hashshard::run(cmd_args)?;
         }
+        Commands::Export(cmd_args) => {
+            export::run(cmd_args)?;
+        }
+        Commands::Inspect(cmd_args) => {
+            inspect::run(cmd_args)?;
+        }
+        Commands::Hashstats(cmd_args) => {
+            hashstats::run(cmd_args)?;
+        }
+        Commands::Db(cmd_args) => {
+            database::run(cmd_args)?;
+        }
+        Commands::Taxonomy(cmd_args) => {
+            taxonomy::run(cmd_args)?;
+        }
         Commands::Splitr(cmd_args) => {
             splitr::run(cmd_args)?;
         }
@@ -1,10 +1,15 @@
 use clap::Parser;
 use flate2::read::GzDecoder;
 use kr2r::args::parse_size;
+use kr2r::checkpoint::remove_if_exists;
 use kr2r::db::generate_taxonomy;
-use kr2r::utils::{find_files, open_file, read_id_to_taxon_map};
+use kr2r::library::{CUSTOM_LIBRARY_PREFIX, CUSTOM_MAP_FILENAME};
+use kr2r::taxonomy::{RedirectStats, TaxidRedirect};
+use kr2r::utils::{
+    find_files, open_file, open_text_file, read_accession2taxid, read_id_to_taxon_map,
+};
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{create_dir_all, File, OpenOptions};
 use std::io::{BufRead, BufReader, BufWriter, Read, Result, Write};
 use std::path::PathBuf;
@@ -26,6 +31,11 @@ pub struct Args {
     /// library fna temp file max size
     #[arg(long = "max-file-size", value_parser = parse_size, default_value = "2G")]
     pub max_file_size: usize,
+
+    /// NCBI accession2taxid files (e.g. nucl_gb.accession2taxid.gz, nucl_wgs.accession2taxid.gz).
+    /// Genomes in the download dir that are not in any assembly summary get their taxids from them. Can be repeated
+    #[arg(long)]
+    pub accession2taxid: Vec<PathBuf>,
 }
 
 struct SizedWriter {
@@ -132,12 +142,13 @@ fn parse_assembly_fna(assembly_file: &PathBuf, site: &str) -> Result<Vec<(String
     Ok(gz_files)
 }
 
+/// 逐条序列写入 library 和 seqid2taxid, taxid 由 seqid 得到, 没有 taxid 的序列跳过
 fn process_gz_file(
     gz_file: &PathBuf,
     map_writer: &mut BufWriter<File>,
     fna_writer: &mut SizedWriter,
     fna_start: &regex::Regex,
-    taxid: &str,
+    taxid_of: &(dyn Fn(&str) -> Option<String> + Sync),
 ) -> Result<()> {
     let file = open_file(gz_file)?;
     let decompressor = GzDecoder::new(BufReader::new(file));
@@ -146,19 +157,24 @@ fn process_gz_file(
     let mut line = String::new();
     let mut map_buffer = String::new(); // Buffer for map writer
     let mut fna_buffer = String::new(); // Buffer for fna writer
+    let mut skipping = false;
 
     while reader.read_line(&mut line)? != 0 {
         if let Some(caps) = fna_start.captures(&line) {
             let seqid = &caps[1];
-            map_buffer.push_str(&format!("taxid|{}|{}\t{}\n", taxid, seqid, taxid));
+            let taxid = taxid_of(seqid);
+            skipping = taxid.is_none();
 
             if !fna_buffer.is_empty() {
                 fna_writer.write(fna_buffer.as_bytes())?;
                 fna_buffer.clear();
             }
 
-            fna_buffer.push_str(&format!(">taxid|{}|{}", taxid, &line[1..]));
-        } else {
+            if let Some(taxid) = taxid {
+                map_buffer.push_str(&format!("taxid|{}|{}\t{}\n", taxid, seqid, taxid));
+                fna_buffer.push_str(&format!(">taxid|{}|{}", taxid, &line[1..]));
+            }
+        } else if !skipping {
             fna_buffer.push_str(&line);
         }
 
@@ -193,12 +209,16 @@ fn process_gz_file(
 
 const PREFIX: &'static str = "assembly_summary";
 const SUFFIX: &'static str = "txt";
+/// 从下载目录复制到数据库的 taxonomy 文件
+const TAXONOMY_FILES: [&str; 4] = ["nodes.dmp", "names.dmp", "merged.dmp", "delnodes.dmp"];
 
 fn merge_fna_parallel(
     assembly_files: &Vec<PathBuf>,
+    download_dir: &PathBuf,
     database: &PathBuf,
     library_dir: &PathBuf,
     max_file_size: u64,
+    accession2taxid: &[PathBuf],
 ) -> Result<()> {
     let pattern = format!(r"{}_(\S+)\.{}", PREFIX, SUFFIX);
     let file_site = regex::Regex::new(&pattern).unwrap();
@@ -206,59 +226,134 @@ fn merge_fna_parallel(
     let fna_start: regex::Regex = regex::Regex::new(r"^>(\S+)").unwrap();
     let is_empty = AtomicBool::new(true);
     let writers: Arc<Mutex<HashMap<usize, SizedWriter>>> = Arc::new(Mutex::new(HashMap::new()));
+    // assembly summary 中合并过的 taxid 改为新的 taxid, 已删除的跳过
+    let redirect = TaxidRedirect::from_dir(database.join("taxonomy"))?;
+    let mut redirect_stats = RedirectStats::default();
+
+    let write_gz_file = |gz_file: &PathBuf, taxid_of: &(dyn Fn(&str) -> Option<String> + Sync)| {
+        if !gz_file.exists() {
+            // eprintln!("{} does not exist", gz_file.to_string_lossy());
+            return;
+        }
 
+        let thread_index = rayon::current_thread_index().unwrap_or(0);
+        let mut writers = writers.lock().unwrap();
+        let mut fna_writer = writers.entry(thread_index).or_insert_with(|| {
+            SizedWriter::new(&library_dir, thread_index, max_file_size).unwrap()
+        });
+        let seqid2taxid_path = database.join(format!("seqid2taxid_{}.map", thread_index));
+        let mut map_writer = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(true)
+                .open(&seqid2taxid_path)
+                .unwrap(),
+        );
+
+        if let Err(e) = process_gz_file(
+            gz_file,
+            &mut map_writer,
+            &mut fna_writer,
+            &fna_start,
+            taxid_of,
+        ) {
+            eprintln!("process_gz_file error: {}", e);
+        } else {
+            fna_writer.flush().unwrap();
+            map_writer.flush().unwrap();
+            is_empty.fetch_and(false, Ordering::Relaxed);
+        }
+    };
+
+    // assembly summary 中列出的基因组, 包括 taxid 已删除而跳过的
+    let mut listed = HashSet::new();
     for assembly_file in assembly_files {
         if let Some(caps) = file_site.captures(assembly_file.to_string_lossy().as_ref()) {
             if let Some(matched) = caps.get(1) {
-                let gz_files = parse_assembly_fna(assembly_file, matched.as_str())?;
+                let gz_files: Vec<(String, String)> =
+                    parse_assembly_fna(assembly_file, matched.as_str())?
+                        .into_iter()
+                        .inspect(|(gz_path, _)| {
+                            listed.insert(PathBuf::from(gz_path));
+                        })
+                        .filter_map(|(gz_path, taxid)| match taxid.parse::<u64>() {
+                            Ok(id) => redirect
+                                .resolve_counted(id, &mut redirect_stats)
+                                .map(|id| (gz_path, id.to_string())),
+                            Err(_) => Some((gz_path, taxid)),
+                        })
+                        .collect();
 
                 gz_files.par_iter().for_each(|(gz_path, taxid)| {
-                    let gz_file = PathBuf::from(&gz_path);
-                    if !gz_file.exists() {
-                        // eprintln!("{} does not exist", gz_file.to_string_lossy());
-                        return;
-                    }
-
-                    let thread_index = rayon::current_thread_index().unwrap_or(0);
-                    let mut writers = writers.lock().unwrap();
-                    let mut fna_writer = writers.entry(thread_index).or_insert_with(|| {
-                        SizedWriter::new(&library_dir, thread_index, max_file_size).unwrap()
-                    });
-                    let seqid2taxid_path =
-                        database.join(format!("seqid2taxid_{}.map", thread_index));
-                    let mut map_writer = BufWriter::new(
-                        OpenOptions::new()
-                            .create(true)
-                            .write(true)
-                            .append(true)
-                            .open(&seqid2taxid_path)
-                            .unwrap(),
-                    );
-
-                    if let Err(e) = process_gz_file(
-                        &gz_file,
-                        &mut map_writer,
-                        &mut fna_writer,
-                        &fna_start,
-                        &taxid,
-                    ) {
-                        eprintln!("process_gz_file error: {}", e);
-                    } else {
-                        fna_writer.flush().unwrap();
-                        map_writer.flush().unwrap();
-                        is_empty.fetch_and(false, Ordering::Relaxed);
-                    }
+                    write_gz_file(&PathBuf::from(gz_path), &|_| Some(taxid.clone()));
                 });
             }
         }
     }
 
+    redirect_stats.warn("assembly summaries");
+
+    // 不在 assembly summary 中的基因组, 按序列的 accession 从 accession2taxid 中查找 taxid
+    let unlisted: Vec<PathBuf> = find_files(download_dir.join("library"), "", "_genomic.fna.gz")
+        .into_iter()
+        .filter(|path| !listed.contains(path))
+        .collect();
+    if !unlisted.is_empty() && accession2taxid.is_empty() {
+        println!(
+            "{} genomes are not in any assembly summary, pass --accession2taxid to add them",
+            unlisted.len()
+        );
+    } else if !unlisted.is_empty() {
+        let mut accessions = HashSet::new();
+        for path in &unlisted {
+            for line in open_text_file(path)?.lines() {
+                let line = line?;
+                if let Some(caps) = fna_start.captures(&line) {
+                    accessions.insert(caps[1].to_string());
+                }
+            }
+        }
+        let mut redirect_stats = RedirectStats::default();
+        let taxids: HashMap<String, String> = read_accession2taxid(accession2taxid, &accessions)?
+            .into_iter()
+            .filter_map(|(accession, taxid)| {
+                redirect
+                    .resolve_counted(taxid, &mut redirect_stats)
+                    .map(|taxid| (accession, taxid.to_string()))
+            })
+            .collect();
+        redirect_stats.warn("accession2taxid");
+        println!(
+            "accession2taxid: found {} of {} accessions in {} genomes outside the assembly summaries",
+            taxids.len(),
+            accessions.len(),
+            unlisted.len()
+        );
+        if taxids.len() < accessions.len() {
+            eprintln!(
+                "warning: skipped the sequences of {} accessions without a taxid",
+                accessions.len() - taxids.len()
+            );
+        }
+
+        unlisted.par_iter().for_each(|gz_file| {
+            write_gz_file(gz_file, &|seqid| taxids.get(seqid).cloned());
+        });
+    }
+
     // let fna_files = find_files(database, "library_", "fna");
     let seqid_files = find_files(database, "seqid2taxid_", "map");
     // let library_fna_path = database.join("library.fna");
     let seqid2taxid_path = database.join("seqid2taxid.map");
     // merge_files(&fna_files, &library_fna_path)?;
     merge_files(&seqid_files, &seqid2taxid_path)?;
+    // add-to-library 加入的序列保留在 library 中, 记录追加回 seqid2taxid.map
+    let custom_map_path = library_dir.join(CUSTOM_MAP_FILENAME);
+    if custom_map_path.exists() {
+        let mut output = OpenOptions::new().append(true).open(&seqid2taxid_path)?;
+        std::io::copy(&mut open_file(&custom_map_path)?, &mut output)?;
+    }
     if is_empty.load(Ordering::Relaxed) {
         panic!("genimics fna files is empty! please check download dir");
     }
@@ -294,6 +389,50 @@ fn merge_files(paths: &Vec<PathBuf>, output_path: &PathBuf) -> Result<()> {
     Ok(())
 }
 
+/// merge fna 的输入文件: assembly summary, 下载的 fna.gz 和 taxonomy
+pub fn input_files(download_dir: &PathBuf) -> Vec<PathBuf> {
+    let mut files = find_files(download_dir, PREFIX, SUFFIX);
+    files.extend(find_files(
+        download_dir.join("library"),
+        "",
+        "_genomic.fna.gz",
+    ));
+    for name in TAXONOMY_FILES {
+        let path = download_dir.join("taxonomy").join(name);
+        if path.exists() {
+            files.push(path);
+        }
+    }
+    files
+}
+
+/// 删除 merge fna 生成的文件, 重新运行前调用. library_*.fna 以追加方式写入, 不删除会重复.
+/// 用户提供的 library.fna 和 add-to-library 加入的 library_custom_*.fna 保持不变
+pub fn clean_outputs(database: &PathBuf) -> Result<()> {
+    let library_dir = database.join("library");
+    let mut files: Vec<PathBuf> = find_files(&library_dir, "library_", ".fna")
+        .into_iter()
+        .filter(|path| {
+            !path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(CUSTOM_LIBRARY_PREFIX))
+        })
+        .collect();
+    files.extend(find_files(&library_dir, "library_", ".json"));
+    files.extend(find_files(database, "seqid2taxid_", ".map"));
+    if !database.join("library.fna").exists() {
+        files.push(database.join("seqid2taxid.map"));
+    }
+    files.push(database.join("taxo.k2d"));
+    files.push(database.join("taxonomy").join("nodes.dmp"));
+    files.push(database.join("taxonomy").join("names.dmp"));
+    for path in files {
+        remove_if_exists(path)?;
+    }
+    Ok(())
+}
+
 pub fn run(args: Args) -> Result<()> {
     // 开始计时
     let start = Instant::now();
@@ -308,18 +447,16 @@ pub fn run(args: Args) -> Result<()> {
     let library_dir = database.join("library");
     create_dir_all(&library_dir)?;
 
-    let source_names_file = &download_dir.join("taxonomy").join("names.dmp");
-    assert!(source_names_file.exists());
-    let dst_name_file = &dst_tax_dir.join("names.dmp");
-    if !dst_name_file.exists() {
-        std::fs::copy(source_names_file, dst_name_file)?;
-    }
-
-    let source_nodes_file = &download_dir.join("taxonomy").join("nodes.dmp");
-    assert!(source_nodes_file.exists());
-    let dst_nodes_file = &dst_tax_dir.join("nodes.dmp");
-    if !dst_nodes_file.exists() {
-        std::fs::copy(source_nodes_file, dst_nodes_file)?;
+    for name in TAXONOMY_FILES {
+        let source_file = download_dir.join("taxonomy").join(name);
+        let dst_file = dst_tax_dir.join(name);
+        // merged.dmp 和 delnodes.dmp 可以没有
+        if name == "nodes.dmp" || name == "names.dmp" {
+            assert!(source_file.exists());
+        }
+        if source_file.exists() && !dst_file.exists() {
+            std::fs::copy(&source_file, &dst_file)?;
+        }
     }
 
     let library_fna_path = database.join("library.fna");
@@ -339,9 +476,11 @@ pub fn run(args: Args) -> Result<()> {
 
     merge_fna_parallel(
         &assembly_files,
+        &download_dir,
         &args.database,
         &library_dir,
         *max_file_size as u64,
+        &args.accession2taxid,
     )?;
 
     let id_to_taxon_map_filename = args.database.join("seqid2taxid.map");
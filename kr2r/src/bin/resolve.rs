@@ -1,8 +1,9 @@
 use clap::Parser;
-use kr2r::classify::process_hitgroup;
+use kr2r::args::{ReadOutputArgs, ScopeArgs, ONEGB};
+use kr2r::classify::{format_read_output, process_hitgroup, CallOptions, TaxidRules};
 use kr2r::compact_hash::{HashConfig, Row};
 use kr2r::readcounts::{TaxonCounters, TaxonCountersDash};
-use kr2r::report::report_kraken_style;
+use kr2r::report::write_reports;
 use kr2r::taxonomy::Taxonomy;
 use kr2r::utils::{find_and_trans_files, open_file};
 use kr2r::HitGroup;
@@ -70,6 +71,13 @@ pub struct Args {
     /// output file contains all unclassified sequence
     #[clap(long, value_parser, default_value_t = false)]
     pub full_output: bool,
+
+    #[clap(flatten)]
+    pub read_output: ReadOutputArgs,
+
+    #[clap(flatten)]
+    pub scope: ScopeArgs,
+
     /// Confidence score threshold, default is 0.0.
     #[clap(
         short = 'T',
@@ -96,6 +104,11 @@ pub struct Args {
     )]
     pub minimum_hit_groups: usize,
 
+    /// Count hit groups as the number of minimizers in the read, as earlier versions did,
+    /// instead of Kraken 2's runs of identical minimizers that hit the database.
+    #[clap(long, value_parser, default_value_t = false)]
+    pub legacy_hit_groups: bool,
+
     #[clap(long, default_value_t = BATCH_SIZE)]
     pub batch_size: usize,
 
@@ -122,13 +135,18 @@ fn process_batch<P: AsRef<Path>>(
     sample_file: P,
     args: &Args,
     taxonomy: &Taxonomy,
+    rules: &TaxidRules,
     id_map: &HashMap<u32, (String, String, usize, Option<usize>)>,
     writer: &mut Box<dyn Write + Send>,
     value_mask: usize,
 ) -> Result<(TaxonCountersDash, usize, HashSet<u32>)> {
     let hit_seq_id_set = HashSet::new();
     let confidence_threshold = args.confidence_threshold;
-    let minimum_hit_groups = args.minimum_hit_groups;
+    let call_options = CallOptions {
+        minimum_hit_groups: args.minimum_hit_groups,
+        legacy_hit_groups: args.legacy_hit_groups,
+        report_rank: args.read_output.report_rank_index(),
+    };
 
     let hit_counts: HashMap<u32, Vec<Row>> = read_rows_from_file(sample_file)?;
 
@@ -142,6 +160,7 @@ fn process_batch<P: AsRef<Path>>(
             if let Some(item) = id_map.get(&k) {
                 let mut rows = rows.to_owned();
                 rows.sort_unstable();
+                rules.scope.retain(&mut rows, taxonomy, value_mask);
                 let dna_id = trim_pair_info(&item.0);
                 let range =
                     OptionPair::from(((0, item.2), item.3.map(|size| (item.2, size + item.2))));
@@ -152,8 +171,8 @@ fn process_batch<P: AsRef<Path>>(
                     taxonomy,
                     &classify_counter,
                     hits.required_score(confidence_threshold),
-                    minimum_hit_groups,
                     value_mask,
+                    call_options,
                 );
 
                 hit_data.3.iter().for_each(|(key, value)| {
@@ -165,9 +184,14 @@ fn process_batch<P: AsRef<Path>>(
                 });
 
                 // 使用锁来同步写入
-                let output_line = format!(
-                    "{}\t{}\t{}\t{}\t{}\n",
-                    hit_data.0, dna_id, hit_data.1, item.1, hit_data.2
+                let output_line = format_read_output(
+                    taxonomy,
+                    &args.read_output,
+                    &hit_data.0,
+                    &dna_id,
+                    rules.remap.external_call(taxonomy, hit_data.1),
+                    &item.1,
+                    &hit_data.2,
                 );
                 Some(output_line)
             } else {
@@ -193,12 +217,14 @@ pub fn run(args: Args) -> Result<()> {
     let k2d_dir = &args.database;
     let taxonomy_filename = k2d_dir.join("taxo.k2d");
     let taxo = Taxonomy::from_file(taxonomy_filename)?;
+    let rules = TaxidRules::from_args(k2d_dir, &args.scope, &args.read_output, &taxo)?;
 
     let sample_files = find_and_trans_files(&args.chunk_dir, "sample_file", ".bin", false)?;
     let sample_id_files = find_and_trans_files(&args.chunk_dir, "sample_id", ".map", false)?;
 
     // let partition = sample_files.len();
-    let hash_config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
+    // 只用到 value_mask, 与分页大小无关
+    let hash_config = HashConfig::from_database(&args.database, ONEGB as usize)?;
     let value_mask = hash_config.value_mask;
 
     let mut total_taxon_counts = TaxonCounters::new();
@@ -227,6 +253,7 @@ pub fn run(args: Args) -> Result<()> {
             &sample_file,
             &args,
             &taxo,
+            &rules,
             &sample_id_map,
             &mut writer,
             value_mask,
@@ -266,15 +293,16 @@ pub fn run(args: Args) -> Result<()> {
                 .unwrap();
         });
         if let Some(output) = &args.kraken_output_dir {
-            let filename = output.join(format!("output_{}.kreport2", i));
-            report_kraken_style(
-                filename,
-                args.report_zero_counts,
-                args.report_kmer_data,
+            write_reports(
+                output.join(format!("output_{}", i)),
                 &taxo,
+                &rules,
                 &sample_taxon_counts,
                 thread_sequences as u64,
                 (thread_sequences - thread_classified) as u64,
+                args.report_zero_counts,
+                args.report_kmer_data,
+                args.read_output.report_rank.as_deref(),
             )?;
         }
 
@@ -287,15 +315,16 @@ pub fn run(args: Args) -> Result<()> {
         let max = &sample_files.keys().max().cloned().unwrap();
 
         if max > min {
-            let filename = output.join(format!("output_{}-{}.kreport2", min, max));
-            report_kraken_style(
-                filename,
-                args.report_zero_counts,
-                args.report_kmer_data,
+            write_reports(
+                output.join(format!("output_{}-{}", min, max)),
                 &taxo,
+                &rules,
                 &total_taxon_counts,
                 total_seqs as u64,
                 total_unclassified as u64,
+                args.report_zero_counts,
+                args.report_kmer_data,
+                args.read_output.report_rank.as_deref(),
             )?;
         }
 
@@ -1,4 +1,5 @@
 use clap::Parser;
+use kr2r::args::parse_size;
 use kr2r::compact_hash::{HashConfig, Slot};
 use kr2r::utils::{
     create_partition_files, create_partition_writers, create_sample_file, get_file_limit,
@@ -55,6 +56,10 @@ pub struct Args {
     #[clap(long)]
     pub chunk_dir: PathBuf,
 
+    /// Capacity of each virtual page when reading a Kraken 2 hash.k2d directly (used only if hash_config.k2d is absent).
+    #[clap(long = "hash-capacity", value_parser = parse_size, default_value = "1G")]
+    pub hash_capacity: usize,
+
     /// A list of input file paths (FASTA/FASTQ) to be processed by the classify program.
     /// Supports fasta or fastq format files (e.g., .fasta, .fastq) and gzip compressed files (e.g., .fasta.gz, .fastq.gz).
     // #[clap(short = 'F', long = "files")]
@@ -248,12 +253,9 @@ pub fn run(args: Args) -> Result<()> {
             "Paired-end processing requires an even number of input files.",
         ));
     }
-    let hash_config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
+    let hash_config = HashConfig::from_database(&args.database, args.hash_capacity)?;
 
     println!("{:?}", hash_config);
-    if hash_config.hash_capacity == 0 {
-        panic!("`hash_capacity` can't be zero!");
-    }
     println!("splitr start...");
     let file_num_limit = get_file_limit();
     if hash_config.partition >= file_num_limit {
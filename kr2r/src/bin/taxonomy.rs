@@ -0,0 +1,300 @@
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use kr2r::taxonomy::{RedirectStats, TaxidRedirect, Taxonomy};
+use regex::RegexBuilder;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufWriter, Result, Write};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Query the taxonomy of a database (taxo.k2d): lineage, name search and subtree listing"
+)]
+pub struct Args {
+    #[clap(subcommand)]
+    pub cmd: TaxonomyCommands,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum TaxonomyCommands {
+    /// Print the full ranked lineage of one or more taxids
+    Lineage(LineageArgs),
+    /// Find taxa by name (case-insensitive prefix, or a regex with --regex)
+    Search(SearchArgs),
+    /// List a taxon and all of its descendants
+    Subtree(SubtreeArgs),
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Tsv,
+    Json,
+}
+
+/// 各子命令共用的参数
+#[derive(ClapArgs, Debug, Clone)]
+pub struct Common {
+    /// database directory or archive containing taxo.k2d
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = Format::Tsv)]
+    pub format: Format,
+
+    /// Output file, default is stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(ClapArgs, Debug, Clone)]
+pub struct LineageArgs {
+    #[clap(flatten)]
+    pub common: Common,
+
+    /// External taxids
+    #[arg(required = true, num_args = 1..)]
+    pub taxids: Vec<u64>,
+}
+
+#[derive(ClapArgs, Debug, Clone)]
+pub struct SearchArgs {
+    #[clap(flatten)]
+    pub common: Common,
+
+    /// Name prefix, or a regular expression with --regex
+    pub query: String,
+
+    /// Treat the query as a regular expression (unanchored, case-insensitive)
+    #[clap(long, value_parser, default_value_t = false)]
+    pub regex: bool,
+
+    /// Only report taxa at these ranks, e.g. --rank species,genus
+    #[arg(long, value_delimiter = ',')]
+    pub rank: Vec<String>,
+}
+
+#[derive(ClapArgs, Debug, Clone)]
+pub struct SubtreeArgs {
+    #[clap(flatten)]
+    pub common: Common,
+
+    /// External taxid of the subtree root
+    pub taxid: u64,
+
+    /// Only report taxa at these ranks, e.g. --rank species
+    #[arg(long, value_delimiter = ',')]
+    pub rank: Vec<String>,
+
+    /// Maximum depth below the subtree root (0 lists the root only)
+    #[arg(long)]
+    pub max_depth: Option<usize>,
+}
+
+/// 输出的一个节点
+#[derive(Serialize, Debug)]
+struct TaxonRecord {
+    taxid: u64,
+    name: String,
+    rank: String,
+    parent_taxid: u64,
+    /// subtree 中相对于子树根节点的深度
+    #[serde(skip_serializing_if = "Option::is_none")]
+    depth: Option<usize>,
+    /// lineage 中从根节点开始的祖先, 包含节点本身
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lineage: Option<Vec<LineageEntry>>,
+}
+
+#[derive(Serialize, Debug)]
+struct LineageEntry {
+    taxid: u64,
+    name: String,
+    rank: String,
+}
+
+fn record(taxonomy: &Taxonomy, internal_id: u32) -> TaxonRecord {
+    let node = &taxonomy.nodes[internal_id as usize];
+    TaxonRecord {
+        taxid: node.external_id,
+        name: taxonomy.name(internal_id).to_string(),
+        rank: taxonomy.rank(internal_id).to_string(),
+        parent_taxid: taxonomy.nodes[node.parent_id as usize].external_id,
+        depth: None,
+        lineage: None,
+    }
+}
+
+fn load_taxonomy(common: &Common) -> Result<Taxonomy> {
+    Taxonomy::from_file(common.database.join("taxo.k2d"))
+}
+
+/// 数据库 taxonomy 目录中 merged.dmp/delnodes.dmp 的重定向
+fn load_redirect(common: &Common) -> Result<TaxidRedirect> {
+    TaxidRedirect::from_dir(common.database.join("taxonomy"))
+}
+
+/// 外部 taxid 对应的内部 id. 先经过 merged.dmp/delnodes.dmp 重定向, 已删除或不在 taxonomy 中时返回 None
+fn internal_id(
+    taxonomy: &Taxonomy,
+    redirect: &TaxidRedirect,
+    stats: &mut RedirectStats,
+    taxid: u64,
+) -> Option<u32> {
+    let current = redirect.resolve_counted(taxid, stats)?;
+    match taxonomy.get_internal_id(current) {
+        0 => None,
+        id => Some(id),
+    }
+}
+
+fn rank_filter(ranks: &[String]) -> impl Fn(&str) -> bool {
+    let ranks: HashSet<String> = ranks.iter().map(|rank| rank.to_lowercase()).collect();
+    move |rank: &str| ranks.is_empty() || ranks.contains(&rank.to_lowercase())
+}
+
+fn write_records(common: &Common, records: &[TaxonRecord]) -> Result<()> {
+    let mut writer: Box<dyn Write> = match &common.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    match common.format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut writer, records)?;
+            writeln!(writer)?;
+        }
+        Format::Tsv => {
+            let has_depth = records.iter().any(|r| r.depth.is_some());
+            let has_lineage = records.iter().any(|r| r.lineage.is_some());
+            write!(writer, "#taxid\tname\trank\tparent_taxid")?;
+            if has_depth {
+                write!(writer, "\tdepth")?;
+            }
+            if has_lineage {
+                write!(writer, "\tlineage_taxids\tlineage_ranks\tlineage_names")?;
+            }
+            writeln!(writer)?;
+            for r in records {
+                write!(
+                    writer,
+                    "{}\t{}\t{}\t{}",
+                    r.taxid, r.name, r.rank, r.parent_taxid
+                )?;
+                if let Some(depth) = r.depth {
+                    write!(writer, "\t{}", depth)?;
+                }
+                if let Some(lineage) = &r.lineage {
+                    let join = |f: &dyn Fn(&LineageEntry) -> String| {
+                        lineage.iter().map(f).collect::<Vec<_>>().join(";")
+                    };
+                    write!(
+                        writer,
+                        "\t{}\t{}\t{}",
+                        join(&|e| e.taxid.to_string()),
+                        join(&|e| e.rank.clone()),
+                        join(&|e| e.name.clone())
+                    )?;
+                }
+                writeln!(writer)?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+fn lineage(args: LineageArgs) -> Result<()> {
+    let taxonomy = load_taxonomy(&args.common)?;
+    let redirect = load_redirect(&args.common)?;
+    let mut stats = RedirectStats::default();
+    let mut records = Vec::new();
+    for &taxid in &args.taxids {
+        let Some(id) = internal_id(&taxonomy, &redirect, &mut stats, taxid) else {
+            eprintln!("taxid {} is not in the database taxonomy", taxid);
+            continue;
+        };
+        let mut rec = record(&taxonomy, id);
+        rec.lineage = Some(
+            taxonomy
+                .lineage(id)
+                .into_iter()
+                .map(|ancestor| LineageEntry {
+                    taxid: taxonomy.nodes[ancestor as usize].external_id,
+                    name: taxonomy.name(ancestor).to_string(),
+                    rank: taxonomy.rank(ancestor).to_string(),
+                })
+                .collect(),
+        );
+        records.push(rec);
+    }
+    stats.warn("lineage taxids");
+    write_records(&args.common, &records)
+}
+
+fn search(args: SearchArgs) -> Result<()> {
+    let taxonomy = load_taxonomy(&args.common)?;
+    let matcher: Box<dyn Fn(&str) -> bool> = if args.regex {
+        let re = RegexBuilder::new(&args.query)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Box::new(move |name| re.is_match(name))
+    } else {
+        let prefix = args.query.to_lowercase();
+        Box::new(move |name| name.to_lowercase().starts_with(&prefix))
+    };
+    let rank_ok = rank_filter(&args.rank);
+    let records: Vec<TaxonRecord> = (1..taxonomy.node_count() as u32)
+        .filter(|&id| rank_ok(taxonomy.rank(id)) && matcher(taxonomy.name(id)))
+        .map(|id| record(&taxonomy, id))
+        .collect();
+    write_records(&args.common, &records)
+}
+
+fn subtree(args: SubtreeArgs) -> Result<()> {
+    let taxonomy = load_taxonomy(&args.common)?;
+    let redirect = load_redirect(&args.common)?;
+    let mut stats = RedirectStats::default();
+    let root = internal_id(&taxonomy, &redirect, &mut stats, args.taxid);
+    stats.warn("subtree taxid");
+    let root = root.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("taxid {} is not in the database taxonomy", args.taxid),
+        )
+    })?;
+    let rank_ok = rank_filter(&args.rank);
+    let max_depth = args.max_depth.unwrap_or(usize::MAX);
+
+    // 先序遍历, 子节点按内部 id 顺序输出
+    let mut records = Vec::new();
+    let mut stack = vec![(root, 0)];
+    while let Some((id, depth)) = stack.pop() {
+        if rank_ok(taxonomy.rank(id)) {
+            let mut rec = record(&taxonomy, id);
+            rec.depth = Some(depth);
+            records.push(rec);
+        }
+        if depth < max_depth {
+            stack.extend(taxonomy.children(id).rev().map(|child| (child, depth + 1)));
+        }
+    }
+    write_records(&args.common, &records)
+}
+
+pub fn run(args: Args) -> Result<()> {
+    match args.cmd {
+        TaxonomyCommands::Lineage(lineage_args) => lineage(lineage_args),
+        TaxonomyCommands::Search(search_args) => search(search_args),
+        TaxonomyCommands::Subtree(subtree_args) => subtree(subtree_args),
+    }
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
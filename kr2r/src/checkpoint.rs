@@ -0,0 +1,243 @@
+//! 构建断点: 在数据库目录的 build_state 中记录已完成的阶段和分页
+//!
+//! 每个标记保存该阶段的指纹(参数和输入文件的大小, 修改时间的 crc32).
+//! 重新运行 build 时指纹一致的阶段和分页会被跳过, 指纹变化说明中间文件已过期, 需要重新生成.
+use crate::k2d::{self, FileKind};
+use crate::manifest::BuildParams;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+pub const CHECKPOINT_DIR: &str = "build_state";
+
+/// 构建阶段, 按执行顺序排列
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    MergeFna,
+    Estimate,
+    ChunkDb,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 3] = [Stage::MergeFna, Stage::Estimate, Stage::ChunkDb];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::MergeFna => "merge_fna",
+            Stage::Estimate => "estimate",
+            Stage::ChunkDb => "chunk_db",
+        }
+    }
+}
+
+/// 阶段完成标记
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StageMarker {
+    pub fingerprint: String,
+    /// 阶段的结果, 例如 estimate 的 required capacity
+    pub value: Option<usize>,
+}
+
+/// 分页完成标记
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PageMarker {
+    /// 生成 chunk 文件的 chunk_db 阶段的指纹
+    pub fingerprint: String,
+    /// 分页中的元素数量
+    pub size: usize,
+    pub file_size: u64,
+    /// hash_N.k2d 头部中的 crc32
+    pub crc32: Option<u32>,
+}
+
+/// 计算参数和输入文件的指纹. 文件只比较路径, 大小和修改时间, 不读取内容
+pub fn fingerprint<T: Serialize>(params: &T, files: &[PathBuf]) -> Result<String> {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&serde_json::to_vec(params)?);
+    for path in files {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.update(&metadata.len().to_le_bytes());
+        hasher.update(&modified.to_le_bytes());
+    }
+    Ok(format!("{:08x}-{}", hasher.finalize(), files.len()))
+}
+
+pub struct Checkpoint {
+    database: PathBuf,
+    dir: PathBuf,
+}
+
+impl Checkpoint {
+    pub fn new<P: AsRef<Path>>(database: P) -> Result<Self> {
+        let database = database.as_ref().to_path_buf();
+        let dir = database.join(CHECKPOINT_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(Self { database, dir })
+    }
+
+    fn stage_path(&self, stage: Stage) -> PathBuf {
+        self.dir.join(format!("{}.done", stage.name()))
+    }
+
+    fn page_path(&self, page_index: usize) -> PathBuf {
+        self.dir.join(format!("page_{}.done", page_index))
+    }
+
+    /// 指纹一致时返回阶段标记
+    pub fn stage(&self, stage: Stage, fingerprint: &str) -> Option<StageMarker> {
+        read_marker::<StageMarker>(&self.stage_path(stage))
+            .filter(|marker| marker.fingerprint == fingerprint)
+    }
+
+    pub fn stage_marker(&self, stage: Stage) -> Option<StageMarker> {
+        read_marker(&self.stage_path(stage))
+    }
+
+    /// chunk_db 阶段的指纹. chunk 文件不是由 build 生成时没有,
+    /// 这时无法判断分页标记是否属于当前的 chunk 文件
+    pub fn chunk_fingerprint(&self) -> Option<String> {
+        self.stage_marker(Stage::ChunkDb)
+            .map(|marker| marker.fingerprint)
+    }
+
+    /// 与 chunk_fingerprint 相同, 没有时返回错误
+    pub fn require_chunk_fingerprint(&self) -> Result<String> {
+        self.chunk_fingerprint().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no chunk_db marker in {:?}, the chunk files were not written by `build`; run `build --chunk-only` first",
+                    self.dir
+                ),
+            )
+        })
+    }
+
+    pub fn finish_stage(
+        &self,
+        stage: Stage,
+        fingerprint: &str,
+        value: Option<usize>,
+    ) -> Result<()> {
+        let marker = StageMarker {
+            fingerprint: fingerprint.to_string(),
+            value,
+        };
+        write_marker(&self.stage_path(stage), &marker)
+    }
+
+    /// 删除 stage 及之后阶段的标记, 以及全部分页标记
+    pub fn invalidate_from(&self, stage: Stage) -> Result<()> {
+        for later in Stage::ALL.iter().filter(|s| **s >= stage) {
+            remove_if_exists(self.stage_path(*later))?;
+        }
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let is_page = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("page_") && name.ends_with(".done"));
+            if is_page {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 保存 build 的参数, 供 build-finalize 写入 manifest
+    pub fn save_params(&self, params: &BuildParams) -> Result<()> {
+        write_marker(&self.dir.join("params.json"), params)
+    }
+
+    pub fn load_params(&self) -> Result<BuildParams> {
+        read_marker(&self.dir.join("params.json")).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "build parameters not found in {:?}, run `build` first",
+                    self.dir
+                ),
+            )
+        })
+    }
+
+    /// 分页 (从1开始) 已按 fingerprint 完成, 并且 hash_N.k2d 与标记一致时返回标记
+    pub fn page(&self, page_index: usize, fingerprint: &str) -> Option<PageMarker> {
+        let marker = read_marker::<PageMarker>(&self.page_path(page_index))?;
+        if marker.fingerprint != fingerprint {
+            return None;
+        }
+        let page_file = self.database.join(format!("hash_{}.k2d", page_index));
+        let file_size = fs::metadata(&page_file).ok()?.len();
+        let crc32 = page_checksum(&page_file).ok()?;
+        if file_size != marker.file_size || crc32 != marker.crc32 {
+            return None;
+        }
+        Some(marker)
+    }
+
+    pub fn finish_page(&self, page_index: usize, fingerprint: &str, size: usize) -> Result<()> {
+        let page_file = self.database.join(format!("hash_{}.k2d", page_index));
+        let marker = PageMarker {
+            fingerprint: fingerprint.to_string(),
+            size,
+            file_size: fs::metadata(&page_file)?.len(),
+            crc32: page_checksum(&page_file)?,
+        };
+        write_marker(&self.page_path(page_index), &marker)
+    }
+}
+
+fn page_checksum(page_file: &Path) -> Result<Option<u32>> {
+    let (_, header) = k2d::open(page_file, FileKind::Page)?;
+    Ok(header.map(|header| header.checksum))
+}
+
+fn read_marker<T: for<'de> Deserialize<'de>>(path: &Path) -> Option<T> {
+    let data = fs::read(path).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// 先写临时文件再重命名, 避免中断时留下不完整的标记
+fn write_marker<T: Serialize>(path: &Path, marker: &T) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, marker)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+    fs::rename(tmp_path, path)
+}
+
+pub fn remove_if_exists<P: AsRef<Path>>(path: P) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// db migrate 在替换文件之前写入的标记, 每行为 `暂存文件<TAB>目标文件` (相对于数据库目录),
+/// 暂存文件为空表示删除目标文件. 标记存在时数据库中新旧文件可能混在一起, 需要再次运行 db migrate 完成替换
+pub const MIGRATE_MARKER: &str = "migrate.pending";
+
+/// 数据库有未完成的 db migrate 时返回错误
+pub fn check_no_pending_migration(database: &Path) -> Result<()> {
+    if database.join(MIGRATE_MARKER).exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{:?} has an unfinished taxonomy migration ({}); run `db migrate` again to finish it",
+                database, MIGRATE_MARKER
+            ),
+        ));
+    }
+    Ok(())
+}
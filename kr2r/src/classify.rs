@@ -1,44 +1,179 @@
-use crate::compact_hash::Compact;
+use crate::args::{ReadOutputArgs, ScopeArgs};
+use crate::compact_hash::{Compact, Row};
 use crate::readcounts::TaxonCounters;
-use crate::taxonomy::Taxonomy;
+use crate::taxonomy::{RedirectStats, TaxidRedirect, Taxonomy};
+use crate::utils::open_file;
 use crate::HitGroup;
 use seqkmer::SpaceDist;
 use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub fn resolve_tree(
-    hit_counts: &HashMap<u32, u64>,
-    taxonomy: &Taxonomy,
-    required_score: u64,
-) -> u32 {
-    let mut max_taxon = 0u32;
-    let mut max_score = 0;
+/// 分类时的 taxid 范围: 只保留 include 子树内 (未设置时为全部) 且不在 exclude 子树内的 hit.
+/// 范围外的 hit 在 resolve_tree 之前被丢弃, 没有范围内 hit 的 read 为未分类
+#[derive(Debug, Default)]
+pub struct TaxonScope {
+    include: Vec<u32>,
+    exclude: Vec<u32>,
+}
+
+impl TaxonScope {
+    /// 命令行的外部 taxid 先经过 merged.dmp/delnodes.dmp 重定向, 不在 taxonomy 中的 taxid 被忽略并给出警告
+    pub fn new(args: &ScopeArgs, taxonomy: &Taxonomy, redirect: &TaxidRedirect) -> Result<Self> {
+        let mut stats = RedirectStats::default();
+        let mut internal_ids = |taxids: &[u64], option: &str| {
+            let mut ids = Vec::new();
+            for &taxid in taxids {
+                let Some(current) = redirect.resolve_counted(taxid, &mut stats) else {
+                    continue;
+                };
+                match taxonomy.get_internal_id(current) {
+                    0 => eprintln!(
+                        "warning: {} {} is not in the database taxonomy, ignored",
+                        option, taxid
+                    ),
+                    id => ids.push(id),
+                }
+            }
+            ids.sort_unstable();
+            ids.dedup();
+            ids
+        };
+        let include = internal_ids(&args.include_taxids, "--include-taxids");
+        let exclude = internal_ids(&args.exclude_taxids, "--exclude-taxids");
+        stats.warn("--include-taxids/--exclude-taxids");
+
+        if include.is_empty() && !args.include_taxids.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "none of --include-taxids are in the database taxonomy",
+            ));
+        }
+        Ok(Self { include, exclude })
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.include.is_empty() || !self.exclude.is_empty()
+    }
+
+    /// 内部 id 是否在范围内
+    pub fn contains(&self, taxonomy: &Taxonomy, internal_id: u32) -> bool {
+        let within = |ids: &[u32]| {
+            ids.iter()
+                .any(|&ancestor| taxonomy.is_a_ancestor_of_b(ancestor, internal_id))
+        };
+        (self.include.is_empty() || within(&self.include)) && !within(&self.exclude)
+    }
+
+    /// 丢弃范围外的 hit
+    pub fn retain(&self, rows: &mut Vec<Row>, taxonomy: &Taxonomy, value_mask: usize) {
+        if self.is_active() {
+            rows.retain(|row| self.contains(taxonomy, row.value.right(value_mask)));
+        }
+    }
+
+    /// 在报告开头以 '#' 注释行记录生效的过滤条件, 未设置过滤时不写入
+    pub fn write_header<W: Write>(&self, writer: &mut W, taxonomy: &Taxonomy) -> Result<()> {
+        let taxids = |ids: &[u32]| {
+            ids.iter()
+                .map(|&id| taxonomy.nodes[id as usize].external_id.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        if !self.include.is_empty() {
+            writeln!(writer, "# include_taxids: {}", taxids(&self.include))?;
+        }
+        if !self.exclude.is_empty() {
+            writeln!(writer, "# exclude_taxids: {}", taxids(&self.exclude))?;
+        }
+        Ok(())
+    }
+}
 
-    for (&taxon, _) in hit_counts {
-        let mut score = 0;
+/// 按 DFS 先序排列的命中节点. 祖先的先序位置在前, 子树是连续区间,
+/// 所以用一个栈就能找到每个节点最近的命中祖先, 子树计数用前缀和求出
+struct PreorderHits {
+    /// (tin, tout, taxon, 根到该节点路径上的命中数之和)
+    taxa: Vec<(u32, u32, u32, u64)>,
+    /// 按先序排列的命中数的前缀和
+    prefix: Vec<u64>,
+}
 
-        for (&taxon2, &count2) in hit_counts {
-            if taxonomy.is_a_ancestor_of_b(taxon2, taxon) {
-                score += count2;
+impl PreorderHits {
+    fn new(hit_counts: &HashMap<u32, u64>, taxonomy: &Taxonomy) -> Self {
+        let mut taxa: Vec<(u32, u32, u32, u64)> = hit_counts
+            .iter()
+            .filter_map(|(&taxon, &count)| {
+                let (tin, tout) = taxonomy.preorder_interval(taxon)?;
+                Some((tin, tout, taxon, count))
+            })
+            .collect();
+        taxa.sort_unstable();
+
+        let mut prefix = Vec::with_capacity(taxa.len() + 1);
+        prefix.push(0);
+        let mut stack: Vec<usize> = Vec::new();
+        for i in 0..taxa.len() {
+            let (tin, _, _, count) = taxa[i];
+            prefix.push(prefix[i] + count);
+            while stack.last().is_some_and(|&top| taxa[top].1 < tin) {
+                stack.pop();
             }
+            taxa[i].3 = count + stack.last().map_or(0, |&top| taxa[top].3);
+            stack.push(i);
         }
+        Self { taxa, prefix }
+    }
 
-        if score > max_score {
-            max_score = score;
-            max_taxon = taxon;
-        } else if score == max_score {
-            max_taxon = taxonomy.lca(max_taxon, taxon);
+    /// 最高得分. 得分为节点的祖先 (含自身) 的命中数之和
+    fn max_score(&self) -> u64 {
+        self.taxa.iter().map(|t| t.3).max().unwrap_or(0)
+    }
+
+    /// 子树 (含自身) 中的命中数之和
+    fn clade_score(&self, taxonomy: &Taxonomy, taxon: u32) -> u64 {
+        match taxonomy.preorder_interval(taxon) {
+            Some((tin, tout)) => {
+                let start = self.taxa.partition_point(|t| t.0 < tin);
+                let end = self.taxa.partition_point(|t| t.0 <= tout);
+                self.prefix[end] - self.prefix[start]
+            }
+            None => 0,
         }
     }
+}
+
+/// 得分为节点的祖先 (含自身) 的命中数之和, 取得分最高的节点, 并列时取它们的 LCA;
+/// 再沿 parent 向上回溯直到子树命中数达到 required_score.
+/// 命中节点按先序排序后线性扫描, 复杂度为 O(k log k), k 为命中的不同 taxon 数
+pub fn resolve_tree(
+    hit_counts: &HashMap<u32, u64>,
+    taxonomy: &Taxonomy,
+    required_score: u64,
+) -> u32 {
+    let hits = PreorderHits::new(hit_counts, taxonomy);
+
+    let max_score = hits.max_score();
+    let mut max_taxon = hits
+        .taxa
+        .iter()
+        .filter(|t| t.3 == max_score)
+        .fold(0, |max_taxon, t| taxonomy.lca(max_taxon, t.2));
+    if max_score == 0 {
+        // 不在 taxonomy 中的节点得分为 0, 只有所有节点得分都为 0 时才参与
+        max_taxon = hit_counts
+            .keys()
+            .filter(|&&taxon| taxonomy.preorder_interval(taxon).is_none())
+            .fold(max_taxon, |max_taxon, &taxon| {
+                taxonomy.lca(max_taxon, taxon)
+            });
+    }
 
-    max_score = *hit_counts.get(&max_taxon).unwrap_or(&0);
+    let mut max_score = *hit_counts.get(&max_taxon).unwrap_or(&0);
 
     while max_taxon != 0 && max_score < required_score {
-        max_score = hit_counts
-            .iter()
-            .filter(|(&taxon, _)| taxonomy.is_a_ancestor_of_b(max_taxon, taxon))
-            .map(|(_, &count)| count)
-            .sum();
+        max_score = hits.clade_score(taxonomy, max_taxon);
 
         if max_score >= required_score {
             break;
@@ -77,17 +212,171 @@ fn stat_hits<'a>(
     space_dist.reduce_str(" |:| ", |str| str.to_string())
 }
 
+/// 报告时的 taxid 重映射, 例如把菌株和血清型归到报告分组, 或者把有问题的节点归到其父节点.
+/// 每个 taxid 只映射一次, 不会沿着目标 taxid 继续映射
+#[derive(Debug, Default)]
+pub struct TaxidRemap {
+    map: HashMap<u32, u32>,
+}
+
+impl TaxidRemap {
+    /// 读取 "taxid<TAB>target_taxid" 格式的 TSV, 忽略空行和 '#' 开头的行.
+    /// 两列都先经过 merged.dmp/delnodes.dmp 重定向; 目标 taxid 必须在 taxonomy 中,
+    /// 不在 taxonomy 中的源 taxid 被忽略并给出警告
+    pub fn from_file<P: AsRef<Path>>(
+        filename: P,
+        taxonomy: &Taxonomy,
+        redirect: &TaxidRedirect,
+    ) -> Result<Self> {
+        let filename = filename.as_ref();
+        let reader = BufReader::new(open_file(filename)?);
+        let mut stats = RedirectStats::default();
+        let mut missing = Vec::new();
+        let mut map = HashMap::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |msg: String| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{:?} line {}: {}", filename, index + 1, msg),
+                )
+            };
+            let taxids: Vec<u64> = line
+                .split('\t')
+                .map(|field| field.trim().parse::<u64>())
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|_| invalid(format!("invalid taxid in {:?}", line)))?;
+            let &[source, target] = taxids.as_slice() else {
+                return Err(invalid("expected taxid<TAB>target_taxid".to_string()));
+            };
+
+            let target_id = redirect
+                .resolve_counted(target, &mut stats)
+                .map_or(0, |target| taxonomy.get_internal_id(target));
+            if target_id == 0 {
+                return Err(invalid(format!(
+                    "target taxid {} is not in the database taxonomy",
+                    target
+                )));
+            }
+            let Some(source) = redirect.resolve_counted(source, &mut stats) else {
+                continue;
+            };
+            let source_id = taxonomy.get_internal_id(source);
+            if source_id == 0 {
+                missing.push(source);
+                continue;
+            }
+            match map.insert(source_id, target_id) {
+                Some(previous) if previous != target_id => {
+                    return Err(invalid(format!(
+                        "taxid {} is mapped to more than one target",
+                        source
+                    )));
+                }
+                _ => {}
+            }
+        }
+        stats.warn(&format!("{:?}", filename));
+        if !missing.is_empty() {
+            eprintln!(
+                "warning: {} taxids in {:?} are not in the database taxonomy, ignored",
+                missing.len(),
+                filename
+            );
+        }
+        map.retain(|source, target| source != target);
+        Ok(Self { map })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// 内部 id 映射后的内部 id
+    pub fn call(&self, internal_id: u32) -> u32 {
+        *self.map.get(&internal_id).unwrap_or(&internal_id)
+    }
+
+    /// 外部 taxid 映射后的外部 taxid, 0 (未分类) 保持不变
+    pub fn external_call(&self, taxonomy: &Taxonomy, ext_call: u64) -> u64 {
+        if self.is_empty() || ext_call == 0 {
+            return ext_call;
+        }
+        let call = self.call(taxonomy.get_internal_id(ext_call));
+        taxonomy.nodes[call as usize].external_id
+    }
+
+    /// 把计数合并到映射后的节点上, 报告中的 clade 计数由 get_clade_counters 重新累加
+    pub fn apply(&self, counters: &TaxonCounters) -> TaxonCounters {
+        let mut remapped = TaxonCounters::new();
+        for (&taxid, counter) in counters {
+            remapped
+                .entry(self.call(taxid as u32) as u64)
+                .or_default()
+                .merge(counter)
+                .expect("read counters are created with the same HyperLogLog precision");
+        }
+        remapped
+    }
+}
+
+/// 一次分类中对 taxid 的处理规则: 分类前的范围过滤和报告时的重映射
+#[derive(Debug, Default)]
+pub struct TaxidRules {
+    pub scope: TaxonScope,
+    pub remap: TaxidRemap,
+}
+
+impl TaxidRules {
+    /// 命令行中的 taxid 按数据库 taxonomy 目录中的 merged.dmp/delnodes.dmp 重定向
+    pub fn from_args<P: AsRef<Path>>(
+        database: P,
+        scope: &ScopeArgs,
+        read_output: &ReadOutputArgs,
+        taxonomy: &Taxonomy,
+    ) -> Result<Self> {
+        let redirect = TaxidRedirect::from_dir(database.as_ref().join("taxonomy"))?;
+        let remap = match &read_output.taxid_remap {
+            Some(filename) => TaxidRemap::from_file(filename, taxonomy, &redirect)?,
+            None => TaxidRemap::default(),
+        };
+        Ok(Self {
+            scope: TaxonScope::new(scope, taxonomy, &redirect)?,
+            remap,
+        })
+    }
+}
+
+/// process_hitgroup 中影响分类结果的选项
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CallOptions {
+    pub minimum_hit_groups: usize,
+    /// 用 read 的 minimizer 数 (hits.capacity()) 代替 hit group 数, 与早期版本结果一致
+    pub legacy_hit_groups: bool,
+    /// STANDARD_RANKS 中的位置, 设置时分类结果折叠到该 rank 的祖先, read 计数也记在折叠后的节点上
+    pub report_rank: Option<usize>,
+}
+
 pub fn process_hitgroup(
     hits: &HitGroup,
     taxonomy: &Taxonomy,
     classify_counter: &AtomicUsize,
     required_score: u64,
-    minimum_hit_groups: usize,
     value_mask: usize,
+    options: CallOptions,
 ) -> (String, u64, String, TaxonCounters) {
     let mut cur_taxon_counts = TaxonCounters::new();
     let mut counts = HashMap::new();
-    let hit_groups = hits.capacity();
+    let hit_groups = if options.legacy_hit_groups {
+        hits.capacity()
+    } else {
+        hits.hit_groups()
+    };
     let hit_string = stat_hits(
         hits,
         &mut counts,
@@ -97,9 +386,14 @@ pub fn process_hitgroup(
     );
 
     let mut call = resolve_tree(&counts, taxonomy, required_score);
-    if call > 0 && hit_groups < minimum_hit_groups {
+    if call > 0 && hit_groups < options.minimum_hit_groups {
         call = 0;
     };
+    if let Some(rank_index) = options.report_rank {
+        if call > 0 {
+            call = taxonomy.ancestor_at_rank(call, rank_index);
+        }
+    }
 
     let ext_call = taxonomy.nodes[call as usize].external_id;
     let clasify = if call > 0 {
@@ -116,3 +410,177 @@ pub fn process_hitgroup(
 
     (clasify.to_owned(), ext_call, hit_string, cur_taxon_counts)
 }
+
+/// 每条 read 的输出行: C/U, read id, taxid (--use-names 时为名称和 taxid), 序列长度, hit 字符串,
+/// --lineage 时再加上 7 个标准 rank 的 lineage
+pub fn format_read_output(
+    taxonomy: &Taxonomy,
+    options: &ReadOutputArgs,
+    classified: &str,
+    id: &str,
+    ext_call: u64,
+    seq_len: &str,
+    hit_string: &str,
+) -> String {
+    let call = taxonomy.get_internal_id(ext_call);
+    let taxon = if !options.use_names {
+        ext_call.to_string()
+    } else if call == 0 {
+        "unclassified (taxid 0)".to_string()
+    } else {
+        format!("{} (taxid {})", taxonomy.name(call), ext_call)
+    };
+    let mut line = format!(
+        "{}\t{}\t{}\t{}\t{}",
+        classified, id, taxon, seq_len, hit_string
+    );
+    if options.lineage {
+        let names: Vec<&str> = taxonomy
+            .ranked_lineage(call)
+            .iter()
+            .map(|id| id.map_or("", |id| taxonomy.name(id)))
+            .collect();
+        line.push('\t');
+        line.push_str(&names.join(";"));
+    }
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taxonomy::tests::{add_unreachable, synthetic_taxonomy, Lcg};
+    use seqkmer::OptionPair;
+
+    /// 原来的 O(k²) 实现, 两两判断祖先关系. 作为 resolve_tree 的参照
+    fn resolve_tree_quadratic(
+        hit_counts: &HashMap<u32, u64>,
+        taxonomy: &Taxonomy,
+        required_score: u64,
+    ) -> u32 {
+        let mut max_taxon = 0u32;
+        let mut max_score = 0;
+
+        for &taxon in hit_counts.keys() {
+            let mut score = 0;
+
+            for (&taxon2, &count2) in hit_counts {
+                if taxonomy.is_a_ancestor_of_b(taxon2, taxon) {
+                    score += count2;
+                }
+            }
+
+            if score > max_score {
+                max_score = score;
+                max_taxon = taxon;
+            } else if score == max_score {
+                max_taxon = taxonomy.lca(max_taxon, taxon);
+            }
+        }
+
+        max_score = *hit_counts.get(&max_taxon).unwrap_or(&0);
+
+        while max_taxon != 0 && max_score < required_score {
+            max_score = hit_counts
+                .iter()
+                .filter(|(&taxon, _)| taxonomy.is_a_ancestor_of_b(max_taxon, taxon))
+                .map(|(_, &count)| count)
+                .sum();
+
+            if max_score >= required_score {
+                break;
+            }
+            max_taxon = taxonomy.nodes[max_taxon as usize].parent_id as u32;
+        }
+
+        max_taxon
+    }
+
+    /// 随机的命中: 大部分集中在一个节点附近, 其余散落在整棵树和不可达的节点上.
+    /// 计数范围很小时会出现很多并列的最高得分
+    fn hit_group(
+        taxonomy: &Taxonomy,
+        reachable: u32,
+        unreachable: &[u32],
+        rng: &mut Lcg,
+    ) -> HashMap<u32, u64> {
+        let distinct_taxa = 1 + rng.next(12) as usize;
+        let max_count = [1, 2, 50][rng.next(3) as usize];
+        let source = 1 + rng.next(reachable as u64 - 1) as u32;
+        let mut hits = HashMap::new();
+        for _ in 0..distinct_taxa {
+            let taxon = match rng.next(8) {
+                0 => unreachable[rng.next(unreachable.len() as u64) as usize],
+                1 | 2 => 1 + rng.next(reachable as u64 - 1) as u32,
+                _ => {
+                    let mut taxon = source;
+                    for _ in 0..rng.next(3) {
+                        match taxonomy.children(taxon) {
+                            children if !children.is_empty() => {
+                                taxon = children.start + rng.next(children.len() as u64) as u32
+                            }
+                            _ => break,
+                        }
+                    }
+                    taxon
+                }
+            };
+            *hits.entry(taxon).or_insert(0) += 1 + rng.next(max_count);
+        }
+        hits
+    }
+
+    #[test]
+    fn resolve_tree_matches_quadratic_reference() {
+        let mut rng = Lcg(42);
+        let mut taxonomy = synthetic_taxonomy(2000, 4, &mut rng);
+        let reachable = taxonomy.nodes.len() as u32;
+        let unreachable = add_unreachable(&mut taxonomy, 8, &mut rng);
+
+        for _ in 0..20000 {
+            let mut hits = hit_group(&taxonomy, reachable, &unreachable, &mut rng);
+            if rng.next(20) == 0 {
+                // 只有不可达的节点
+                hits.retain(|taxon, _| *taxon >= reachable);
+            }
+            let total: u64 = hits.values().sum();
+            for required_score in [0, 1, total / 2, total, total + 1] {
+                assert_eq!(
+                    resolve_tree(&hits, &taxonomy, required_score),
+                    resolve_tree_quadratic(&hits, &taxonomy, required_score),
+                    "hits {:?}, required_score {}",
+                    hits,
+                    required_score
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_hit_groups_use_capacity() {
+        let mut rng = Lcg(1);
+        let taxonomy = synthetic_taxonomy(20, 3, &mut rng);
+        // 10 个 minimizer 都命中同一个值, 只有一个 hit group
+        let rows = (1..=10)
+            .map(|kmer_id| Row::new((7 << 8) | 2, 0, kmer_id))
+            .collect();
+        let hits = HitGroup::new(rows, OptionPair::Single((0, 10)));
+        assert_eq!(hits.hit_groups(), 1);
+
+        let call = |minimum_hit_groups, legacy_hit_groups| {
+            let options = CallOptions {
+                minimum_hit_groups,
+                legacy_hit_groups,
+                report_rank: None,
+            };
+            let counter = AtomicUsize::new(0);
+            process_hitgroup(&hits, &taxonomy, &counter, 0, 0xFF, options).1
+        };
+        assert_eq!(call(1, false), 2);
+        assert_eq!(call(2, false), 0);
+        assert_eq!(call(2, true), 2);
+        assert_eq!(call(hits.capacity(), true), 2);
+        assert_eq!(call(hits.capacity() + 1, true), 0);
+    }
+}
@@ -1,9 +1,14 @@
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::Serialize;
 use std::cmp::Ordering as CmpOrdering;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::{BufWriter, Read, Result, Write};
-use std::path::Path;
+use std::io::{self, BufReader, BufWriter, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::{Archive, ArchiveEntry};
+use crate::k2d::{self, FileKind, K2dError, K2dWriter};
 
 /// 1101010101 => left: 11010, right: 10101;
 pub trait Compact: Default + PartialEq + Clone + Copy + Eq + Sized + Send + Sync + Debug {
@@ -221,14 +226,23 @@ impl HashConfig {
     pub fn write_to_file<P: AsRef<Path>>(&self, file_path: P) -> Result<()> {
         // 打开文件用于写入
         let file = File::create(file_path)?;
-        let mut writer = BufWriter::new(file);
+        let mut writer = K2dWriter::new(BufWriter::new(file), FileKind::HashConfig)?;
         writer.write_u64::<LittleEndian>(self.version as u64)?;
         writer.write_u64::<LittleEndian>(self.partition as u64)?;
         writer.write_u64::<LittleEndian>(self.hash_capacity as u64)?;
         writer.write_u64::<LittleEndian>(self.capacity as u64)?;
         writer.write_u64::<LittleEndian>(self.size as u64)?;
         writer.write_u64::<LittleEndian>(self.value_bits as u64)?;
-        writer.flush()?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// 写入 Kraken 2 hash.k2d 的头部: capacity, size, key_bits, value_bits
+    pub fn write_kraken2_header<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u64::<LittleEndian>(self.capacity as u64)?;
+        writer.write_u64::<LittleEndian>(self.size as u64)?;
+        writer.write_u64::<LittleEndian>((32 - self.value_bits) as u64)?;
+        writer.write_u64::<LittleEndian>(self.value_bits as u64)?;
         Ok(())
     }
 
@@ -242,13 +256,30 @@ impl HashConfig {
     }
 
     pub fn from_hash_header<P: AsRef<Path>>(filename: P) -> Result<Self> {
-        let mut file = OpenOptions::new().read(true).open(&filename)?;
-        let version = file.read_u64::<LittleEndian>()? as usize;
-        let partition = file.read_u64::<LittleEndian>()? as usize;
-        let hash_capacity = file.read_u64::<LittleEndian>()? as usize;
-        let capacity = file.read_u64::<LittleEndian>()? as usize;
-        let size = file.read_u64::<LittleEndian>()? as usize;
-        let value_bits = file.read_u64::<LittleEndian>()? as usize;
+        let payload = k2d::read_payload(&filename, FileKind::HashConfig)?;
+        let malformed = |reason: &str| K2dError::Malformed {
+            path: filename.as_ref().to_path_buf(),
+            reason: reason.to_string(),
+        };
+        if payload.len() < 48 {
+            return Err(malformed("expected 6 u64 values").into());
+        }
+        let mut reader = &payload[..];
+        let version = reader.read_u64::<LittleEndian>()? as usize;
+        let partition = reader.read_u64::<LittleEndian>()? as usize;
+        let hash_capacity = reader.read_u64::<LittleEndian>()? as usize;
+        let capacity = reader.read_u64::<LittleEndian>()? as usize;
+        let size = reader.read_u64::<LittleEndian>()? as usize;
+        let value_bits = reader.read_u64::<LittleEndian>()? as usize;
+        if hash_capacity == 0 {
+            return Err(malformed("`hash_capacity` can't be zero").into());
+        }
+        if value_bits == 0 || value_bits >= 32 {
+            return Err(malformed("value_bits must be between 1 and 31").into());
+        }
+        if partition != capacity.div_ceil(hash_capacity) {
+            return Err(malformed("partition does not match capacity / hash_capacity").into());
+        }
 
         Ok(Self::new(
             version,
@@ -260,6 +291,41 @@ impl HashConfig {
         ))
     }
 
+    /// 读取数据库目录(或 db pack 生成的归档)的哈希表配置.
+    /// 没有 hash_config.k2d 时读取 Kraken 2 的 hash.k2d 头部, 每 hash_capacity 个单元作为一个虚拟分页.
+    /// db migrate 没有完成的数据库返回错误
+    pub fn from_database<P: AsRef<Path>>(database: P, hash_capacity: usize) -> Result<Self> {
+        let database = database.as_ref();
+        if !Archive::is_archive(database) {
+            crate::checkpoint::check_no_pending_migration(database)?;
+        }
+        let config_file = database.join("hash_config.k2d");
+        if config_file.exists() || Archive::is_archive(database) {
+            return Self::from_hash_header(config_file);
+        }
+
+        let index_file = database.join("hash.k2d");
+        if !index_file.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "neither hash_config.k2d nor hash.k2d found in {:?}",
+                    database
+                ),
+            ));
+        }
+        if hash_capacity == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "`hash_capacity` can't be zero!",
+            ));
+        }
+        let mut config = Self::from_kraken2_header(index_file)?;
+        config.hash_capacity = hash_capacity;
+        config.partition = config.capacity.div_ceil(hash_capacity);
+        Ok(config)
+    }
+
     pub fn get_idx_mask(&self) -> usize {
         let idx_bits = ((self.hash_capacity as f64).log2().ceil() as usize).max(1);
         (1 << idx_bits) - 1
@@ -296,15 +362,227 @@ impl HashConfig {
     }
 }
 
-fn read_page_from_file<P: AsRef<Path>>(filename: P) -> Result<Page> {
-    let mut file = std::fs::File::open(filename)?;
+/// Kraken 2 hash.k2d 头部的字节数
+pub const KRAKEN2_HEADER_SIZE: usize = 32;
 
-    // 读取索引和容量
+/// 读取 hash_N.k2d 分页文件头部的索引和容量
+pub fn read_page_header<R: Read>(reader: &mut R) -> Result<(usize, usize)> {
     let mut buffer = [0u8; 16];
-    file.read_exact(&mut buffer)?;
+    reader.read_exact(&mut buffer)?;
 
     let index = LittleEndian::read_u64(&buffer[0..8]) as usize;
     let capacity = LittleEndian::read_u64(&buffer[8..16]) as usize;
+    Ok((index, capacity))
+}
+
+/// 哈希表分页的数据来源
+#[derive(Debug, Clone)]
+pub enum HashFiles {
+    /// Kun-peng 的分页文件 hash_1.k2d ... hash_N.k2d
+    Pages(Vec<PathBuf>),
+    /// Kraken 2 的 hash.k2d, 每 hash_capacity 个单元作为一个虚拟分页
+    Kraken2 {
+        path: PathBuf,
+        capacity: usize,
+        hash_capacity: usize,
+    },
+    /// db pack 生成的归档中的分页
+    Packed {
+        archive: Archive,
+        pages: Vec<ArchiveEntry>,
+    },
+}
+
+impl HashFiles {
+    /// 根据数据库目录中的文件选择分页来源, config 由 `HashConfig::from_database` 读取
+    pub fn from_database<P: AsRef<Path>>(database: P, config: &HashConfig) -> Result<Self> {
+        let database = database.as_ref();
+        if Archive::is_archive(database) {
+            let archive = Archive::open(database)?;
+            let pages = archive.pages()?;
+            Ok(HashFiles::Packed { archive, pages })
+        } else if database.join("hash_config.k2d").exists() {
+            let hash_files = crate::utils::find_and_sort_files(database, "hash", ".k2d", true)?;
+            Ok(HashFiles::Pages(hash_files))
+        } else {
+            Ok(HashFiles::Kraken2 {
+                path: database.join("hash.k2d"),
+                capacity: config.capacity,
+                hash_capacity: config.hash_capacity,
+            })
+        }
+    }
+
+    /// 分页数量
+    pub fn len(&self) -> usize {
+        match self {
+            HashFiles::Pages(files) => files.len(),
+            HashFiles::Kraken2 {
+                capacity,
+                hash_capacity,
+                ..
+            } => capacity.div_ceil(*hash_capacity),
+            HashFiles::Packed { pages, .. } => pages.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 打开第 page_index (从0开始) 个分页, 读取器位于单元数据开始处
+    pub fn open_page(&self, page_index: usize) -> Result<PageReader> {
+        match self {
+            HashFiles::Pages(files) => {
+                let path = files.get(page_index).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("hash page {} not found", page_index + 1),
+                    )
+                })?;
+                let file = crate::utils::open_file(path)?;
+                let file_len = file.metadata()?.len();
+                open_page_reader(path, Box::new(BufReader::new(file)), file_len)
+            }
+            HashFiles::Kraken2 {
+                path,
+                capacity,
+                hash_capacity,
+            } => {
+                let page_start = page_index * hash_capacity;
+                if page_start >= *capacity {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("hash page {} exceeds {:?}", page_index + 1, path),
+                    ));
+                }
+                let page_size = (*hash_capacity).min(capacity - page_start);
+                let mut file = File::open(path)?;
+                let offset = KRAKEN2_HEADER_SIZE + page_start * std::mem::size_of::<u32>();
+                file.seek(SeekFrom::Start(offset as u64))?;
+                let reader =
+                    BufReader::new(file).take((page_size * std::mem::size_of::<u32>()) as u64);
+                Ok(PageReader {
+                    reader: Box::new(reader),
+                    index: page_index + 1,
+                    capacity: page_size,
+                    path: path.clone(),
+                    checksum: None,
+                })
+            }
+            HashFiles::Packed { archive, pages } => {
+                let entry = pages.get(page_index).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "hash page {} not found in {:?}",
+                            page_index + 1,
+                            archive.path
+                        ),
+                    )
+                })?;
+                let reader = archive.open_entry(entry)?;
+                open_page_reader(&archive.path.join(&entry.name), reader, entry.len)
+            }
+        }
+    }
+}
+
+/// 从分页文件的开头读取可选的 .k2d 头部和分页头部, 并检查文件长度.
+/// file_len 为整个分页文件的长度
+fn open_page_reader(path: &Path, mut reader: Box<dyn Read>, file_len: u64) -> Result<PageReader> {
+    let mut header = None;
+    if file_len >= k2d::K2D_HEADER_SIZE {
+        let mut buffer = [0u8; k2d::K2D_HEADER_SIZE as usize];
+        reader.read_exact(&mut buffer)?;
+        header = k2d::parse_header(path, FileKind::Page, &buffer, file_len)?;
+        if header.is_none() {
+            // 旧格式没有头部, 读出的内容属于分页头部和数据
+            reader = Box::new(io::Cursor::new(buffer).chain(reader));
+        }
+    }
+    let (index, capacity) = read_page_header(&mut reader)?;
+
+    // 旧格式没有头部, 只能用文件长度检查是否被截断
+    let body_len = (16 + capacity * std::mem::size_of::<u32>()) as u64;
+    let expected = match header {
+        Some(header) => {
+            if header.payload_len != body_len {
+                return Err(K2dError::Malformed {
+                    path: path.to_path_buf(),
+                    reason: format!(
+                        "page capacity {} does not match payload length {}",
+                        capacity, header.payload_len
+                    ),
+                }
+                .into());
+            }
+            k2d::K2D_HEADER_SIZE + body_len
+        }
+        None => body_len,
+    };
+    if file_len != expected {
+        return Err(K2dError::Truncated {
+            path: path.to_path_buf(),
+            expected,
+            actual: file_len,
+        }
+        .into());
+    }
+
+    Ok(PageReader {
+        reader,
+        index,
+        capacity,
+        path: path.to_path_buf(),
+        checksum: header.map(|header| header.checksum),
+    })
+}
+
+/// 打开的分页: 读取器位于单元数据开始处
+pub struct PageReader {
+    pub reader: Box<dyn Read>,
+    /// 分页索引(从1开始)
+    pub index: usize,
+    /// 分页单元数
+    pub capacity: usize,
+    pub path: PathBuf,
+    /// 文件头部记录的 crc32, 旧格式和 Kraken 2 hash.k2d 没有
+    pub checksum: Option<u32>,
+}
+
+impl PageReader {
+    /// 校验分页的 crc32, cells 为读取到的全部单元数据
+    pub fn verify(&self, cells: &[u8]) -> Result<()> {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(cells);
+        self.verify_checksum(hasher)
+    }
+
+    /// 校验分页的 crc32, cells_hasher 已经依次输入了全部单元数据
+    pub fn verify_checksum(&self, cells_hasher: crc32fast::Hasher) -> Result<()> {
+        if let Some(expected) = self.checksum {
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&(self.index as u64).to_le_bytes());
+            hasher.update(&(self.capacity as u64).to_le_bytes());
+            hasher.combine(&cells_hasher);
+            let actual = hasher.finalize();
+            if actual != expected {
+                return Err(K2dError::ChecksumMismatch {
+                    path: self.path.clone(),
+                    expected,
+                    actual,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_page(hash_files: &HashFiles, page_index: usize) -> Result<Page> {
+    let mut page_reader = hash_files.open_page(page_index)?;
+    let (index, capacity) = (page_reader.index, page_reader.capacity);
 
     // 读取数据部分
     let mut data = vec![0u32; capacity + 1024 * 1024];
@@ -314,19 +592,19 @@ fn read_page_from_file<P: AsRef<Path>>(filename: P) -> Result<Page> {
             capacity * std::mem::size_of::<u32>(),
         )
     };
-    file.read_exact(data_bytes)?;
+    page_reader.reader.read_exact(data_bytes)?;
+    page_reader.verify(data_bytes)?;
 
     Ok(Page::new(index, capacity, data))
 }
 
-fn read_first_block_from_file<P: AsRef<Path>>(filename: P) -> Result<Page> {
-    let mut file = std::fs::File::open(filename)?;
-
-    // Read the index and capacity
-    let mut buffer = [0u8; 16];
-    file.read_exact(&mut buffer)?;
-    let index = LittleEndian::read_u64(&buffer[0..8]) as usize;
-    let capacity = LittleEndian::read_u64(&buffer[8..16]) as usize;
+fn read_first_block(hash_files: &HashFiles, page_index: usize) -> Result<Page> {
+    let PageReader {
+        mut reader,
+        index,
+        capacity,
+        ..
+    } = hash_files.open_page(page_index)?;
 
     let mut first_zero_end = capacity;
     let chunk_size = 1024 * 4;
@@ -338,7 +616,7 @@ fn read_first_block_from_file<P: AsRef<Path>>(filename: P) -> Result<Page> {
         let end = usize::min(read_pos + chunk_size, capacity);
         let bytes_to_read = (end - read_pos) * std::mem::size_of::<u32>();
         let mut chunk = vec![0u8; bytes_to_read];
-        file.read_exact(&mut chunk)?;
+        reader.read_exact(&mut chunk)?;
         let chunk_u32 =
             unsafe { std::slice::from_raw_parts(chunk.as_ptr() as *const u32, end - read_pos) };
         data[read_pos..end].copy_from_slice(chunk_u32);
@@ -426,6 +704,91 @@ impl Page {
     }
 }
 
+/// 哈希表(或单个分页)的统计信息
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HashStats {
+    /// 分页索引(从1开始), 整表统计时为 0
+    pub page: usize,
+    pub capacity: usize,
+    pub size: usize,
+    pub occupancy: f64,
+    /// 探测链越过分页末尾, 延续到下一分页(或回绕到页首)的单元数
+    pub spilled: usize,
+    /// 查找不存在的 key 时 find_index 比较的非空单元数: 长度 => 起点个数
+    pub probe_lengths: BTreeMap<usize, u64>,
+    pub max_probe_length: usize,
+    pub mean_probe_length: f64,
+    /// 压缩 key 的位数 (32 - value_bits)
+    pub key_bits: usize,
+    /// 查找不存在的 key 时误判的估计概率: mean_probe_length / 2^key_bits
+    pub false_positive_rate: f64,
+}
+
+impl HashStats {
+    /// cells 为分页数据, next_block 为探测链的延续(下一分页或本页的页首区块)
+    pub fn from_cells(page: usize, cells: &[u32], next_block: &[u32], key_bits: usize) -> Self {
+        let spilled = if cells.last().is_some_and(|&x| x != 0) {
+            next_block.iter().take_while(|&&x| x != 0).count()
+        } else {
+            0
+        };
+
+        let mut stats = HashStats {
+            page,
+            capacity: cells.len(),
+            spilled,
+            key_bits,
+            ..Default::default()
+        };
+
+        // 从后往前, run 为当前位置开始到第一个空单元之间的非空单元数
+        let mut run = spilled;
+        for &cell in cells.iter().rev() {
+            if cell != 0 {
+                run += 1;
+                stats.size += 1;
+            } else {
+                run = 0;
+            }
+            *stats.probe_lengths.entry(run).or_insert(0) += 1;
+        }
+        stats.finish();
+        stats
+    }
+
+    /// 合并其他分页的统计
+    pub fn merge(&mut self, other: &Self) {
+        self.capacity += other.capacity;
+        self.size += other.size;
+        self.spilled += other.spilled;
+        for (&len, &count) in &other.probe_lengths {
+            *self.probe_lengths.entry(len).or_insert(0) += count;
+        }
+        self.finish();
+    }
+
+    fn finish(&mut self) {
+        let starts: u64 = self.probe_lengths.values().sum();
+        let probes: u64 = self
+            .probe_lengths
+            .iter()
+            .map(|(&len, &count)| len as u64 * count)
+            .sum();
+        self.occupancy = if self.capacity > 0 {
+            self.size as f64 / self.capacity as f64
+        } else {
+            0.0
+        };
+        self.max_probe_length = self.probe_lengths.keys().last().copied().unwrap_or(0);
+        self.mean_probe_length = if starts > 0 {
+            probes as f64 / starts as f64
+        } else {
+            0.0
+        };
+        self.false_positive_rate = self.mean_probe_length / (1u64 << self.key_bits) as f64;
+    }
+}
+
 #[allow(unused)]
 pub struct CHTable {
     pub config: HashConfig,
@@ -433,30 +796,28 @@ pub struct CHTable {
 }
 
 impl CHTable {
-    pub fn from_hash_files<P: AsRef<Path> + Debug>(
-        config: HashConfig,
-        hash_sorted_files: &Vec<P>,
-    ) -> Result<CHTable> {
-        let end = hash_sorted_files.len();
-        Self::from_range(config, hash_sorted_files, 0, end)
+    pub fn from_hash_files(config: HashConfig, hash_files: &HashFiles) -> Result<CHTable> {
+        let end = hash_files.len();
+        Self::from_range(config, hash_files, 0, end)
     }
 
-    pub fn from_range<P: AsRef<Path> + Debug>(
+    pub fn from_range(
         config: HashConfig,
-        hash_sorted_files: &Vec<P>,
+        hash_files: &HashFiles,
         start: usize,
         end: usize,
     ) -> Result<CHTable> {
         let mut pages = vec![Page::default(); start];
-        let parition = hash_sorted_files.len();
+        let parition = hash_files.len();
         for i in start..end {
-            let mut hash_file = &hash_sorted_files[i];
-            let mut page = read_page_from_file(&hash_file)?;
+            let mut page = read_page(hash_files, i)?;
             let next_page = if page.data.last().map_or(false, |&x| x != 0) {
-                if config.version < 1 {
-                    hash_file = &hash_sorted_files[(i + 1) % parition]
-                }
-                read_first_block_from_file(&hash_file)?
+                let next_index = if config.version < 1 {
+                    (i + 1) % parition
+                } else {
+                    i
+                };
+                read_first_block(hash_files, next_index)?
             } else {
                 Page::default()
             };
@@ -468,6 +829,32 @@ impl CHTable {
         Ok(chtm)
     }
 
+    /// 统计第 page_index (从0开始) 个分页, 探测链的延续方式与 from_range 相同
+    pub fn page_stats(
+        config: &HashConfig,
+        hash_files: &HashFiles,
+        page_index: usize,
+    ) -> Result<HashStats> {
+        let page = read_page(hash_files, page_index)?;
+        let cells = &page.data[..page.size];
+        let next_block = if cells.last().is_some_and(|&x| x != 0) {
+            let next_index = if config.version < 1 {
+                (page_index + 1) % hash_files.len()
+            } else {
+                page_index
+            };
+            read_first_block(hash_files, next_index)?.data
+        } else {
+            vec![]
+        };
+        Ok(HashStats::from_cells(
+            page.index,
+            cells,
+            &next_block,
+            32 - config.value_bits,
+        ))
+    }
+
     pub fn get_from_page(&self, indx: usize, compacted: u32, page_index: usize) -> u32 {
         if let Some(page) = self.pages.get(page_index) {
             page.find_index(
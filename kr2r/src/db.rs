@@ -1,15 +1,20 @@
 // 使用时需要引用模块路径
+use crate::checkpoint::Checkpoint;
 use crate::compact_hash::{Compact, HashConfig, Slot};
+use crate::k2d::{FileKind, K2dWriter};
 // use crate::mmscanner::MinimizerScanner;
-use crate::taxonomy::{NCBITaxonomy, Taxonomy};
+use crate::taxonomy::{
+    parse_custom_nodes_file, CustomNode, NCBITaxonomy, RedirectStats, TaxidRedirect, Taxonomy,
+    CUSTOM_NODES_FILENAME,
+};
 use seqkmer::{read_parallel, BufferFastaReader, Meros};
 
 use crate::utils::open_file;
 use byteorder::{LittleEndian, WriteBytesExt};
 use rayon::prelude::*;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Result as IOResult, Write};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Result as IOResult, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 // 定义每批次处理的 Cell 数量
@@ -62,7 +67,7 @@ fn write_hashtable_to_file(
 ) -> IOResult<usize> {
     // 打开文件用于写入
     let file = File::create(file_path)?;
-    let mut writer = BufWriter::new(file);
+    let mut writer = K2dWriter::new(BufWriter::new(file), FileKind::Page)?;
     let mut count = 0;
     writer.write_u64::<LittleEndian>(page_index)?;
     writer.write_u64::<LittleEndian>(capacity)?;
@@ -76,7 +81,7 @@ fn write_hashtable_to_file(
         writer.write_u32::<LittleEndian>(value)?;
     }
 
-    writer.flush()?; // 确保所有内容都被写入文件
+    writer.finish()?; // 回填头部, 确保所有内容都被写入文件
     Ok(count)
 }
 
@@ -130,22 +135,222 @@ pub fn process_k2file(
     Ok(size_count)
 }
 
-/// 生成taxonomy树文件
+/// 由 chunk_N.k2 生成 hash_N.k2d (page_index 从1开始), 返回分页中的元素数量.
+/// 已经完成的分页直接返回记录的数量; 生成后删除 chunk 文件.
+/// 没有 chunk_db 指纹时不跳过也不记录分页
+pub fn build_page(
+    database: &PathBuf,
+    hash_config: HashConfig,
+    taxonomy: &Taxonomy,
+    checkpoint: &Checkpoint,
+    fingerprint: Option<&str>,
+    page_index: usize,
+) -> IOResult<usize> {
+    if let Some(marker) = fingerprint.and_then(|fp| checkpoint.page(page_index, fp)) {
+        println!(
+            "hash page {}/{} already built, skipping",
+            page_index, hash_config.partition
+        );
+        return Ok(marker.size);
+    }
+
+    let chunk_file = database.join(format!("chunk_{}.k2", page_index));
+    if !chunk_file.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "chunk file {:?} for hash page {} is missing, chunk_db has to be run again",
+                chunk_file, page_index
+            ),
+        ));
+    }
+    let count = process_k2file(
+        hash_config,
+        database,
+        &chunk_file,
+        taxonomy,
+        hash_config.hash_capacity,
+        page_index,
+    )?;
+    if let Some(fingerprint) = fingerprint {
+        checkpoint.finish_page(page_index, fingerprint, count)?;
+    }
+    fs::remove_file(&chunk_file)?;
+    Ok(count)
+}
+
+/// 汇总各分页的元素数量写入 hash_config.k2d. 所有分页都必须已经完成
+pub fn finalize(database: &Path) -> IOResult<HashConfig> {
+    let hash_filename = database.join("hash_config.k2d");
+    let mut hash_config = HashConfig::from_hash_header(&hash_filename)?;
+    let checkpoint = Checkpoint::new(database)?;
+    let fingerprint = checkpoint.require_chunk_fingerprint()?;
+
+    let mut size = 0;
+    let mut missing = Vec::new();
+    for page_index in 1..=hash_config.partition {
+        match checkpoint.page(page_index, &fingerprint) {
+            Some(marker) => size += marker.size,
+            None => missing.push(page_index.to_string()),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "hash pages not built yet: {} (of {})",
+                missing.join(","),
+                hash_config.partition
+            ),
+        ));
+    }
+
+    hash_config.size = size;
+    hash_config.write_to_file(&hash_filename)?;
+    Ok(hash_config)
+}
+
+/// 读取 taxonomy 目录中的 nodes.dmp 和 names.dmp, 并合并自定义节点 (如果有)
+pub fn load_ncbi_taxonomy(ncbi_taxonomy_directory: &Path) -> IOResult<NCBITaxonomy> {
+    let nodes_filename = ncbi_taxonomy_directory.join("nodes.dmp");
+    let names_filename = ncbi_taxonomy_directory.join("names.dmp");
+    let mut ncbi = NCBITaxonomy::from_ncbi(nodes_filename, names_filename)?;
+    let custom_nodes_filename = ncbi_taxonomy_directory.join(CUSTOM_NODES_FILENAME);
+    if custom_nodes_filename.exists() {
+        ncbi.add_custom_nodes(&read_custom_nodes(
+            &custom_nodes_filename,
+            ncbi_taxonomy_directory,
+        )?)?;
+    }
+    Ok(ncbi)
+}
+
+/// 读取自定义节点. 自定义 taxid 不能是 NCBI 合并或删除过的 taxid, parent 按 merged.dmp 重定向
+fn read_custom_nodes(filename: &Path, ncbi_taxonomy_directory: &Path) -> IOResult<Vec<CustomNode>> {
+    let redirect = TaxidRedirect::from_dir(ncbi_taxonomy_directory)?;
+    let mut nodes = parse_custom_nodes_file(filename)?;
+    for node in nodes.iter_mut() {
+        if redirect.is_retired(node.taxid) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "custom taxid {} ({}) collides with a merged or deleted NCBI taxid",
+                    node.taxid, node.name
+                ),
+            ));
+        }
+        node.parent_id = redirect.resolve(node.parent_id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "parent taxid {} of custom taxid {} has been deleted from the NCBI taxonomy",
+                    node.parent_id, node.taxid
+                ),
+            )
+        })?;
+    }
+    Ok(nodes)
+}
+
+/// 把自定义节点文件合并到 taxonomy 目录中已经安装的自定义节点上, 只检查不写入.
+/// 已安装的 taxid 再次定义时 parent, rank 和 name 必须相同; 新节点的 parent 可以是 NCBI taxid 或任一自定义 taxid.
+/// 返回合并后的全部自定义节点和新加入的节点数量
+pub fn merge_custom_nodes(
+    filename: &Path,
+    ncbi_taxonomy_directory: &Path,
+) -> IOResult<(Vec<CustomNode>, usize)> {
+    let installed_filename = ncbi_taxonomy_directory.join(CUSTOM_NODES_FILENAME);
+    let mut nodes = if installed_filename.exists() {
+        read_custom_nodes(&installed_filename, ncbi_taxonomy_directory)?
+    } else {
+        Vec::new()
+    };
+    let installed: HashMap<u64, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.taxid, i))
+        .collect();
+    let mut added = 0;
+    for node in read_custom_nodes(filename, ncbi_taxonomy_directory)? {
+        match installed.get(&node.taxid).map(|&i| &nodes[i]) {
+            Some(old)
+                if old.parent_id == node.parent_id
+                    && old.rank == node.rank
+                    && old.name == node.name => {}
+            Some(old) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "custom taxid {} is already installed as {}\t{}\t{}, not {}\t{}\t{}",
+                        node.taxid,
+                        old.parent_id,
+                        old.rank,
+                        old.name,
+                        node.parent_id,
+                        node.rank,
+                        node.name
+                    ),
+                ))
+            }
+            None => {
+                nodes.push(node);
+                added += 1;
+            }
+        }
+    }
+    let mut ncbi = NCBITaxonomy::from_ncbi(
+        ncbi_taxonomy_directory.join("nodes.dmp"),
+        ncbi_taxonomy_directory.join("names.dmp"),
+    )?;
+    ncbi.add_custom_nodes(&nodes)?;
+    Ok((nodes, added))
+}
+
+/// 写入 taxonomy 目录中的自定义节点文件
+pub fn write_custom_nodes(ncbi_taxonomy_directory: &Path, nodes: &[CustomNode]) -> IOResult<()> {
+    let content: String = nodes
+        .iter()
+        .map(|node| {
+            format!(
+                "{}\t{}\t{}\t{}\n",
+                node.taxid, node.parent_id, node.rank, node.name
+            )
+        })
+        .collect();
+    let filename = ncbi_taxonomy_directory.join(CUSTOM_NODES_FILENAME);
+    let temp = filename.with_extension("tsv.tmp");
+    fs::write(&temp, content)?;
+    fs::rename(&temp, &filename)
+}
+
+/// 合并自定义节点并写入 taxonomy 目录, 返回新加入的节点数量. 没有新节点时不改写文件
+pub fn install_custom_nodes(filename: &Path, ncbi_taxonomy_directory: &Path) -> IOResult<usize> {
+    let (nodes, added) = merge_custom_nodes(filename, ncbi_taxonomy_directory)?;
+    if added > 0 {
+        write_custom_nodes(ncbi_taxonomy_directory, &nodes)?;
+    }
+    Ok(added)
+}
+
+/// 生成taxonomy树文件. id_map 中合并过的 taxid 按 merged.dmp 重定向, 已删除的忽略
 pub fn generate_taxonomy(
     ncbi_taxonomy_directory: &PathBuf,
     taxonomy_filename: &PathBuf,
     id_map: &HashMap<String, u64>,
 ) -> IOResult<Taxonomy> {
-    let nodes_filename = ncbi_taxonomy_directory.join("nodes.dmp");
-    let names_filename = ncbi_taxonomy_directory.join("names.dmp");
-    let mut ncbi = NCBITaxonomy::from_ncbi(nodes_filename, names_filename)?;
+    let mut ncbi = load_ncbi_taxonomy(ncbi_taxonomy_directory)?;
 
+    let redirect = TaxidRedirect::from_dir(ncbi_taxonomy_directory)?;
+    let mut stats = RedirectStats::default();
     for (_, id) in id_map.into_iter() {
-        ncbi.mark_node(*id);
+        if let Some(id) = redirect.resolve_counted(*id, &mut stats) {
+            ncbi.mark_node(id);
+        }
     }
+    stats.warn("seqid2taxid.map");
     let mut taxo = ncbi.convert_to_kraken_taxonomy();
     taxo.generate_external_to_internal_id_map();
-    taxo.build_path_cache();
+    taxo.build_lca_index();
     taxo.write_to_disk(&taxonomy_filename)?;
 
     Ok(taxo)
@@ -0,0 +1,326 @@
+//! Kun-peng 数据库 .k2d 文件的通用头部
+//!
+//! 头部固定 32 字节, 之后是原来(旧格式)的文件内容:
+//! magic(8) | kind(u32) | format version(u32) | payload length(u64) | crc32(u32) | reserved(u32)
+//!
+//! 没有 magic 的文件按旧格式读取.
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const K2D_MAGIC: &[u8; 8] = b"KUNPENGD";
+/// 当前的文件格式版本
+pub const K2D_FORMAT_VERSION: u32 = 1;
+pub const K2D_HEADER_SIZE: u64 = 32;
+
+/// .k2d 文件类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    HashConfig = 1,
+    IndexOptions = 2,
+    Taxonomy = 3,
+    Page = 4,
+}
+
+impl FileKind {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(FileKind::HashConfig),
+            2 => Some(FileKind::IndexOptions),
+            3 => Some(FileKind::Taxonomy),
+            4 => Some(FileKind::Page),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FileKind::HashConfig => "hash config",
+            FileKind::IndexOptions => "index options",
+            FileKind::Taxonomy => "taxonomy",
+            FileKind::Page => "hash page",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// 数据库文件校验错误
+#[derive(Debug)]
+pub enum K2dError {
+    /// 文件长度与头部记录的不一致
+    Truncated {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    /// 文件类型不符, 例如把 taxo.k2d 当作 opts.k2d 读取
+    KindMismatch {
+        path: PathBuf,
+        expected: FileKind,
+        found: u32,
+    },
+    UnsupportedVersion {
+        path: PathBuf,
+        version: u32,
+    },
+    ChecksumMismatch {
+        path: PathBuf,
+        expected: u32,
+        actual: u32,
+    },
+    UnsupportedRevcomVersion {
+        path: PathBuf,
+        version: i32,
+    },
+    Malformed {
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+impl fmt::Display for K2dError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            K2dError::Truncated {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{:?} is truncated: expected {} bytes, found {}",
+                path, expected, actual
+            ),
+            K2dError::KindMismatch {
+                path,
+                expected,
+                found,
+            } => match FileKind::from_u32(*found) {
+                Some(kind) => write!(f, "{:?} is a {} file, expected {}", path, kind, expected),
+                None => write!(f, "{:?} has unknown file kind {}", path, found),
+            },
+            K2dError::UnsupportedVersion { path, version } => write!(
+                f,
+                "{:?} has format version {}, this build supports up to {}",
+                path, version, K2D_FORMAT_VERSION
+            ),
+            K2dError::ChecksumMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{:?} checksum mismatch: expected {:08x}, found {:08x}",
+                path, expected, actual
+            ),
+            K2dError::UnsupportedRevcomVersion { path, version } => {
+                write!(f, "{:?} has unsupported revcom version {}", path, version)
+            }
+            K2dError::Malformed { path, reason } => {
+                write!(f, "{:?} is malformed: {}", path, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for K2dError {}
+
+impl From<K2dError> for io::Error {
+    fn from(err: K2dError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// .k2d 文件头部
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct K2dHeader {
+    pub kind: FileKind,
+    pub version: u32,
+    pub payload_len: u64,
+    pub checksum: u32,
+}
+
+impl K2dHeader {
+    fn to_bytes(self) -> [u8; K2D_HEADER_SIZE as usize] {
+        let mut buffer = [0u8; K2D_HEADER_SIZE as usize];
+        buffer[0..8].copy_from_slice(K2D_MAGIC);
+        buffer[8..12].copy_from_slice(&(self.kind as u32).to_le_bytes());
+        buffer[12..16].copy_from_slice(&self.version.to_le_bytes());
+        buffer[16..24].copy_from_slice(&self.payload_len.to_le_bytes());
+        buffer[24..28].copy_from_slice(&self.checksum.to_le_bytes());
+        buffer
+    }
+}
+
+/// 计算 crc32 校验和
+pub fn checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+/// 打开 .k2d 文件并校验头部和文件长度, 返回的文件位于正文开始处.
+/// 旧格式(没有头部)的文件返回 None, 文件位于开头.
+pub fn open<P: AsRef<Path>>(path: P, kind: FileKind) -> io::Result<(File, Option<K2dHeader>)> {
+    let path = path.as_ref();
+    let mut file = crate::utils::open_file(path)?;
+    let file_len = file.metadata()?.len();
+
+    let mut buffer = [0u8; K2D_HEADER_SIZE as usize];
+    if file_len < K2D_HEADER_SIZE {
+        return Ok((file, None));
+    }
+    file.read_exact(&mut buffer)?;
+    let header = parse_header(path, kind, &buffer, file_len)?;
+    if header.is_none() {
+        file.seek(SeekFrom::Start(0))?;
+    }
+    Ok((file, header))
+}
+
+/// 解析文件开头的 32 字节, 没有 magic 时返回 None. file_len 为整个文件的长度
+pub fn parse_header(
+    path: &Path,
+    kind: FileKind,
+    buffer: &[u8; K2D_HEADER_SIZE as usize],
+    file_len: u64,
+) -> io::Result<Option<K2dHeader>> {
+    if &buffer[0..8] != K2D_MAGIC {
+        return Ok(None);
+    }
+
+    let found = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
+    if found != kind as u32 {
+        return Err(K2dError::KindMismatch {
+            path: path.to_path_buf(),
+            expected: kind,
+            found,
+        }
+        .into());
+    }
+    let version = u32::from_le_bytes(buffer[12..16].try_into().unwrap());
+    if version > K2D_FORMAT_VERSION {
+        return Err(K2dError::UnsupportedVersion {
+            path: path.to_path_buf(),
+            version,
+        }
+        .into());
+    }
+    let payload_len = u64::from_le_bytes(buffer[16..24].try_into().unwrap());
+    let checksum = u32::from_le_bytes(buffer[24..28].try_into().unwrap());
+    let expected = K2D_HEADER_SIZE + payload_len;
+    if file_len != expected {
+        return Err(K2dError::Truncated {
+            path: path.to_path_buf(),
+            expected,
+            actual: file_len,
+        }
+        .into());
+    }
+
+    Ok(Some(K2dHeader {
+        kind,
+        version,
+        payload_len,
+        checksum,
+    }))
+}
+
+/// 读取整个正文并校验 crc32, 适用于 hash_config.k2d opts.k2d taxo.k2d 这类小文件.
+/// path 也可以是归档内的文件, 例如 `db.kpdb/taxo.k2d`
+pub fn read_payload<P: AsRef<Path>>(path: P, kind: FileKind) -> io::Result<Vec<u8>> {
+    let path = path.as_ref();
+    if let Some((archive_path, name)) = crate::archive::split_path(path) {
+        let data = crate::archive::Archive::open(archive_path)?.read_entry(&name)?;
+        let header_size = K2D_HEADER_SIZE as usize;
+        if data.len() < header_size {
+            return Ok(data);
+        }
+        let mut buffer = [0u8; K2D_HEADER_SIZE as usize];
+        buffer.copy_from_slice(&data[..header_size]);
+        return match parse_header(path, kind, &buffer, data.len() as u64)? {
+            Some(header) => {
+                let payload = data[header_size..].to_vec();
+                verify_checksum(path, header.checksum, &payload)?;
+                Ok(payload)
+            }
+            None => Ok(data),
+        };
+    }
+
+    let (mut file, header) = open(path, kind)?;
+    let mut payload = Vec::new();
+    file.read_to_end(&mut payload)?;
+    if let Some(header) = header {
+        verify_checksum(path, header.checksum, &payload)?;
+    }
+    Ok(payload)
+}
+
+pub fn verify_checksum<P: AsRef<Path>>(path: P, expected: u32, payload: &[u8]) -> io::Result<()> {
+    let actual = checksum(payload);
+    if actual != expected {
+        return Err(K2dError::ChecksumMismatch {
+            path: path.as_ref().to_path_buf(),
+            expected,
+            actual,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// 写入带头部的 .k2d 文件: 先写占位头部, finish 时回填正文长度和校验和
+pub struct K2dWriter<W: Write + Seek> {
+    inner: W,
+    kind: FileKind,
+    hasher: crc32fast::Hasher,
+    payload_len: u64,
+}
+
+impl<W: Write + Seek> K2dWriter<W> {
+    pub fn new(mut inner: W, kind: FileKind) -> io::Result<Self> {
+        inner.write_all(&[0u8; K2D_HEADER_SIZE as usize])?;
+        Ok(Self {
+            inner,
+            kind,
+            hasher: crc32fast::Hasher::new(),
+            payload_len: 0,
+        })
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let header = K2dHeader {
+            kind: self.kind,
+            version: K2D_FORMAT_VERSION,
+            payload_len: self.payload_len,
+            checksum: self.hasher.finalize(),
+        };
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.write_all(&header.to_bytes())?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek> Write for K2dWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.payload_len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// 以 payload 创建带头部的 .k2d 文件
+pub fn write_file<P: AsRef<Path>>(path: P, kind: FileKind, payload: &[u8]) -> io::Result<()> {
+    let mut writer = K2dWriter::new(io::BufWriter::new(File::create(path)?), kind)?;
+    writer.write_all(payload)?;
+    writer.finish()?;
+    Ok(())
+}
@@ -1,12 +1,10 @@
 use crate::compact_hash::Row;
-use crate::utils::open_file;
+use crate::k2d::{self, FileKind, K2dError};
 // use crate::{Meros, CURRENT_REVCOM_VERSION};
 use seqkmer::Meros;
 use seqkmer::OptionPair;
 use seqkmer::CURRENT_REVCOM_VERSION;
-use std::fs::File;
-use std::io::{Read, Result as IoResult, Write};
-use std::mem;
+use std::io::Result as IoResult;
 use std::path::Path;
 
 pub fn parse_binary(src: &str) -> Result<u64, std::num::ParseIntError> {
@@ -47,6 +45,29 @@ impl HitGroup {
         self.range.reduce(0, |acc, range| acc + range.1 - range.0)
     }
 
+    /// Kraken 2 的 hit group 数: 连续且相同的命中 minimizer 算作一组.
+    /// rows 需按 kmer_id 排序; 未命中的 minimizer 会断开一组, 双端 read 的两条 mate 分别计数
+    pub fn hit_groups(&self) -> usize {
+        let mate_end = match self.range {
+            OptionPair::Pair((_, end), _) => Some(end as u32),
+            OptionPair::Single(_) => None,
+        };
+        let mut groups = 0;
+        let mut last: Option<&Row> = None;
+        for row in &self.rows {
+            let same_group = last.is_some_and(|prev| {
+                prev.value == row.value
+                    && prev.kmer_id + 1 == row.kmer_id
+                    && mate_end.is_none_or(|end| prev.kmer_id > end || row.kmer_id <= end)
+            });
+            if !same_group {
+                groups += 1;
+            }
+            last = Some(row);
+        }
+        groups
+    }
+
     pub fn required_score(&self, confidence_threshold: f64) -> u64 {
         (confidence_threshold * self.capacity() as f64).ceil() as u64
     }
@@ -89,38 +110,66 @@ impl IndexOptions {
         }
     }
 
-    pub fn read_index_options<P: AsRef<Path>>(file_path: P) -> IoResult<Self> {
-        let mut file = open_file(file_path)?;
-        let mut buffer = vec![0; std::mem::size_of::<Self>()];
-        file.read_exact(&mut buffer)?;
+    /// 与 Kraken 2 的 opts.k2d (#[repr(C)] 结构体) 字节布局一致, 共 64 字节
+    pub const SERIALIZED_SIZE: usize = 64;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; Self::SERIALIZED_SIZE];
+        bytes[0..8].copy_from_slice(&(self.k as u64).to_le_bytes());
+        bytes[8..16].copy_from_slice(&(self.l as u64).to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.spaced_seed_mask.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.toggle_mask.to_le_bytes());
+        bytes[32] = self.dna_db as u8;
+        bytes[40..48].copy_from_slice(&self.minimum_acceptable_hash_value.to_le_bytes());
+        bytes[48..52].copy_from_slice(&self.revcom_version.to_le_bytes());
+        bytes[52..56].copy_from_slice(&self.db_version.to_le_bytes());
+        bytes[56..60].copy_from_slice(&self.db_type.to_le_bytes());
+        bytes
+    }
 
-        let idx_opts = unsafe {
-            // 确保这种转换是安全的，这依赖于数据的确切布局和来源
-            std::ptr::read(buffer.as_ptr() as *const Self)
-        };
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SERIALIZED_SIZE {
+            return None;
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let i32_at = |i: usize| i32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Some(Self {
+            k: u64_at(0) as usize,
+            l: u64_at(8) as usize,
+            spaced_seed_mask: u64_at(16),
+            toggle_mask: u64_at(24),
+            dna_db: bytes[32] != 0,
+            minimum_acceptable_hash_value: u64_at(40),
+            revcom_version: i32_at(48),
+            db_version: i32_at(52),
+            db_type: i32_at(56),
+        })
+    }
+
+    pub fn read_index_options<P: AsRef<Path>>(file_path: P) -> IoResult<Self> {
+        let path = file_path.as_ref().to_path_buf();
+        let payload = k2d::read_payload(&path, FileKind::IndexOptions)?;
+        let idx_opts = Self::from_bytes(&payload).ok_or_else(|| K2dError::Malformed {
+            path: path.clone(),
+            reason: format!(
+                "expected {} bytes of index options, found {}",
+                Self::SERIALIZED_SIZE,
+                payload.len()
+            ),
+        })?;
         if idx_opts.revcom_version != CURRENT_REVCOM_VERSION as i32 {
-            // 如果版本为0，直接触发 panic
-            panic!("Unsupported version (revcom_version == 0)");
+            return Err(K2dError::UnsupportedRevcomVersion {
+                path,
+                version: idx_opts.revcom_version,
+            }
+            .into());
         }
 
         Ok(idx_opts)
     }
 
     pub fn write_to_file<P: AsRef<Path>>(&self, file_path: P) -> IoResult<()> {
-        let mut file = File::create(file_path)?;
-
-        // 将结构体转换为字节切片。这是不安全的操作，因为我们正在
-        // 强制将内存内容解释为字节，这要求IndexOptions是#[repr(C)]，
-        // 且所有字段都可以安全地以其原始内存表示形式进行复制。
-        let bytes: &[u8] = unsafe {
-            std::slice::from_raw_parts(
-                (self as *const IndexOptions) as *const u8,
-                mem::size_of::<IndexOptions>(),
-            )
-        };
-
-        file.write_all(bytes)?;
-        Ok(())
+        k2d::write_file(file_path, FileKind::IndexOptions, &self.to_bytes())
     }
 
     pub fn from_meros(meros: Meros) -> Self {
@@ -144,3 +193,55 @@ impl IndexOptions {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (kmer_id, value) 生成按 kmer_id 排序的命中
+    fn rows(hits: &[(u32, u32)]) -> Vec<Row> {
+        hits.iter()
+            .map(|&(kmer_id, value)| Row::new(value, 0, kmer_id))
+            .collect()
+    }
+
+    #[test]
+    fn hit_groups_count_runs_of_identical_hits() {
+        let hits = HitGroup::new(
+            rows(&[(1, 5), (2, 5), (3, 5), (4, 7), (5, 7), (6, 5)]),
+            OptionPair::Single((0, 10)),
+        );
+        assert_eq!(hits.hit_groups(), 3);
+        assert_eq!(hits.capacity(), 10);
+        assert_eq!(
+            HitGroup::new(vec![], OptionPair::Single((0, 10))).hit_groups(),
+            0
+        );
+    }
+
+    #[test]
+    fn hit_groups_split_at_a_miss() {
+        // kmer 3 未命中, 前后相同的值也是两组
+        let hits = HitGroup::new(
+            rows(&[(1, 5), (2, 5), (4, 5), (5, 5)]),
+            OptionPair::Single((0, 10)),
+        );
+        assert_eq!(hits.hit_groups(), 2);
+    }
+
+    #[test]
+    fn hit_groups_split_at_the_mate_boundary() {
+        // 第一条 mate 的 kmer 为 1..=3, 第二条为 4..=6
+        let mate_rows = [(2, 5), (3, 5), (4, 5), (5, 5)];
+        let paired = HitGroup::new(rows(&mate_rows), OptionPair::Pair((0, 3), (3, 6)));
+        assert_eq!(paired.hit_groups(), 2);
+        let single = HitGroup::new(rows(&mate_rows), OptionPair::Single((0, 6)));
+        assert_eq!(single.hit_groups(), 1);
+        // 边界两侧的组内部仍然连续计数
+        let paired = HitGroup::new(
+            rows(&[(1, 5), (2, 5), (3, 7), (4, 7), (6, 7)]),
+            OptionPair::Pair((0, 3), (3, 6)),
+        );
+        assert_eq!(paired.hit_groups(), 4);
+    }
+}
@@ -5,7 +5,13 @@ pub mod report;
 pub mod taxonomy;
 pub mod utils;
 
+pub mod archive;
+pub mod checkpoint;
 pub mod db;
+pub mod k2d;
+pub mod library;
+pub mod lineage;
+pub mod manifest;
 pub use kr2r_data::*;
 pub use kv_store::*;
 pub use readcounts::TaxonCounts;
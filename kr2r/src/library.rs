@@ -0,0 +1,259 @@
+//! 向数据库的 library 追加自定义序列 (add-to-library 和各种导入工具共用)
+//!
+//! 序列写入 library/library_custom_N.fna, 标题改写为 `taxid|N|seqid`, 与 merge_fna 一致.
+//! 记录同时追加到 seqid2taxid.map 和 library/custom_seqid2taxid.map,
+//! 重新运行 merge_fna 时后者会追加回 seqid2taxid.map.
+use crate::checkpoint::remove_if_exists;
+use crate::db::generate_taxonomy;
+use crate::taxonomy::Taxonomy;
+use crate::utils::{find_files, open_text_file, read_id_to_taxon_map};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufWriter, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// 自定义序列写入 library/library_custom_N.fna
+pub const CUSTOM_LIBRARY_PREFIX: &str = "library_custom_";
+/// 自定义序列的 seqid2taxid 记录 (library 目录中), 重新运行 merge_fna 时追加回 seqid2taxid.map
+pub const CUSTOM_MAP_FILENAME: &str = "custom_seqid2taxid.map";
+
+/// 标题行 (不含 '>') 的第一个词
+pub fn header_seqid(header: &str) -> &str {
+    header.split_whitespace().next().unwrap_or_default()
+}
+
+/// seqid2taxid.map 和 library 中的序列名
+pub fn sequence_name(taxid: u64, seqid: &str) -> String {
+    format!("taxid|{}|{}", taxid, seqid)
+}
+
+/// 依次遍历 fasta (可以是 gz) 的记录, 对每个标题行 (不含 '>') 调用 f,
+/// 返回新的标题行时保留该记录并交给 write 写出, write 的第二个参数表示记录开始
+pub fn for_each_record<F, W>(path: &Path, mut f: F, mut write: W) -> Result<()>
+where
+    F: FnMut(&str) -> Option<String>,
+    W: FnMut(&[u8], bool) -> Result<()>,
+{
+    let mut reader = open_text_file(path)?;
+    let mut line = String::new();
+    let mut keep = false;
+    while reader.read_line(&mut line)? != 0 {
+        if let Some(header) = line.strip_prefix('>') {
+            match f(header.trim_end()) {
+                Some(new_header) => {
+                    keep = true;
+                    write(new_header.as_bytes(), true)?;
+                }
+                None => keep = false,
+            }
+        } else if keep {
+            write(line.as_bytes(), false)?;
+        }
+        line.clear();
+    }
+    Ok(())
+}
+
+/// 追加写入 library_custom_N.fna, 文件超过 max_file_size 时在记录边界换新文件
+struct LibraryWriter {
+    library_dir: PathBuf,
+    index: usize,
+    writer: BufWriter<File>,
+    bytes_written: u64,
+    max_file_size: u64,
+    /// 最后写入的字节是否为 '\n'. 输入文件最后一行可能没有换行, 写下一个标题前要补上
+    at_line_start: bool,
+}
+
+impl LibraryWriter {
+    fn file_path(library_dir: &Path, index: usize) -> PathBuf {
+        library_dir.join(format!("{}{}.fna", CUSTOM_LIBRARY_PREFIX, index))
+    }
+
+    /// 返回 writer, 文件长度以及文件是否以换行结尾 (空文件视为是)
+    fn open(library_dir: &Path, index: usize) -> Result<(BufWriter<File>, u64, bool)> {
+        let path = Self::file_path(library_dir, index);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let len = file.metadata()?.len();
+        let mut last = [b'\n'];
+        if len > 0 {
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+        }
+        // 追加后 estimate 的 hllp 缓存 (library_custom_N.hllp_n.json) 过期
+        let cache_prefix = format!("{}{}.", CUSTOM_LIBRARY_PREFIX, index);
+        for cache in find_files(library_dir, &cache_prefix, ".json") {
+            remove_if_exists(cache)?;
+        }
+        Ok((BufWriter::new(file), len, last[0] == b'\n'))
+    }
+
+    /// 从最后一个 library_custom_N.fna 继续写
+    fn new(library_dir: &Path, max_file_size: u64) -> Result<Self> {
+        let index = find_files(library_dir, CUSTOM_LIBRARY_PREFIX, ".fna")
+            .iter()
+            .filter_map(|path| {
+                path.file_stem()?
+                    .to_str()?
+                    .strip_prefix(CUSTOM_LIBRARY_PREFIX)?
+                    .parse::<usize>()
+                    .ok()
+            })
+            .max()
+            .unwrap_or(0);
+        let (writer, bytes_written, at_line_start) = Self::open(library_dir, index)?;
+        Ok(Self {
+            library_dir: library_dir.to_path_buf(),
+            index,
+            writer,
+            bytes_written,
+            max_file_size,
+            at_line_start,
+        })
+    }
+
+    fn write(&mut self, buf: &[u8], record_start: bool) -> Result<()> {
+        if record_start {
+            self.end_line()?;
+        }
+        if record_start && self.bytes_written > 0 && self.bytes_written >= self.max_file_size {
+            self.writer.flush()?;
+            self.index += 1;
+            (self.writer, self.bytes_written, self.at_line_start) =
+                Self::open(&self.library_dir, self.index)?;
+        }
+        self.writer.write_all(buf)?;
+        self.bytes_written += buf.len() as u64;
+        if let Some(&last) = buf.last() {
+            self.at_line_start = last == b'\n';
+        }
+        Ok(())
+    }
+
+    /// 上一行没有换行时补一个 '\n'
+    fn end_line(&mut self) -> Result<()> {
+        if !self.at_line_start {
+            self.writer.write_all(b"\n")?;
+            self.bytes_written += 1;
+            self.at_line_start = true;
+        }
+        Ok(())
+    }
+}
+
+fn append_file(path: &Path) -> Result<BufWriter<File>> {
+    Ok(BufWriter::new(
+        OpenOptions::new().create(true).append(true).open(path)?,
+    ))
+}
+
+/// 向数据库追加序列和 seqid2taxid 记录
+pub struct LibraryAppender {
+    fna_writer: LibraryWriter,
+    map_writer: BufWriter<File>,
+    custom_map_writer: BufWriter<File>,
+    rna_to_dna: bool,
+}
+
+impl LibraryAppender {
+    pub fn new(database: &Path, max_file_size: u64) -> Result<Self> {
+        let library_dir = database.join("library");
+        std::fs::create_dir_all(&library_dir)?;
+        Ok(Self {
+            fna_writer: LibraryWriter::new(&library_dir, max_file_size)?,
+            map_writer: append_file(&database.join("seqid2taxid.map"))?,
+            custom_map_writer: append_file(&library_dir.join(CUSTOM_MAP_FILENAME))?,
+            rna_to_dna: false,
+        })
+    }
+
+    /// 把序列中的 U 写成 T (SILVA 等 rRNA 数据库的序列是 RNA)
+    pub fn rna_to_dna(mut self) -> Self {
+        self.rna_to_dna = true;
+        self
+    }
+
+    /// 追加 fasta 文件中的序列. f 由标题行 (不含 '>') 返回序列名和 taxid, 返回 None 时跳过该序列
+    pub fn append<F>(&mut self, path: &Path, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str) -> Option<(String, u64)>,
+    {
+        let mut map_lines = String::new();
+        let fna_writer = &mut self.fna_writer;
+        let rna_to_dna = self.rna_to_dna;
+        for_each_record(
+            path,
+            |header| {
+                let (name, taxid) = f(header)?;
+                map_lines.push_str(&format!("{}\t{}\n", name, taxid));
+                Some(match header.split_once(char::is_whitespace) {
+                    Some((_, rest)) if !rest.is_empty() => format!(">{} {}\n", name, rest),
+                    _ => format!(">{}\n", name),
+                })
+            },
+            |buf, record_start| {
+                if rna_to_dna && !record_start {
+                    let dna: Vec<u8> = buf
+                        .iter()
+                        .map(|&c| match c {
+                            b'U' => b'T',
+                            b'u' => b't',
+                            c => c,
+                        })
+                        .collect();
+                    fna_writer.write(&dna, record_start)
+                } else {
+                    fna_writer.write(buf, record_start)
+                }
+            },
+        )?;
+        fna_writer.end_line()?;
+        self.map_writer.write_all(map_lines.as_bytes())?;
+        self.custom_map_writer.write_all(map_lines.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.fna_writer.writer.flush()?;
+        self.map_writer.flush()?;
+        self.custom_map_writer.flush()
+    }
+}
+
+/// 读取数据库已有的 seqid2taxid.map (没有时为空), 用于替换 taxonomy 的导入工具.
+/// 已有的序列必须都在新的 taxonomy 中, 例如不能和 NCBI 的 library 混用
+pub fn read_library_map(
+    database: &Path,
+    taxids: &HashSet<u64>,
+    source: &str,
+) -> Result<HashMap<String, u64>> {
+    let seqid2taxid_path = database.join("seqid2taxid.map");
+    if !seqid2taxid_path.exists() {
+        return Ok(HashMap::new());
+    }
+    let known_ids = read_id_to_taxon_map(&seqid2taxid_path)?;
+    if let Some((seqid, taxid)) = known_ids.iter().find(|(_, taxid)| !taxids.contains(taxid)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{:?} has sequences whose taxid is not in the {} taxonomy (e.g. {} with taxid {}), use an empty database",
+                seqid2taxid_path, source, seqid, taxid
+            ),
+        ));
+    }
+    Ok(known_ids)
+}
+
+/// 由 seqid2taxid.map 和 taxonomy 目录中的 nodes.dmp/names.dmp 重新生成 taxo.k2d
+pub fn generate_library_taxonomy(database: &Path) -> Result<Taxonomy> {
+    let id_to_taxon_map = read_id_to_taxon_map(database.join("seqid2taxid.map"))?;
+    generate_taxonomy(
+        &database.join("taxonomy"),
+        &database.join("taxo.k2d"),
+        &id_to_taxon_map,
+    )
+}
@@ -0,0 +1,173 @@
+//! 由 lineage 字符串生成的合成 taxonomy (GTDB, 16S 数据库等)
+//!
+//! 节点以从根开始的名称路径 (`Bacteria;Pseudomonadota;...`) 区分, 写成 NCBI 格式的
+//! nodes.dmp 和 names.dmp, 之后与 NCBI taxonomy 走同样的流程生成 taxo.k2d.
+//! 没有给定 taxid 的节点由路径的哈希得到 taxid, 与输入顺序无关, 重新导入时保持不变.
+use crate::taxonomy::CUSTOM_NODES_FILENAME;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Result, Write};
+use std::path::Path;
+
+pub const ROOT_TAXID: u64 = 1;
+
+/// 只对 NCBI taxonomy 有效的文件, 留在合成 taxonomy 旁边会把合成 taxid 当作合并或删除的 NCBI taxid,
+/// 自定义节点的 parent 也是 NCBI taxid
+const NCBI_ONLY_FILES: [&str; 3] = ["merged.dmp", "delnodes.dmp", CUSTOM_NODES_FILENAME];
+
+/// 按 rank 前缀 (`d__`, `p__` ...) 或 rank 名称返回标准 rank, 与 report 中的 rank 代码一致
+pub fn standard_rank(rank: &str) -> String {
+    match rank {
+        "d" | "k" | "domain" | "superkingdom" => "superkingdom",
+        "p" | "phylum" => "phylum",
+        "c" | "class" => "class",
+        "o" | "order" => "order",
+        "f" | "family" => "family",
+        "g" | "genus" => "genus",
+        "s" | "species" => "species",
+        other => other,
+    }
+    .to_string()
+}
+
+/// 解析带 rank 前缀的 lineage, 如 GTDB 和 Greengenes 的
+/// `d__Bacteria;p__Pseudomonadota;...;s__Escherichia coli`. 名称为空的层级 (`s__`) 被忽略
+pub fn parse_prefixed_lineage(lineage: &str) -> Vec<(String, String)> {
+    lineage
+        .split(';')
+        .filter_map(|level| {
+            let (prefix, name) = level.trim().split_once("__")?;
+            let name = name.trim();
+            if name.is_empty() {
+                None
+            } else {
+                Some((standard_rank(prefix), name.to_string()))
+            }
+        })
+        .collect()
+}
+
+struct LineageNode {
+    parent: String,
+    rank: String,
+    name: String,
+    taxid: Option<u64>,
+}
+
+/// 合成 taxonomy. 根节点的路径为空字符串
+#[derive(Default)]
+pub struct LineageTaxonomy {
+    nodes: BTreeMap<String, LineageNode>,
+}
+
+impl LineageTaxonomy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.nodes.contains_key(path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// 添加一条从高到低的 lineage [(rank, name)], 返回叶节点的路径. 已有的节点保持不变
+    pub fn insert(&mut self, lineage: &[(String, String)]) -> String {
+        let mut path = String::new();
+        for (rank, name) in lineage {
+            let parent = path.clone();
+            if !path.is_empty() {
+                path.push(';');
+            }
+            path.push_str(name);
+            self.nodes
+                .entry(path.clone())
+                .or_insert_with(|| LineageNode {
+                    parent,
+                    rank: rank.clone(),
+                    name: name.clone(),
+                    taxid: None,
+                });
+        }
+        path
+    }
+
+    /// 为节点指定 taxid (例如 SILVA 自带的 taxid), 节点不存在时返回 false
+    pub fn set_taxid(&mut self, path: &str, taxid: u64) -> bool {
+        match self.nodes.get_mut(path) {
+            Some(node) => {
+                node.taxid = Some(taxid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 返回每个路径的 taxid (根节点 "" 为 1). 指定的 taxid 优先,
+    /// 其余节点按路径顺序由哈希得到 taxid, 冲突时顺延到下一个空闲的值
+    pub fn assign_taxids(&self) -> HashMap<String, u64> {
+        let mut taxids = HashMap::with_capacity(self.nodes.len() + 1);
+        let mut used: HashSet<u64> = HashSet::new();
+        taxids.insert(String::new(), ROOT_TAXID);
+        used.insert(ROOT_TAXID);
+        for (path, node) in &self.nodes {
+            if let Some(taxid) = node.taxid {
+                taxids.insert(path.clone(), taxid);
+                used.insert(taxid);
+            }
+        }
+
+        let range = u32::MAX as u64 - 1;
+        for (path, node) in &self.nodes {
+            if node.taxid.is_some() {
+                continue;
+            }
+            let mut taxid = 2 + seahash::hash(path.as_bytes()) % range;
+            while !used.insert(taxid) {
+                taxid = 2 + (taxid - 1) % range;
+            }
+            taxids.insert(path.clone(), taxid);
+        }
+        taxids
+    }
+
+    /// 在 dir 中写入 nodes.dmp 和 names.dmp, 并删除之前 NCBI taxonomy 留下的 merged.dmp, delnodes.dmp 和自定义节点
+    pub fn write_taxdump(&self, dir: &Path, taxids: &HashMap<String, u64>) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        for name in NCBI_ONLY_FILES {
+            let path = dir.join(name);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        let mut nodes = BufWriter::new(File::create(dir.join("nodes.dmp"))?);
+        let mut names = BufWriter::new(File::create(dir.join("names.dmp"))?);
+        writeln!(
+            nodes,
+            "{}\t|\t{}\t|\tno rank\t|\t\t|",
+            ROOT_TAXID, ROOT_TAXID
+        )?;
+        writeln!(names, "{}\t|\troot\t|\t\t|\tscientific name\t|", ROOT_TAXID)?;
+        for (path, node) in &self.nodes {
+            let taxid = taxids[path];
+            writeln!(
+                nodes,
+                "{}\t|\t{}\t|\t{}\t|\t\t|",
+                taxid, taxids[&node.parent], node.rank
+            )?;
+            writeln!(
+                names,
+                "{}\t|\t{}\t|\t\t|\tscientific name\t|",
+                taxid, node.name
+            )?;
+        }
+        nodes.flush()?;
+        names.flush()
+    }
+}
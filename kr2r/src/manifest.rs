@@ -0,0 +1,527 @@
+//! 数据库清单 manifest.json
+//!
+//! 记录构建参数, 输入文件及校验和, 分类学版本和哈希表信息, 用于复现和校验数据库.
+use crate::archive::Archive;
+use crate::args::Build;
+use crate::compact_hash::HashConfig;
+use crate::k2d::{self, FileKind};
+use crate::taxonomy::Taxonomy;
+use crate::utils::{find_and_sort_files, find_files, open_file};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// 构建参数, 包含 `Build`/`KLMTArgs` 的全部取值
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BuildParams {
+    pub download_dir: Option<PathBuf>,
+    #[serde(flatten)]
+    pub build: Build,
+    pub max_n: usize,
+    pub load_factor: f64,
+    pub max_file_size: usize,
+    pub hash_capacity: usize,
+    /// 为不在 assembly summary 中的基因组提供 taxid 的 accession2taxid 文件
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accession2taxid: Vec<PathBuf>,
+    /// 是否记录输入文件 (library 等) 的 crc32. 默认只记录大小和修改时间, 避免每次构建重新读取整个 library
+    #[serde(default)]
+    pub source_checksums: bool,
+}
+
+/// 文件大小, crc32 校验和及修改时间. path 为相对于所在目录的路径.
+/// 索引文件总有 crc32; 输入文件只在 `source_checksums` 时记录 crc32, 否则记录修改时间
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc32: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<String>,
+}
+
+/// 下载目录中的 assembly summary 文件
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AssemblySource {
+    pub group: String,
+    pub site: String,
+    #[serde(flatten)]
+    pub file: FileEntry,
+    /// 实际存在 fna.gz 的基因组数量
+    pub genomes: usize,
+    /// 按 assembly_level 统计的基因组数量
+    pub asm_levels: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaxonomyInfo {
+    /// nodes.dmp (或 taxdump.tar.gz) 的修改时间 (UTC)
+    pub date: Option<String>,
+    pub node_count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HashTableInfo {
+    pub capacity: usize,
+    pub size: usize,
+    pub partition: usize,
+    pub hash_capacity: usize,
+    pub value_bits: usize,
+    pub version: usize,
+}
+
+impl From<&HashConfig> for HashTableInfo {
+    fn from(config: &HashConfig) -> Self {
+        Self {
+            capacity: config.capacity,
+            size: config.size,
+            partition: config.partition,
+            hash_capacity: config.hash_capacity,
+            value_bits: config.value_bits,
+            version: config.version,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub manifest_version: u32,
+    pub tool_version: String,
+    pub created: String,
+    pub build: BuildParams,
+    pub taxonomy: TaxonomyInfo,
+    pub hash_table: HashTableInfo,
+    /// 下载目录中的 assembly summary 文件
+    pub assemblies: Vec<AssemblySource>,
+    /// 数据库目录中的输入文件: library, seqid2taxid.map, taxonomy
+    pub sources: Vec<FileEntry>,
+    /// 数据库索引文件. 带头部的 .k2d 文件记录的是正文的校验和
+    pub index_files: Vec<FileEntry>,
+}
+
+/// 问题列表, 为空表示清单与文件一致
+pub type Problems = Vec<String>;
+
+/// 将 SystemTime 格式化为 UTC 时间, 例如 2024-05-01T08:00:00Z
+pub fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Howard Hinnant 的 civil_from_days 算法
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// 流式计算整个文件的大小和 crc32
+pub fn file_checksum<P: AsRef<Path>>(path: P) -> Result<(u64, u32)> {
+    let mut reader = BufReader::with_capacity(1 << 20, open_file(path)?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut size = 0u64;
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        size += n as u64;
+    }
+    Ok((size, hasher.finalize()))
+}
+
+fn relative_path(base: &Path, path: &Path) -> String {
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// 输入文件条目. checksum 为 false 时不读取文件内容
+fn source_entry(base: &Path, path: &Path, checksum: bool) -> Result<FileEntry> {
+    let (size, crc32) = if checksum {
+        let (size, crc32) = file_checksum(path)?;
+        (size, Some(format!("{:08x}", crc32)))
+    } else {
+        (fs::metadata(path)?.len(), None)
+    };
+    Ok(FileEntry {
+        path: relative_path(base, path),
+        size,
+        crc32,
+        modified: modified_time(path),
+    })
+}
+
+/// 索引文件条目: 带头部的文件直接使用头部中的校验和, 旧格式文件计算整个文件
+fn index_entry(database: &Path, path: &Path, kind: FileKind) -> Result<FileEntry> {
+    let size = fs::metadata(path)?.len();
+    let (_, header) = k2d::open(path, kind)?;
+    let crc32 = match header {
+        Some(header) => header.checksum,
+        None => file_checksum(path)?.1,
+    };
+    Ok(FileEntry {
+        path: relative_path(database, path),
+        size,
+        crc32: Some(format!("{:08x}", crc32)),
+        modified: None,
+    })
+}
+
+/// 数据库索引文件及其类型
+pub fn index_files(database: &Path) -> Result<Vec<(PathBuf, FileKind)>> {
+    let mut files = vec![
+        (database.join("hash_config.k2d"), FileKind::HashConfig),
+        (database.join("opts.k2d"), FileKind::IndexOptions),
+        (database.join("taxo.k2d"), FileKind::Taxonomy),
+    ];
+    for path in find_and_sort_files(database, "hash", ".k2d", true)? {
+        files.push((path, FileKind::Page));
+    }
+    Ok(files)
+}
+
+/// 数据库目录中的输入文件
+fn source_files(database: &Path) -> Vec<PathBuf> {
+    let mut files = find_files(database.join("library"), "library", ".fna");
+    let library_fna = database.join("library.fna");
+    if library_fna.exists() {
+        files.push(library_fna);
+    }
+    for name in [
+        "seqid2taxid.map",
+        "taxonomy/nodes.dmp",
+        "taxonomy/names.dmp",
+    ] {
+        let path = database.join(name);
+        if path.exists() {
+            files.push(path);
+        }
+    }
+    files
+}
+
+/// 解析 assembly summary, 统计实际下载了 fna.gz 的基因组的 assembly_level
+fn assembly_source(download_dir: &Path, path: &Path) -> Result<Option<AssemblySource>> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let site = match file_name
+        .strip_prefix("assembly_summary_")
+        .and_then(|s| s.strip_suffix(".txt"))
+    {
+        Some(site) => site.to_string(),
+        None => return Ok(None),
+    };
+    let parent = path.parent().unwrap_or(download_dir);
+    let group = relative_path(&download_dir.join("library"), parent);
+
+    let mut genomes = 0;
+    let mut asm_levels = BTreeMap::new();
+    for line in BufReader::new(open_file(path)?).lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() <= 19 || fields[19] == "na" {
+            continue;
+        }
+        let fna_file = parent.join(&site).join(format!(
+            "{}_genomic.fna.gz",
+            fields[19].rsplit('/').next().unwrap_or_default()
+        ));
+        if fna_file.exists() {
+            genomes += 1;
+            *asm_levels.entry(fields[11].to_string()).or_insert(0) += 1;
+        }
+    }
+
+    Ok(Some(AssemblySource {
+        group,
+        site,
+        file: source_entry(download_dir, path, true)?,
+        genomes,
+        asm_levels,
+    }))
+}
+
+/// 文件的修改时间 (UTC)
+pub fn modified_time(path: &Path) -> Option<String> {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .map(format_time)
+}
+
+impl Manifest {
+    /// 构建完成后收集数据库信息
+    pub fn collect(database: &Path, build: BuildParams) -> Result<Self> {
+        let config = HashConfig::from_hash_header(database.join("hash_config.k2d"))?;
+        let taxonomy = Taxonomy::from_file(database.join("taxo.k2d"))?;
+
+        let mut assemblies = Vec::new();
+        let mut date = None;
+        if let Some(download_dir) = build.download_dir.as_deref() {
+            for path in find_files(download_dir.join("library"), "assembly_summary_", ".txt") {
+                if let Some(source) = assembly_source(download_dir, &path)? {
+                    assemblies.push(source);
+                }
+            }
+            let taxonomy_dir = download_dir.join("taxonomy");
+            date = modified_time(&taxonomy_dir.join("taxdump.tar.gz"))
+                .or_else(|| modified_time(&taxonomy_dir.join("nodes.dmp")));
+        }
+        if date.is_none() {
+            date = modified_time(&database.join("taxonomy").join("nodes.dmp"));
+        }
+
+        let sources = source_files(database)
+            .iter()
+            .map(|path| source_entry(database, path, build.source_checksums))
+            .collect::<Result<Vec<_>>>()?;
+        let index_files = index_files(database)?
+            .iter()
+            .map(|(path, kind)| index_entry(database, path, *kind))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            manifest_version: MANIFEST_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: format_time(SystemTime::now()),
+            build,
+            taxonomy: TaxonomyInfo {
+                date,
+                node_count: taxonomy.node_count(),
+            },
+            hash_table: HashTableInfo::from(&config),
+            assemblies,
+            sources,
+            index_files,
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let reader = BufReader::new(open_file(&path)?);
+        serde_json::from_reader(reader).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} is not a valid manifest: {}", path.as_ref(), e),
+            )
+        })
+    }
+
+    /// 读取数据库目录或 db pack 归档中的清单
+    pub fn from_database<P: AsRef<Path>>(database: P) -> Result<Self> {
+        let database = database.as_ref();
+        if !Archive::is_archive(database) {
+            return Self::from_file(database.join(MANIFEST_FILENAME));
+        }
+        let data = Archive::open(database)?.read_entry(MANIFEST_FILENAME)?;
+        serde_json::from_slice(&data).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} has an invalid manifest: {}", database, e),
+            )
+        })
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+
+    /// 对照数据库目录中的文件校验清单. 默认只比较输入文件的大小,
+    /// `checksums` 为 true 时重新计算记录了 crc32 的输入文件的校验和
+    pub fn validate(&self, database: &Path, checksums: bool) -> Result<Problems> {
+        let mut problems = Problems::new();
+
+        let archive = if Archive::is_archive(database) {
+            Some(Archive::open(database)?)
+        } else {
+            None
+        };
+        match &archive {
+            Some(archive) => self.validate_archive(archive, &mut problems),
+            None => self.validate_index_files(database, &mut problems),
+        }
+
+        match HashConfig::from_hash_header(database.join("hash_config.k2d")) {
+            Ok(config) => {
+                let actual = HashTableInfo::from(&config);
+                if actual != self.hash_table {
+                    problems.push(format!(
+                        "hash_config.k2d: expected {:?}, found {:?}",
+                        self.hash_table, actual
+                    ));
+                }
+            }
+            Err(e) => problems.push(format!("hash_config.k2d: {}", e)),
+        }
+        match Taxonomy::from_file(database.join("taxo.k2d")) {
+            Ok(taxonomy) if taxonomy.node_count() != self.taxonomy.node_count => {
+                problems.push(format!(
+                    "taxo.k2d: expected {} nodes, found {}",
+                    self.taxonomy.node_count,
+                    taxonomy.node_count()
+                ))
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(format!("taxo.k2d: {}", e)),
+        }
+
+        // 归档中不包含输入文件
+        let sources = if archive.is_some() {
+            &[][..]
+        } else {
+            &self.sources[..]
+        };
+        for entry in sources {
+            let path = database.join(&entry.path);
+            if !path.exists() {
+                problems.push(format!("{}: missing", entry.path));
+            } else if checksums && entry.crc32.is_some() {
+                match source_entry(database, &path, true) {
+                    Ok(actual) => compare_entry(entry, &actual, &mut problems),
+                    Err(e) => problems.push(format!("{}: {}", entry.path, e)),
+                }
+            } else {
+                let size = fs::metadata(&path)?.len();
+                if size != entry.size {
+                    problems.push(format!(
+                        "{}: expected {} bytes, found {}",
+                        entry.path, entry.size, size
+                    ));
+                }
+            }
+        }
+
+        Ok(problems)
+    }
+
+    fn validate_index_files(&self, database: &Path, problems: &mut Problems) {
+        let listed: Vec<&str> = self.index_files.iter().map(|e| e.path.as_str()).collect();
+        match index_files(database) {
+            Ok(files) => {
+                for (path, kind) in files {
+                    let name = relative_path(database, &path);
+                    if !listed.contains(&name.as_str()) {
+                        problems.push(format!("{}: not listed in the manifest", name));
+                        continue;
+                    }
+                    if !path.exists() {
+                        continue;
+                    }
+                    let expected = self.index_files.iter().find(|e| e.path == name).unwrap();
+                    match index_entry(database, &path, kind) {
+                        Ok(actual) => compare_entry(expected, &actual, problems),
+                        Err(e) => problems.push(format!("{}: {}", name, e)),
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("hash pages: {}", e)),
+        }
+        for entry in &self.index_files {
+            if !database.join(&entry.path).exists() {
+                problems.push(format!("{}: missing", entry.path));
+            }
+        }
+    }
+
+    /// 归档中的索引文件: 比较长度和 .k2d 头部中的校验和
+    fn validate_archive(&self, archive: &Archive, problems: &mut Problems) {
+        for expected in &self.index_files {
+            let entry = match archive.entry(&expected.path) {
+                Some(entry) => entry,
+                None => {
+                    problems.push(format!("{}: missing from the archive", expected.path));
+                    continue;
+                }
+            };
+            let mut buffer = [0u8; k2d::K2D_HEADER_SIZE as usize];
+            let header = archive.open_entry(entry).and_then(|mut reader| {
+                if entry.len < k2d::K2D_HEADER_SIZE {
+                    return Ok(None);
+                }
+                reader.read_exact(&mut buffer)?;
+                let kind = index_file_kind(&expected.path);
+                k2d::parse_header(&archive.path.join(&entry.name), kind, &buffer, entry.len)
+            });
+            let crc32 = match header {
+                Ok(Some(header)) => header.checksum,
+                Ok(None) => entry.crc32,
+                Err(e) => {
+                    problems.push(format!("{}: {}", expected.path, e));
+                    continue;
+                }
+            };
+            let actual = FileEntry {
+                path: expected.path.clone(),
+                size: entry.len,
+                crc32: Some(format!("{:08x}", crc32)),
+                modified: None,
+            };
+            compare_entry(expected, &actual, problems);
+        }
+        for entry in &archive.entries {
+            if entry.name != MANIFEST_FILENAME
+                && !self.index_files.iter().any(|e| e.path == entry.name)
+            {
+                problems.push(format!("{}: not listed in the manifest", entry.name));
+            }
+        }
+    }
+}
+
+/// 根据文件名判断索引文件的类型
+fn index_file_kind(name: &str) -> FileKind {
+    match name {
+        "hash_config.k2d" => FileKind::HashConfig,
+        "opts.k2d" => FileKind::IndexOptions,
+        "taxo.k2d" => FileKind::Taxonomy,
+        _ => FileKind::Page,
+    }
+}
+
+fn compare_entry(expected: &FileEntry, actual: &FileEntry, problems: &mut Problems) {
+    if expected.size != actual.size {
+        problems.push(format!(
+            "{}: expected {} bytes, found {}",
+            expected.path, expected.size, actual.size
+        ));
+    } else if let (Some(expected_crc32), Some(actual_crc32)) = (&expected.crc32, &actual.crc32) {
+        if expected_crc32 != actual_crc32 {
+            problems.push(format!(
+                "{}: checksum mismatch: expected {}, found {}",
+                expected.path, expected_crc32, actual_crc32
+            ));
+        }
+    }
+}
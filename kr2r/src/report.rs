@@ -1,3 +1,4 @@
+use crate::classify::TaxidRules;
 use crate::readcounts::{ReadCounter, TaxonCounters};
 use crate::taxonomy::Taxonomy;
 use std::collections::HashMap;
@@ -152,8 +153,8 @@ pub fn report_mpa_style<P: AsRef<Path>>(
     )
 }
 
-pub fn print_kraken_style_report_line(
-    file: &mut File,
+pub fn print_kraken_style_report_line<W: Write>(
+    file: &mut W,
     report_kmer_data: bool,
     total_seqs: u64,
     clade_counter: &mut ReadCounter,
@@ -192,9 +193,9 @@ pub fn print_kraken_style_report_line(
     writeln!(file, "{}", sci_name)
 }
 
-pub fn kraken_report_dfs(
+pub fn kraken_report_dfs<W: Write>(
     taxid: u64,
-    file: &mut File,
+    file: &mut W,
     report_zeros: bool,
     report_kmer_data: bool,
     taxonomy: &Taxonomy,
@@ -296,16 +297,36 @@ pub fn report_kraken_style<P: AsRef<Path>>(
     total_seqs: u64,
     total_unclassified: u64,
 ) -> io::Result<()> {
-    let mut clade_counters = get_clade_counters(taxonomy, call_counters);
-
     let mut file = File::create(filename)?;
+    write_kraken_style_report(
+        &mut file,
+        report_zeros,
+        report_kmer_data,
+        taxonomy,
+        call_counters,
+        total_seqs,
+        total_unclassified,
+    )
+}
+
+/// 以 kraken report 格式写入任意 writer
+pub fn write_kraken_style_report<W: Write>(
+    file: &mut W,
+    report_zeros: bool,
+    report_kmer_data: bool,
+    taxonomy: &Taxonomy,
+    call_counters: &HashMap<u64, ReadCounter>,
+    total_seqs: u64,
+    total_unclassified: u64,
+) -> io::Result<()> {
+    let mut clade_counters = get_clade_counters(taxonomy, call_counters);
 
     // 处理未分类序列的特殊情况
     if total_unclassified != 0 || report_zeros {
         let mut rc = ReadCounter::new(total_unclassified, 0);
         let trc = ReadCounter::new(total_unclassified, 0);
         print_kraken_style_report_line(
-            &mut file,
+            file,
             report_kmer_data,
             total_seqs,
             &mut rc,
@@ -320,7 +341,7 @@ pub fn report_kraken_style<P: AsRef<Path>>(
     // 通过 DFS 遍历分类树
     kraken_report_dfs(
         1,
-        &mut file,
+        file,
         report_zeros,
         report_kmer_data,
         taxonomy,
@@ -332,3 +353,91 @@ pub fn report_kraken_style<P: AsRef<Path>>(
         0,
     )
 }
+
+/// 扁平的丰度表: 每个有直接 read 计数的节点一行, 按 read 数从多到少排列, 第一行为未分类.
+/// 配合 --report-rank 使用时, 每一行就是目标 rank (或其之上) 的一个分类单元
+pub fn report_abundance<P: AsRef<Path>>(
+    filename: P,
+    taxonomy: &Taxonomy,
+    call_counters: &HashMap<u64, ReadCounter>,
+    total_seqs: u64,
+    total_unclassified: u64,
+) -> io::Result<()> {
+    let mut rows: Vec<(u64, u64)> = call_counters
+        .iter()
+        .map(|(&taxid, counter)| (taxid, counter.read_count()))
+        .filter(|&(_, reads)| reads > 0)
+        .collect();
+    rows.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let fraction = |reads: u64| {
+        if total_seqs == 0 {
+            0.0
+        } else {
+            reads as f64 / total_seqs as f64
+        }
+    };
+
+    let mut file = io::BufWriter::new(File::create(filename)?);
+    writeln!(file, "#taxid\tname\trank\treads\tfraction")?;
+    writeln!(
+        file,
+        "0\tunclassified\tno rank\t{}\t{:.6}",
+        total_unclassified,
+        fraction(total_unclassified)
+    )?;
+    for (taxid, reads) in rows {
+        let internal_id = taxid as u32;
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{:.6}",
+            taxonomy.nodes[taxid as usize].external_id,
+            taxonomy.name(internal_id),
+            taxonomy.rank(internal_id),
+            reads,
+            fraction(reads)
+        )?;
+    }
+    file.flush()
+}
+
+/// 写入 {prefix}.kreport2, 设置 report_rank 时再写入 {prefix}.abundance.tsv.
+/// 计数先按 --taxid-remap 重映射, kreport 开头记录 taxid 范围过滤条件
+#[allow(clippy::too_many_arguments)]
+pub fn write_reports<P: AsRef<Path>>(
+    prefix: P,
+    taxonomy: &Taxonomy,
+    rules: &TaxidRules,
+    taxon_counts: &TaxonCounters,
+    total_seqs: u64,
+    total_unclassified: u64,
+    report_zero_counts: bool,
+    report_kmer_data: bool,
+    report_rank: Option<&str>,
+) -> io::Result<()> {
+    let prefix = prefix.as_ref();
+    let taxon_counts = rules.remap.apply(taxon_counts);
+    let mut file = io::BufWriter::new(File::create(prefix.with_extension("kreport2"))?);
+    rules.scope.write_header(&mut file, taxonomy)?;
+    write_kraken_style_report(
+        &mut file,
+        report_zero_counts,
+        report_kmer_data,
+        taxonomy,
+        &taxon_counts,
+        total_seqs,
+        total_unclassified,
+    )?;
+    file.flush()?;
+
+    if report_rank.is_some() {
+        report_abundance(
+            prefix.with_extension("abundance.tsv"),
+            taxonomy,
+            &taxon_counts,
+            total_seqs,
+            total_unclassified,
+        )?;
+    }
+    Ok(())
+}
@@ -1,8 +1,10 @@
+use crate::k2d::{self, FileKind, K2dError, K2dWriter};
 use crate::utils::open_file;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::ops::Range;
 use std::path::Path;
 
 /// 解析 ncbi 文件的 taxonomy nodes 文件
@@ -92,6 +94,210 @@ pub fn parse_names_file<P: AsRef<Path>>(names_filename: P) -> Result<HashMap<u64
     Ok(name_map)
 }
 
+/// NCBI merged.dmp 和 delnodes.dmp: 已合并到其它节点的 taxid 和已删除的 taxid.
+/// 文件不存在时为空, 所有 taxid 保持不变
+#[derive(Debug, Default)]
+pub struct TaxidRedirect {
+    merged: HashMap<u64, u64>,
+    deleted: HashSet<u64>,
+}
+
+/// 读取 dmp 文件中每行的前 n 个 taxid
+fn parse_dmp_ids(filename: &Path, n: usize) -> Result<Vec<Vec<u64>>> {
+    let reader = BufReader::new(open_file(filename)?);
+    let mut rows = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches(['\t', '|']);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let ids: Vec<u64> = line
+            .split("\t|\t")
+            .take(n)
+            .map(|field| field.trim().parse::<u64>())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{:?}: invalid taxid in line {:?}", filename, line),
+                )
+            })?;
+        if ids.len() == n {
+            rows.push(ids);
+        }
+    }
+    Ok(rows)
+}
+
+impl TaxidRedirect {
+    pub fn from_dir<P: AsRef<Path>>(ncbi_taxonomy_directory: P) -> Result<Self> {
+        let dir = ncbi_taxonomy_directory.as_ref();
+        let mut redirect = Self::default();
+        let merged_filename = dir.join("merged.dmp");
+        if merged_filename.exists() {
+            for ids in parse_dmp_ids(&merged_filename, 2)? {
+                redirect.merged.insert(ids[0], ids[1]);
+            }
+        }
+        let delnodes_filename = dir.join("delnodes.dmp");
+        if delnodes_filename.exists() {
+            for ids in parse_dmp_ids(&delnodes_filename, 1)? {
+                redirect.deleted.insert(ids[0]);
+            }
+        }
+        Ok(redirect)
+    }
+
+    /// taxid 是否已经合并或删除
+    pub fn is_retired(&self, taxid: u64) -> bool {
+        self.merged.contains_key(&taxid) || self.deleted.contains(&taxid)
+    }
+
+    /// 返回当前的 taxid: 合并过的 taxid 返回合并后的节点 (可能合并多次), 已删除的返回 None
+    pub fn resolve(&self, taxid: u64) -> Option<u64> {
+        let mut current = taxid;
+        for _ in 0..=self.merged.len() {
+            match self.merged.get(&current) {
+                Some(&target) => current = target,
+                None => break,
+            }
+        }
+        if self.deleted.contains(&current) {
+            None
+        } else {
+            Some(current)
+        }
+    }
+
+    /// 同 resolve, 并记录到 stats 中
+    pub fn resolve_counted(&self, taxid: u64, stats: &mut RedirectStats) -> Option<u64> {
+        let resolved = self.resolve(taxid);
+        match resolved {
+            None => {
+                stats.deleted.insert(taxid);
+            }
+            Some(current) if current != taxid => {
+                stats.merged.insert(taxid);
+            }
+            _ => {}
+        }
+        resolved
+    }
+}
+
+/// 重定向的 taxid 统计, 处理完成后打印一次警告
+#[derive(Debug, Default)]
+pub struct RedirectStats {
+    pub merged: BTreeSet<u64>,
+    pub deleted: BTreeSet<u64>,
+}
+
+impl RedirectStats {
+    pub fn is_empty(&self) -> bool {
+        self.merged.is_empty() && self.deleted.is_empty()
+    }
+
+    /// 打印警告, source 说明 taxid 的来源
+    pub fn warn(&self, source: &str) {
+        let examples = |ids: &BTreeSet<u64>| {
+            let mut text: Vec<String> = ids.iter().take(5).map(|id| id.to_string()).collect();
+            if ids.len() > 5 {
+                text.push("...".to_string());
+            }
+            text.join(", ")
+        };
+        if !self.merged.is_empty() {
+            eprintln!(
+                "warning: {} merged taxids in {} redirected to their new taxids: {}",
+                self.merged.len(),
+                source,
+                examples(&self.merged)
+            );
+        }
+        if !self.deleted.is_empty() {
+            eprintln!(
+                "warning: {} deleted taxids in {} dropped: {}",
+                self.deleted.len(),
+                source,
+                examples(&self.deleted)
+            );
+        }
+    }
+}
+
+/// 把 seqid 到 taxid 的映射中合并过的 taxid 改为当前的 taxid, 去掉已删除的 taxid
+pub fn redirect_id_map(
+    id_map: &mut HashMap<String, u64>,
+    redirect: &TaxidRedirect,
+) -> RedirectStats {
+    let mut stats = RedirectStats::default();
+    id_map.retain(
+        |_, taxid| match redirect.resolve_counted(*taxid, &mut stats) {
+            Some(current) => {
+                *taxid = current;
+                true
+            }
+            None => false,
+        },
+    );
+    stats
+}
+
+/// taxonomy 目录中的用户自定义节点文件, 生成 taxo.k2d 时合并到 NCBI taxonomy
+pub const CUSTOM_NODES_FILENAME: &str = "custom_nodes.tsv";
+
+/// 用户自定义节点, 例如没有 NCBI taxid 的 MAG
+#[derive(Debug, Clone)]
+pub struct CustomNode {
+    pub taxid: u64,
+    pub parent_id: u64,
+    pub rank: String,
+    pub name: String,
+}
+
+/// 解析自定义节点文件, 每行 id<TAB>parent taxid<TAB>rank<TAB>name
+pub fn parse_custom_nodes_file<P: AsRef<Path>>(filename: P) -> Result<Vec<CustomNode>> {
+    let filename = filename.as_ref();
+    let reader = BufReader::new(open_file(filename)?);
+    let mut nodes = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<_> = line.split('\t').map(|f| f.trim()).collect();
+        let node = match fields.as_slice() {
+            [taxid, parent_id, rank, name, ..] if !name.is_empty() => {
+                match (taxid.parse::<u64>(), parent_id.parse::<u64>()) {
+                    (Ok(taxid), Ok(parent_id)) if taxid > 1 => Some(CustomNode {
+                        taxid,
+                        parent_id,
+                        rank: rank.to_string(),
+                        name: name.to_string(),
+                    }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match node {
+            Some(node) => nodes.push(node),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{:?} line {}: expected taxid<TAB>parent taxid<TAB>rank<TAB>name",
+                        filename,
+                        i + 1
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(nodes)
+}
+
 /// 结构体定义
 #[derive(Debug)]
 pub struct TaxonomyNode {
@@ -148,6 +354,64 @@ impl NCBITaxonomy {
         })
     }
 
+    /// 合并自定义节点. taxid 不能和已有的节点重复, parent 必须是已有节点或其它自定义节点
+    pub fn add_custom_nodes(&mut self, nodes: &[CustomNode]) -> Result<()> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+        let mut custom_ids = HashSet::new();
+        for node in nodes {
+            if self.parent_map.contains_key(&node.taxid) || !custom_ids.insert(node.taxid) {
+                return Err(invalid(format!(
+                    "custom taxid {} ({}) collides with an existing taxid",
+                    node.taxid, node.name
+                )));
+            }
+        }
+        for node in nodes {
+            if !self.parent_map.contains_key(&node.parent_id)
+                && !custom_ids.contains(&node.parent_id)
+            {
+                return Err(invalid(format!(
+                    "parent taxid {} of custom taxid {} is not in the taxonomy",
+                    node.parent_id, node.taxid
+                )));
+            }
+        }
+
+        for node in nodes {
+            self.parent_map.insert(node.taxid, node.parent_id);
+            self.child_map
+                .entry(node.parent_id)
+                .or_default()
+                .insert(node.taxid);
+            self.rank_map.insert(node.taxid, node.rank.clone());
+            self.known_ranks.insert(node.rank.clone());
+            self.name_map.insert(node.taxid, node.name.clone());
+        }
+
+        // 自定义节点之间不能有环, 每个节点都要能到达根节点
+        for node in nodes {
+            let mut taxid = node.taxid;
+            for _ in 0..=custom_ids.len() {
+                if !custom_ids.contains(&taxid) {
+                    break;
+                }
+                taxid = self.parent_map[&taxid];
+            }
+            if custom_ids.contains(&taxid) {
+                return Err(invalid(format!(
+                    "custom taxid {} is part of a parent cycle",
+                    node.taxid
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// taxid 是否在 taxonomy 中
+    pub fn contains(&self, taxid: u64) -> bool {
+        self.parent_map.contains_key(&taxid)
+    }
+
     pub fn mark_node(&mut self, taxid: u64) {
         let mut current_taxid = taxid;
         while !self.marked_nodes.contains(&current_taxid) {
@@ -232,10 +496,174 @@ impl NCBITaxonomy {
     }
 }
 
+/// lineage 输出使用的 7 个标准 rank
+pub const STANDARD_RANKS: [&str; 7] = [
+    "superkingdom",
+    "phylum",
+    "class",
+    "order",
+    "family",
+    "genus",
+    "species",
+];
+
+/// rank 在 STANDARD_RANKS 中的位置, 新版 NCBI taxonomy 的 domain 视为 superkingdom
+pub fn standard_rank_index(rank: &str) -> Option<usize> {
+    match rank {
+        "domain" => Some(0),
+        rank => STANDARD_RANKS.iter().position(|&r| r == rank),
+    }
+}
+
+/// name_data/rank_data 中从 offset 开始, 以 '\0' 结尾的字符串
+fn string_at(data: &[u8], offset: u64) -> &str {
+    data.get(offset as usize..)
+        .and_then(|data| data.split(|&c| c == 0).next())
+        .and_then(|s| std::str::from_utf8(s).ok())
+        .unwrap_or("")
+}
+
+/// LCA 索引每块的先序位置数
+const LCA_BLOCK_SIZE: usize = 32;
+
+/// 基于 DFS 先序的 LCA 索引. 子树在先序中是连续区间 [tin, tout], 祖先判断为 O(1);
+/// 不相关的两个节点 a, b (tin[a] < tin[b]) 的 LCA 是先序区间 (tin[a], tin[b]] 中深度最小的节点的父节点.
+/// 区间最小值按 LCA_BLOCK_SIZE 分块, 稀疏表只保存块的最小值, 每个节点约占 4 个 u32
+#[derive(Debug, Default)]
+struct LcaIndex {
+    /// 节点的先序位置, 根节点不可达的节点为 u32::MAX
+    tin: Vec<u32>,
+    /// 子树中最后一个节点的先序位置
+    tout: Vec<u32>,
+    depth: Vec<u32>,
+    /// 每个先序位置上的节点
+    order: Vec<u32>,
+    /// sparse[k][j]: 第 j .. j + 2^k 块中深度最小的先序位置
+    sparse: Vec<Vec<u32>>,
+}
+
+impl LcaIndex {
+    /// 迭代遍历, 很深的树也不会栈溢出. 子节点为 first_child 起连续的 child_count 个节点
+    fn new(nodes: &[TaxonomyNode], root: u32) -> Self {
+        let n = nodes.len();
+        let mut index = LcaIndex {
+            tin: vec![u32::MAX; n],
+            tout: vec![0; n],
+            depth: vec![0; n],
+            order: Vec::with_capacity(n),
+            sparse: Vec::new(),
+        };
+        if root as usize >= n {
+            return index;
+        }
+
+        let mut stack = vec![(root, false)];
+        while let Some((node, exit)) = stack.pop() {
+            let i = node as usize;
+            if exit {
+                index.tout[i] = index.order.len() as u32 - 1;
+                continue;
+            }
+            index.tin[i] = index.order.len() as u32;
+            index.order.push(node);
+            stack.push((node, true));
+            let first = nodes[i].first_child as usize;
+            let last = (first + nodes[i].child_count as usize).min(n);
+            for child in (first..last).rev() {
+                if index.tin[child] == u32::MAX && child != i {
+                    index.depth[child] = index.depth[i] + 1;
+                    stack.push((child as u32, false));
+                }
+            }
+        }
+
+        let blocks: Vec<u32> = (0..index.order.len().div_ceil(LCA_BLOCK_SIZE))
+            .map(|b| {
+                let start = b * LCA_BLOCK_SIZE;
+                let end = (start + LCA_BLOCK_SIZE).min(index.order.len()) - 1;
+                index.scan(start, end)
+            })
+            .collect();
+        let mut level = 1;
+        index.sparse.push(blocks);
+        while level * 2 <= index.sparse[0].len() {
+            let prev = index.sparse.last().unwrap();
+            let next: Vec<u32> = (0..prev.len() - level)
+                .map(|j| index.shallower(prev[j], prev[j + level]))
+                .collect();
+            index.sparse.push(next);
+            level *= 2;
+        }
+        index
+    }
+
+    fn contains(&self, node: u32) -> bool {
+        self.tin.get(node as usize).is_some_and(|&t| t != u32::MAX)
+    }
+
+    fn is_ancestor(&self, a: u32, b: u32) -> bool {
+        if !self.contains(a) || !self.contains(b) {
+            return false;
+        }
+        let (a, b) = (a as usize, b as usize);
+        self.tin[a] <= self.tin[b] && self.tout[b] <= self.tout[a]
+    }
+
+    /// 两个先序位置中深度较小的一个, 深度相同时取前一个
+    fn shallower(&self, p: u32, q: u32) -> u32 {
+        let depth = |pos: u32| self.depth[self.order[pos as usize] as usize];
+        if depth(q) < depth(p) {
+            q
+        } else {
+            p
+        }
+    }
+
+    fn scan(&self, start: usize, end: usize) -> u32 {
+        (start as u32 + 1..=end as u32).fold(start as u32, |p, q| self.shallower(p, q))
+    }
+
+    /// 先序位置 [l, r] 中深度最小的位置
+    fn min_position(&self, l: usize, r: usize) -> u32 {
+        let (bl, br) = (l / LCA_BLOCK_SIZE, r / LCA_BLOCK_SIZE);
+        if bl == br {
+            return self.scan(l, r);
+        }
+        let mut best = self.shallower(
+            self.scan(l, (bl + 1) * LCA_BLOCK_SIZE - 1),
+            self.scan(br * LCA_BLOCK_SIZE, r),
+        );
+        if bl + 1 < br {
+            let (from, to) = (bl + 1, br - 1);
+            let k = (usize::BITS - 1 - (to - from + 1).leading_zeros()) as usize;
+            let table = &self.sparse[k];
+            best = self.shallower(best, self.shallower(table[from], table[to + 1 - (1 << k)]));
+        }
+        best
+    }
+
+    /// 两个节点不在同一棵树中 (不可达) 时返回 0
+    fn lca(&self, nodes: &[TaxonomyNode], a: u32, b: u32) -> u32 {
+        if !self.contains(a) || !self.contains(b) {
+            return 0;
+        }
+        if self.is_ancestor(a, b) {
+            return a;
+        }
+        if self.is_ancestor(b, a) {
+            return b;
+        }
+        let (ta, tb) = (self.tin[a as usize], self.tin[b as usize]);
+        let (l, r) = (ta.min(tb) as usize, ta.max(tb) as usize);
+        let node = self.order[self.min_position(l + 1, r) as usize];
+        nodes[node as usize].parent_id as u32
+    }
+}
+
 // Taxonomy 类型定义
 #[derive(Debug)]
 pub struct Taxonomy {
-    pub path_cache: HashMap<u32, Vec<u32>>,
+    lca_index: LcaIndex,
     pub nodes: Vec<TaxonomyNode>,
     pub name_data: Vec<u8>, // 字符串数据以 Vec<u8> 存储
     pub rank_data: Vec<u8>, // 字符串数据以 Vec<u8> 存储
@@ -245,7 +673,7 @@ pub struct Taxonomy {
 impl Default for Taxonomy {
     fn default() -> Self {
         Taxonomy {
-            path_cache: HashMap::new(),
+            lca_index: LcaIndex::default(),
             nodes: Vec::new(),
             name_data: Vec::new(),
             rank_data: Vec::new(),
@@ -258,35 +686,66 @@ impl Taxonomy {
     const MAGIC: &'static [u8] = b"K2TAXDAT"; // 替换为实际的 magic bytes
 
     pub fn from_file<P: AsRef<Path> + Debug>(filename: P) -> Result<Taxonomy> {
-        let mut file = open_file(&filename)?;
-
-        let mut magic = vec![0; Self::MAGIC.len()];
-        file.read_exact(&mut magic)?;
-        if magic != Self::MAGIC {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Malformed taxonomy file {:?}", &filename),
-            ));
-        }
-
-        let mut buffer = [0; 24];
-        file.read_exact(&mut buffer)?;
-        let (node_count, name_data_len, rank_data_len) =
-            unsafe { std::mem::transmute::<[u8; 24], (u64, u64, u64)>(buffer) };
-
-        let mut nodes = Vec::with_capacity(node_count as usize);
-        for _ in 0..node_count {
-            let mut buffer = [0; 56];
-            file.read_exact(&mut buffer)?;
-            let node = unsafe { std::mem::transmute::<[u8; 56], TaxonomyNode>(buffer) };
-            nodes.push(node);
+        let payload = k2d::read_payload(&filename, FileKind::Taxonomy)?;
+        let malformed = |reason: String| -> Error {
+            K2dError::Malformed {
+                path: filename.as_ref().to_path_buf(),
+                reason,
+            }
+            .into()
+        };
+
+        let magic_len = Self::MAGIC.len();
+        if payload.len() < magic_len + 24 || &payload[..magic_len] != Self::MAGIC {
+            return Err(malformed("missing K2TAXDAT magic".to_string()));
         }
 
-        let mut name_data = vec![0; name_data_len as usize];
-        file.read_exact(&mut name_data)?;
+        let u64_at = |i: usize| u64::from_le_bytes(payload[i..i + 8].try_into().unwrap());
+        let node_count = u64_at(magic_len) as usize;
+        let name_data_len = u64_at(magic_len + 8) as usize;
+        let rank_data_len = u64_at(magic_len + 16) as usize;
+
+        let nodes_start = magic_len + 24;
+        let names_start = nodes_start + node_count * 56;
+        let ranks_start = names_start + name_data_len;
+        if payload.len() != ranks_start + rank_data_len {
+            return Err(malformed(format!(
+                "{} nodes, {} name bytes and {} rank bytes do not match file length {}",
+                node_count,
+                name_data_len,
+                rank_data_len,
+                payload.len()
+            )));
+        }
 
-        let mut rank_data = vec![0; rank_data_len as usize];
-        file.read_exact(&mut rank_data)?;
+        let mut nodes = Vec::with_capacity(node_count);
+        for i in 0..node_count {
+            let offset = nodes_start + i * 56;
+            let node = TaxonomyNode {
+                parent_id: u64_at(offset),
+                first_child: u64_at(offset + 8),
+                child_count: u64_at(offset + 16),
+                name_offset: u64_at(offset + 24),
+                rank_offset: u64_at(offset + 32),
+                external_id: u64_at(offset + 40),
+                godparent_id: u64_at(offset + 48),
+            };
+            if i > 0 && node.parent_id as usize >= node_count {
+                return Err(malformed(format!(
+                    "node {} has invalid parent {}",
+                    i, node.parent_id
+                )));
+            }
+            if node.name_offset as usize >= name_data_len.max(1)
+                || node.rank_offset as usize >= rank_data_len.max(1)
+            {
+                return Err(malformed(format!("node {} has invalid string offsets", i)));
+            }
+            nodes.push(node);
+        }
+
+        let name_data = payload[names_start..ranks_start].to_vec();
+        let rank_data = payload[ranks_start..].to_vec();
 
         let mut external_to_internal_id_map = HashMap::new();
         for (internal_id, node) in nodes.iter().enumerate() {
@@ -295,13 +754,13 @@ impl Taxonomy {
         }
 
         let mut taxo = Taxonomy {
-            path_cache: HashMap::new(),
+            lca_index: LcaIndex::default(),
             nodes,
             name_data,
             rank_data,
             external_to_internal_id_map,
         };
-        taxo.build_path_cache();
+        taxo.build_lca_index();
         Ok(taxo)
     }
 
@@ -322,18 +781,22 @@ impl Taxonomy {
         current == a
     }
 
+    /// a 是否为 b 的祖先 (a == b 时也为 true)
     pub fn is_a_ancestor_of_b(&self, a: u32, b: u32) -> bool {
         if a == 0 || b == 0 {
             return false;
         }
+        self.lca_index.is_ancestor(a, b)
+    }
 
-        // 尝试从path_cache中获取b的祖先路径
-        if let Some(path) = self.path_cache.get(&b) {
-            // 检查路径中是否包含a
-            return path.contains(&a);
+    /// 节点子树在 DFS 先序中的区间 [tin, tout], 根节点不可达的节点为 None.
+    /// a 是 b 的祖先当且仅当 b 的 tin 落在 a 的区间内
+    pub fn preorder_interval(&self, internal_id: u32) -> Option<(u32, u32)> {
+        if internal_id == 0 || !self.lca_index.contains(internal_id) {
+            return None;
         }
-
-        false
+        let i = internal_id as usize;
+        Some((self.lca_index.tin[i], self.lca_index.tout[i]))
     }
 
     // 查找两个节点的最低公共祖先
@@ -341,22 +804,7 @@ impl Taxonomy {
         if a == 0 || b == 0 || a == b {
             return if a != 0 { a } else { b };
         }
-
-        let default: Vec<u32> = vec![0];
-        let path_a = self.path_cache.get(&a).unwrap_or(&default);
-        let path_b = self.path_cache.get(&b).unwrap_or(&default);
-
-        let mut i = 0;
-        while i < path_a.len() && i < path_b.len() && path_a[i] == path_b[i] {
-            i += 1;
-        }
-
-        if i == 0 {
-            return 0;
-        }
-
-        // 返回最后一个共同的祖先
-        *path_a.get(i - 1).unwrap_or(&0)
+        self.lca_index.lca(&self.nodes, a, b)
     }
 
     pub fn lowest_common_ancestor(&self, mut a: u32, mut b: u32) -> u32 {
@@ -383,35 +831,80 @@ impl Taxonomy {
         a
     }
 
-    pub fn build_path_cache(&mut self) {
-        let mut cache: HashMap<u32, Vec<u32>> = HashMap::new();
-        let root_external_id = 1u64;
-        if let Some(&root_internal_id) = self.external_to_internal_id_map.get(&root_external_id) {
-            // 开始从根节点遍历
-            self.build_path_for_node(root_internal_id, &mut cache, Vec::new());
+    /// 从根节点 (external id 1) 建立 LCA 索引, 读取或生成 taxonomy 后调用
+    pub fn build_lca_index(&mut self) {
+        self.lca_index = match self.external_to_internal_id_map.get(&1) {
+            Some(&root) => LcaIndex::new(&self.nodes, root),
+            None => LcaIndex::default(),
+        };
+    }
+
+    /// 节点的名称
+    pub fn name(&self, internal_id: u32) -> &str {
+        self.nodes
+            .get(internal_id as usize)
+            .map_or("", |node| string_at(&self.name_data, node.name_offset))
+    }
+
+    /// 节点的 rank, 如 species, no rank
+    pub fn rank(&self, internal_id: u32) -> &str {
+        self.nodes
+            .get(internal_id as usize)
+            .map_or("", |node| string_at(&self.rank_data, node.rank_offset))
+    }
+
+    /// 节点在 STANDARD_RANKS 上的祖先 (包含节点本身), 没有该 rank 的祖先时为 None.
+    /// no rank 等不在 STANDARD_RANKS 中的节点被跳过
+    pub fn ranked_lineage(&self, internal_id: u32) -> [Option<u32>; 7] {
+        let mut ranked = [None; 7];
+        for id in self.lineage(internal_id) {
+            if let Some(i) = standard_rank_index(self.rank(id)) {
+                ranked[i] = Some(id);
+            }
         }
-        self.path_cache = cache;
+        ranked
     }
 
-    fn build_path_for_node(
-        &self,
-        node_id: u32,
-        path_cache: &mut HashMap<u32, Vec<u32>>,
-        mut current_path: Vec<u32>,
-    ) {
-        current_path.push(node_id); // 将当前节点添加到路径中
-                                    // 存储当前节点的路径
-        path_cache.insert(node_id, current_path.clone());
+    /// 节点在 STANDARD_RANKS[rank_index] 上的祖先 (包含节点本身). lineage 中没有该 rank 时
+    /// (节点在该 rank 之上, 或 lineage 跳过了该 rank), 返回该 rank 之上最近的有标准 rank 的祖先,
+    /// 所以 genus 下的 no rank 和 species group 等非标准 rank 节点都折叠到 genus.
+    /// 该 rank 之上没有有 rank 的祖先时, 返回第一个低于该 rank 的祖先的父节点;
+    /// lineage 中没有任何 STANDARD_RANKS 的节点时原样返回
+    pub fn ancestor_at_rank(&self, internal_id: u32, rank_index: usize) -> u32 {
+        let lineage = self.lineage(internal_id);
+        let mut ranked_above = None;
+        for (pos, &id) in lineage.iter().enumerate() {
+            match standard_rank_index(self.rank(id)) {
+                Some(index) if index < rank_index => ranked_above = Some(id),
+                Some(index) if index == rank_index => return id,
+                Some(_) => return ranked_above.unwrap_or(lineage[pos.saturating_sub(1)]),
+                None => {}
+            }
+        }
+        ranked_above.unwrap_or(internal_id)
+    }
 
-        // 获取当前节点的信息
-        let node = &self.nodes[node_id as usize];
-        let first_child_id = node.first_child as u32;
-        let child_count = node.child_count as u32;
+    /// 从根节点到 internal_id 的路径 (包含两端)
+    pub fn lineage(&self, internal_id: u32) -> Vec<u32> {
+        let mut path = Vec::new();
+        let mut current = internal_id;
+        while current != 0 && (current as usize) < self.nodes.len() && path.len() < self.nodes.len()
+        {
+            path.push(current);
+            current = self.nodes[current as usize].parent_id as u32;
+        }
+        path.reverse();
+        path
+    }
 
-        // 遍历所有子节点
-        for i in 0..child_count {
-            let child_internal_id = first_child_id + i; // 这里假设子节点的ID是连续的
-            self.build_path_for_node(child_internal_id, path_cache, current_path.clone());
+    /// 子节点的内部 id, 子节点是连续的
+    pub fn children(&self, internal_id: u32) -> Range<u32> {
+        match self.nodes.get(internal_id as usize) {
+            Some(node) if node.child_count > 0 => {
+                let first = node.first_child as u32;
+                first..first + node.child_count as u32
+            }
+            _ => 0..0,
         }
     }
 
@@ -438,7 +931,7 @@ impl Taxonomy {
     }
 
     pub fn write_to_disk<P: AsRef<Path>>(&self, filename: P) -> Result<()> {
-        let mut file = File::create(filename)?;
+        let mut file = K2dWriter::new(BufWriter::new(File::create(filename)?), FileKind::Taxonomy)?;
 
         // Write file magic
         file.write_all(Taxonomy::MAGIC)?;
@@ -465,7 +958,279 @@ impl Taxonomy {
         // Write name data and rank data
         file.write_all(&self.name_data)?;
         file.write_all(&self.rank_data)?;
+        file.finish()?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// 固定种子的线性同余随机数, 每次运行生成相同的树
+    pub(crate) struct Lcg(pub u64);
+
+    impl Lcg {
+        pub(crate) fn next(&mut self, bound: u64) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % bound
+        }
+    }
+
+    /// 按 BFS 编号生成 node_count 个节点的树, 子节点连续, 与 taxo.k2d 的布局相同.
+    /// 每个节点有 0 到 max_children 个子节点, 根节点至少有一个
+    pub(crate) fn synthetic_taxonomy(
+        node_count: usize,
+        max_children: u64,
+        rng: &mut Lcg,
+    ) -> Taxonomy {
+        let mut taxonomy = Taxonomy::default();
+        taxonomy.nodes.push(TaxonomyNode::default());
+        taxonomy.nodes.push(TaxonomyNode {
+            external_id: 1,
+            ..Default::default()
+        });
+        let mut next = 2;
+        let mut parent = 1;
+        while next < node_count && parent < next {
+            let child_count = match rng.next(max_children + 1) as usize {
+                0 if parent == 1 => 1,
+                n => n.min(node_count - next),
+            };
+            if child_count > 0 {
+                taxonomy.nodes[parent].first_child = next as u64;
+                taxonomy.nodes[parent].child_count = child_count as u64;
+            }
+            for id in next..next + child_count {
+                taxonomy.nodes.push(TaxonomyNode {
+                    parent_id: parent as u64,
+                    external_id: id as u64,
+                    ..Default::default()
+                });
+            }
+            next += child_count;
+            parent += 1;
+        }
+        taxonomy.generate_external_to_internal_id_map();
+        taxonomy.build_lca_index();
+        taxonomy
+    }
+
+    /// 追加 count 个父节点不把它们列为子节点的节点, 从根节点不可达
+    pub(crate) fn add_unreachable(
+        taxonomy: &mut Taxonomy,
+        count: usize,
+        rng: &mut Lcg,
+    ) -> Vec<u32> {
+        let reachable = taxonomy.nodes.len() as u64;
+        let ids: Vec<u32> = (0..count)
+            .map(|i| {
+                let parent = if i % 2 == 0 {
+                    1 + rng.next(reachable - 1)
+                } else {
+                    0
+                };
+                taxonomy.nodes.push(TaxonomyNode {
+                    parent_id: parent,
+                    external_id: 1_000_000 + i as u64,
+                    ..Default::default()
+                });
+                taxonomy.nodes.len() as u32 - 1
+            })
+            .collect();
+        taxonomy.generate_external_to_internal_id_map();
+        taxonomy.build_lca_index();
+        ids
+    }
+
+    fn naive_ancestors(taxonomy: &Taxonomy, node: u32) -> Vec<u32> {
+        let mut path = vec![node];
+        while path[path.len() - 1] != 1 {
+            path.push(taxonomy.nodes[path[path.len() - 1] as usize].parent_id as u32);
+        }
+        path
+    }
+
+    fn naive_lca(taxonomy: &Taxonomy, a: u32, b: u32) -> u32 {
+        let ancestors: HashSet<u32> = naive_ancestors(taxonomy, a).into_iter().collect();
+        *naive_ancestors(taxonomy, b)
+            .iter()
+            .find(|id| ancestors.contains(id))
+            .unwrap()
+    }
+
+    fn check_pair(taxonomy: &Taxonomy, a: u32, b: u32) {
+        assert_eq!(
+            taxonomy.lca(a, b),
+            naive_lca(taxonomy, a, b),
+            "lca({}, {})",
+            a,
+            b
+        );
+        assert_eq!(
+            taxonomy.is_a_ancestor_of_b(a, b),
+            naive_ancestors(taxonomy, b).contains(&a),
+            "is_a_ancestor_of_b({}, {})",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn lca_index_matches_parent_walk_on_random_trees() {
+        let mut rng = Lcg(7);
+        // 块大小附近和跨多个块的树, 以及很宽和很窄的分支
+        for (node_count, max_children) in
+            [(3, 1), (33, 2), (65, 3), (500, 8), (5000, 2), (5000, 40)]
+        {
+            let taxonomy = synthetic_taxonomy(node_count, max_children, &mut rng);
+            let n = taxonomy.nodes.len() as u64;
+            for a in 1..n.min(40) as u32 {
+                for b in 1..n.min(40) as u32 {
+                    check_pair(&taxonomy, a, b);
+                }
+            }
+            for _ in 0..2000 {
+                let (a, b) = (1 + rng.next(n - 1) as u32, 1 + rng.next(n - 1) as u32);
+                check_pair(&taxonomy, a, b);
+                check_pair(&taxonomy, b, a);
+            }
+        }
+    }
+
+    #[test]
+    fn lca_index_handles_deep_chain() {
+        // 递归遍历会栈溢出的深度. 节点 id - 1 是 id 的父节点, 节点 1000 另有一个叶子 1001
+        let node_count = 200_000u64;
+        let mut taxonomy = Taxonomy::default();
+        taxonomy.nodes.push(TaxonomyNode::default());
+        for id in 1..node_count {
+            let (parent, first_child, child_count) = match id {
+                1 => (0, 2, 1),
+                1000 => (999, 1001, 2),
+                1001 => (1000, 0, 0),
+                1002 => (1000, 1003, 1),
+                id if id + 1 == node_count => (id - 1, 0, 0),
+                id => (id - 1, id + 1, 1),
+            };
+            taxonomy.nodes.push(TaxonomyNode {
+                parent_id: parent,
+                first_child,
+                child_count,
+                external_id: id,
+                ..Default::default()
+            });
+        }
+        taxonomy.generate_external_to_internal_id_map();
+        taxonomy.build_lca_index();
+
+        let last = node_count as u32 - 1;
+        assert_eq!(taxonomy.lca(1001, last), 1000);
+        assert!(taxonomy.is_a_ancestor_of_b(1, last));
+        assert!(!taxonomy.is_a_ancestor_of_b(1001, last));
+        let mut rng = Lcg(11);
+        for _ in 0..50 {
+            let a = 1 + rng.next(node_count - 1) as u32;
+            let b = 1 + rng.next(node_count - 1) as u32;
+            check_pair(&taxonomy, a, b);
+            check_pair(&taxonomy, 1001, b);
+        }
+    }
+
+    #[test]
+    fn lca_index_ignores_unreachable_nodes() {
+        let mut rng = Lcg(3);
+        let mut taxonomy = synthetic_taxonomy(300, 4, &mut rng);
+        let reachable = taxonomy.nodes.len() as u64;
+        let unreachable = add_unreachable(&mut taxonomy, 6, &mut rng);
+        for &x in &unreachable {
+            assert_eq!(taxonomy.preorder_interval(x), None);
+            for _ in 0..50 {
+                let y = 1 + rng.next(reachable - 1) as u32;
+                assert_eq!(taxonomy.lca(x, y), 0);
+                assert_eq!(taxonomy.lca(y, x), 0);
+                assert!(!taxonomy.is_a_ancestor_of_b(x, y));
+                assert!(!taxonomy.is_a_ancestor_of_b(y, x));
+            }
+        }
+        // 可达部分不受影响
+        for _ in 0..500 {
+            let (a, b) = (
+                1 + rng.next(reachable - 1) as u32,
+                1 + rng.next(reachable - 1) as u32,
+            );
+            check_pair(&taxonomy, a, b);
+        }
+        assert_eq!(taxonomy.lca(0, 5), 5);
+        assert!(!taxonomy.is_a_ancestor_of_b(0, 5));
+    }
+
+    /// 按 (父节点, rank) 依次生成节点 1, 2, ..., 只设置 lineage 用到的父节点和 rank
+    fn ranked_taxonomy(nodes: &[(u64, &str)]) -> Taxonomy {
+        let mut taxonomy = Taxonomy::default();
+        taxonomy.nodes.push(TaxonomyNode::default());
+        for (i, &(parent_id, rank)) in nodes.iter().enumerate() {
+            taxonomy.nodes.push(TaxonomyNode {
+                parent_id,
+                rank_offset: taxonomy.rank_data.len() as u64,
+                external_id: i as u64 + 1,
+                ..Default::default()
+            });
+            taxonomy.rank_data.extend_from_slice(rank.as_bytes());
+            taxonomy.rank_data.push(0);
+        }
+        taxonomy
+    }
+
+    #[test]
+    fn ancestor_at_rank_collapses_to_nearest_ranked_ancestor() {
+        let taxonomy = ranked_taxonomy(&[
+            (0, "no rank"),       // 1 root
+            (1, "superkingdom"),  // 2
+            (2, "family"),        // 3
+            (3, "genus"),         // 4
+            (4, "no rank"),       // 5 genus 下的 no rank
+            (4, "species"),       // 6
+            (6, "strain"),        // 7
+            (3, "no rank"),       // 8 跳过 genus 的分支
+            (8, "species"),       // 9
+            (1, "no rank"),       // 10 没有 rank 的分支
+            (10, "species"),      // 11
+            (4, "species group"), // 12 species 之上的非标准 rank
+            (12, "species"),      // 13
+        ]);
+        let species = standard_rank_index("species").unwrap();
+        let genus = standard_rank_index("genus").unwrap();
+        let cases = [
+            (7, species, 6),
+            (6, species, 6),
+            (5, species, 4),
+            (4, species, 4),
+            (3, species, 3),
+            (1, species, 1),
+            (7, genus, 4),
+            (9, genus, 3),
+            (8, genus, 3),
+            (9, species, 9),
+            (10, genus, 10),
+            (11, genus, 10),
+            (11, species, 11),
+            (12, species, 4),
+            (13, species, 13),
+            (13, genus, 4),
+        ];
+        for (node, rank_index, expected) in cases {
+            assert_eq!(
+                taxonomy.ancestor_at_rank(node, rank_index),
+                expected,
+                "ancestor_at_rank({}, {})",
+                node,
+                STANDARD_RANKS[rank_index]
+            );
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::{BTreeMap as Map, HashMap};
+use std::collections::{BTreeMap as Map, HashMap, HashSet};
 use std::fs::{self, create_dir_all, File, OpenOptions};
 use std::io::{self, BufRead, BufReader, BufWriter, Result};
 use std::path::{Path, PathBuf};
@@ -314,6 +314,62 @@ pub fn open_file<P: AsRef<Path>>(path: P) -> io::Result<File> {
     })
 }
 
+/// 流式读取 NCBI accession2taxid 文件 (nucl_gb, nucl_wgs 等, 可以是 gz), 只保留 accessions 中的记录.
+/// 文件格式为 accession<TAB>accession.version<TAB>taxid<TAB>gi, 第一行是表头.
+/// accessions 中的 id 可以带版本号也可以不带, 返回的 map 以 accessions 中的写法为键
+pub fn read_accession2taxid<P: AsRef<Path>>(
+    files: &[P],
+    accessions: &HashSet<String>,
+) -> Result<HashMap<String, u64>> {
+    let mut id_map = HashMap::new();
+    for path in files {
+        let path = path.as_ref();
+        for (i, line) in open_text_file(path)?.lines().enumerate() {
+            let line = line?;
+            let mut fields = line.split('\t');
+            let (Some(accession), Some(accession_version), Some(taxid)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let keys = [accession_version, accession];
+            if !keys.iter().any(|key| accessions.contains(*key)) {
+                continue;
+            }
+            let taxid = match taxid.parse::<u64>() {
+                Ok(taxid) => taxid,
+                Err(_) if i == 0 => continue, // 表头
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{:?} line {}: invalid taxid {:?}", path, i + 1, taxid),
+                    ))
+                }
+            };
+            for key in keys {
+                if accessions.contains(key) {
+                    id_map.insert(key.to_string(), taxid);
+                }
+            }
+        }
+    }
+    Ok(id_map)
+}
+
+/// 打开文本文件, gzip 压缩的文件(按文件头判断)自动解压
+pub fn open_text_file<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn BufRead + Send>> {
+    let mut file = open_file(path)?;
+    let mut magic = [0u8; 2];
+    let n = io::Read::read(&mut file, &mut magic)?;
+    io::Seek::rewind(&mut file)?;
+    if n == 2 && magic == [0x1F, 0x8B] {
+        let decoder = flate2::read::MultiGzDecoder::new(BufReader::new(file));
+        Ok(Box::new(BufReader::new(decoder)))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
 /// 获取最新的文件序号
 pub fn get_lastest_file_index(file_path: &PathBuf) -> Result<usize> {
     let file_content = fs::read_to_string(&file_path)?;
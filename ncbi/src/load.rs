@@ -102,7 +102,10 @@ impl NcbiFile {
             NcbiFile::Genomic(_, _) => {}
             NcbiFile::Taxonomy(dt1, _) => {
                 let taxo_files: Vec<String> =
-                    vec!["names.dmp".to_string(), "nodes.dmp".to_string()];
+                    ["names.dmp", "nodes.dmp", "merged.dmp", "delnodes.dmp"]
+                        .iter()
+                        .map(|name| name.to_string())
+                        .collect();
                 decompress_and_extract_tar_gz(&dt1.file, &data_dir, taxo_files).await?;
             }
         }
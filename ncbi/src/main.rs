@@ -95,6 +95,30 @@ impl fmt::Display for Plas {
     }
 }
 
+/// NCBI accession2taxid 文件
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Accession2Taxid {
+    /// nucl_gb.accession2taxid.gz
+    #[value(name = "nucl_gb")]
+    NuclGb,
+    /// nucl_wgs.accession2taxid.gz
+    #[value(name = "nucl_wgs")]
+    NuclWgs,
+}
+
+impl fmt::Display for Accession2Taxid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Accession2Taxid::NuclGb => "nucl_gb",
+                Accession2Taxid::NuclWgs => "nucl_wgs",
+            }
+        )
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Mode {
     /// Check the md5 of files only
@@ -143,7 +167,11 @@ enum Commands {
     },
     /// Download taxonomy files from NCBI (alias: tax)
     #[command(alias = "tax")]
-    Taxonomy,
+    Taxonomy {
+        /// Also download accession2taxid files, comma-separated: nucl_gb, nucl_wgs
+        #[arg(long, value_enum, value_delimiter = ',')]
+        accession2taxid: Vec<Accession2Taxid>,
+    },
 
     /// Download genomes data from NCBI (alias: gen)
     #[command(alias = "gen")]
@@ -181,10 +209,12 @@ async fn async_run(args: Args) -> Result<()> {
             utils::create_dir(&data_dir)?;
             download_plas_files(data_dir, &mode.to_string()).await?
         }
-        Commands::Taxonomy => {
+        Commands::Taxonomy { accession2taxid } => {
             let data_dir: PathBuf = db_path.join("taxonomy");
             utils::create_dir(&data_dir)?;
-            let _ = task::run_taxo(&data_dir).await;
+            let accession2taxid: Vec<String> =
+                accession2taxid.iter().map(|a| a.to_string()).collect();
+            let _ = task::run_taxo(&data_dir, &accession2taxid).await;
         }
         Commands::Genomes {
             site,
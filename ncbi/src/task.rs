@@ -153,16 +153,21 @@ pub async fn run_check(
     Ok(())
 }
 
-pub async fn run_taxo(taxo_dir: &PathBuf) -> Result<()> {
+/// 下载 taxdump, accession2taxid 中的文件名如 nucl_gb, nucl_wgs
+pub async fn run_taxo(taxo_dir: &PathBuf, accession2taxid: &[String]) -> Result<()> {
     log::info!("download taxonomy...");
-    let files = [
-        "taxdump.tar.gz",
-        // "accession2taxid/nucl_gb.accession2taxid.gz",
-        // "accession2taxid/nucl_wgs.accession2taxid.gz",
-    ];
+    let mut files = vec!["taxdump.tar.gz".to_string()];
+    files.extend(
+        accession2taxid
+            .iter()
+            .map(|name| format!("accession2taxid/{}.accession2taxid.gz", name)),
+    );
     for url_path in files.iter() {
         let ncbi_file = NcbiFile::new_taxo(taxo_dir, &url_path).await;
         let result = ncbi_file.run().await;
+        if let Err(e) = &result {
+            log::error!("download {} error: {:?}", url_path, e);
+        }
         if result.is_ok() && url_path.to_string() == "taxdump.tar.gz" {
             ncbi_file.decompress(taxo_dir).await?;
         }
@@ -79,7 +79,7 @@ where
             let first_space_index = s
                 .as_bytes()
                 .iter()
-                .position(|&c| c == b' ')
+                .position(|c| c.is_ascii_whitespace())
                 .unwrap_or(s.len());
 
             // 直接从原始切片创建第一个单词的切片
@@ -216,7 +216,7 @@ where
             let first_space_index = s
                 .as_bytes()
                 .iter()
-                .position(|&c| c == b' ')
+                .position(|c| c.is_ascii_whitespace())
                 .unwrap_or(s.len());
 
             // 直接从原始切片创建第一个单词的切片